@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+/// Delays between reconnect attempts, doubled after every attempt up to the limit
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Start over from the initial delay, once server streamed again
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    #[test]
+    fn test_doubling_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+
+        let delays = (0..5).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+
+        assert_eq!(delays, [100, 200, 400, 500, 500].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        backoff.next_delay();
+        backoff.next_delay();
+
+        backoff.reset();
+
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded};
+use log::{info, trace, warn};
+use quotes_lib::{
+    control_message::ControlMessage, session::TickerDictionary,
+    subscribe_response::SubscribeResponse,
+};
+
+use crate::error::ClientError;
+
+/// Read commands typed into stdin for the whole client run, so they reach sessions after reconnect.
+/// `UNSUBSCRIBE` stops the client, it is sent to server by [`ControlSession::shutdown`]
+pub fn read_commands(running: Arc<AtomicBool>) -> Receiver<ControlMessage> {
+    let (command_tx, command_rx) = unbounded();
+    // blocked on stdin read, so the thread is not joined and ends with process
+    thread::spawn(move || {
+        trace!("Starting stdin reader thread");
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match ControlMessage::try_from(line.as_str()) {
+                Ok(ControlMessage::Unsubscribe) => {
+                    running.store(false, Ordering::SeqCst);
+                    break;
+                }
+                Ok(message) => {
+                    if command_tx.send(message).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("{e}, expected ADD|REMOVE|REPLACE <tickers> or UNSUBSCRIBE"),
+            }
+        }
+        trace!("Stdin reader thread finished");
+    });
+
+    command_rx
+}
+
+/// Sends commands typed into stdin over TCP connection kept open after subscribe
+pub struct ControlSession {
+    stream: TcpStream,
+    active: Arc<AtomicBool>,
+    handle: JoinHandle<Vec<String>>,
+}
+
+impl ControlSession {
+    pub fn new(
+        stream: TcpStream,
+        command_rx: Receiver<ControlMessage>,
+        tickers: Vec<String>,
+        dictionary_tx: Sender<TickerDictionary>,
+    ) -> Result<Self, ClientError> {
+        let active = Arc::new(AtomicBool::new(true));
+
+        // server may push updates between replies, so responses are read by their own thread,
+        // it ends once connection is closed
+        let reader = BufReader::new(stream.try_clone()?);
+        let reader_active = active.clone();
+        thread::spawn(move || {
+            if let Err(e) = Self::read_responses(reader_active, reader, dictionary_tx) {
+                warn!("Control session read error {e}");
+            }
+        });
+
+        let thread_active = active.clone();
+        let thread_stream = stream.try_clone()?;
+        let handle = thread::spawn(move || {
+            trace!("Starting control session thread");
+            let mut tickers = tickers;
+            if let Err(e) = Self::run(thread_active, thread_stream, command_rx, &mut tickers) {
+                warn!("Control session error {e}");
+            }
+            trace!("Control session thread finished");
+            tickers
+        });
+
+        Ok(Self {
+            stream,
+            active,
+            handle,
+        })
+    }
+
+    fn run(
+        active: Arc<AtomicBool>,
+        mut stream: TcpStream,
+        command_rx: Receiver<ControlMessage>,
+        tickers: &mut Vec<String>,
+    ) -> Result<(), ClientError> {
+        const COMMAND_TIMEOUT: Duration = Duration::from_millis(500);
+
+        while active.load(Ordering::SeqCst) {
+            let message = match command_rx.recv_timeout(COMMAND_TIMEOUT) {
+                Ok(message) => message,
+                // timeout to check if session is still active
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            writeln!(stream, "{message}")?;
+            Self::apply(&message, tickers);
+        }
+
+        Ok(())
+    }
+
+    /// Handle replies to commands and updates server pushes on its own,
+    /// e.g. ids of tickers that reloaded tickers file added to subscribed group
+    fn read_responses(
+        active: Arc<AtomicBool>,
+        mut reader: BufReader<TcpStream>,
+        dictionary_tx: Sender<TickerDictionary>,
+    ) -> Result<(), ClientError> {
+        let mut response = String::new();
+
+        loop {
+            response.clear();
+            if reader.read_line(&mut response)? == 0 {
+                // servers without control sessions close connection after subscribe
+                if active.load(Ordering::SeqCst) {
+                    warn!("Server closed control session");
+                }
+                return Ok(());
+            }
+
+            match SubscribeResponse::try_from(response.as_str())? {
+                SubscribeResponse::Accepted {
+                    dictionary,
+                    rejected,
+                    ..
+                } => {
+                    for rejected in &rejected {
+                        warn!("Ticker {} rejected: {}", rejected.ticker, rejected.code);
+                    }
+                    info!("Subscription updated, ticker dictionary {dictionary}");
+                    dictionary_tx.send(dictionary)?;
+                }
+                SubscribeResponse::Rejected(error) => warn!("Request rejected: {error}"),
+            }
+        }
+    }
+
+    /// Keep tickers to resubscribe with in line with sent command, selectors server rejected
+    /// are rejected again on resubscribe. Tickers removed from a pattern or group are streamed again
+    /// after resubscribe
+    fn apply(message: &ControlMessage, tickers: &mut Vec<String>) {
+        match message {
+            ControlMessage::Add(added) => {
+                for ticker in added {
+                    if !tickers.contains(ticker) {
+                        tickers.push(ticker.clone());
+                    }
+                }
+            }
+            ControlMessage::Remove(removed) => tickers.retain(|ticker| !removed.contains(ticker)),
+            ControlMessage::Replace(replaced) => *tickers = replaced.clone(),
+            ControlMessage::Unsubscribe | ControlMessage::Verify(_) => {}
+        }
+    }
+
+    /// Tell server to stop the stream and close connection.
+    /// Returns tickers changed by sent commands, to resubscribe with
+    pub fn shutdown(mut self) -> Result<Vec<String>, ClientError> {
+        trace!("Shutting down control session");
+        self.active.store(false, Ordering::SeqCst);
+        // connection is closed already if server is gone
+        let _ = writeln!(self.stream, "{}", ControlMessage::Unsubscribe);
+        if let Err(e) = self.stream.shutdown(Shutdown::Both)
+            && e.kind() != ErrorKind::NotConnected
+        {
+            warn!("Unable to close control session {e}");
+        }
+        self.handle.join().map_err(|_| ClientError::ThreadJoin)
+    }
+}
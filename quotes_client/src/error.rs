@@ -1,7 +1,11 @@
 use std::fmt::Display;
 
 use log::SetLoggerError;
-use quotes_lib::error::QuotesError;
+use quotes_lib::{
+    error::QuotesError,
+    error_message::{ErrorCode, ErrorMessage},
+    subscribe_response::RejectedTicker,
+};
 
 #[derive(Debug)]
 pub enum ClientError {
@@ -12,6 +16,20 @@ pub enum ClientError {
     RecvError(String),
     ThreadJoin,
     CtrlCError(ctrlc::Error),
+    SubscribeRejected(ErrorMessage),
+    NoTickersAccepted(Vec<RejectedTicker>),
+    NoStreamSource,
+}
+
+impl ClientError {
+    /// Server may be restarting or unreachable for a while, so connecting again can succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Io(_) => true,
+            ClientError::SubscribeRejected(error) => error.code == ErrorCode::ShuttingDown,
+            _ => false,
+        }
+    }
 }
 
 impl From<SetLoggerError> for ClientError {
@@ -59,6 +77,24 @@ impl Display for ClientError {
             ClientError::RecvError(reason) => write!(f, "Receive error: {reason}"),
             ClientError::ThreadJoin => write!(f, "Thread stop error"),
             ClientError::CtrlCError(e) => write!(f, "Ctrl-C setup error {e}"),
+            ClientError::SubscribeRejected(error) => {
+                write!(f, "Server rejected subscription: {error}")
+            }
+            ClientError::NoTickersAccepted(rejected) => write!(
+                f,
+                "Server accepted none of requested tickers: {}",
+                rejected
+                    .iter()
+                    .map(|rejected| format!("{} ({})", rejected.ticker, rejected.code))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ClientError::NoStreamSource => {
+                write!(
+                    f,
+                    "Server didn't tell where to ping, stream address can't be learned"
+                )
+            }
         }
     }
 }
@@ -1,39 +1,88 @@
 use std::{
-    io::Write,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
     path::PathBuf,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
-    time::Duration,
+    thread,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{Receiver, RecvTimeoutError, unbounded};
 use env_logger::Builder;
 use log::{LevelFilter, debug, error, info, trace, warn};
 use quotes_lib::{
-    read_tickers_from_file, server_message::ServerMessage, subscribe_message::SubscribeMessage,
+    auth::{AuthParams, Direction, PreSharedKey},
+    control_message::ControlMessage,
+    credentials::Credentials,
+    datagram::DatagramParser,
+    error::QuotesError,
+    error_message::{ErrorCode, ErrorMessage},
+    protocol::WireFormat,
+    read_tickers_from_file,
+    server_message::ServerMessage,
+    session::SessionDecoder,
+    subscribe_message::SubscribeMessage,
+    subscribe_response::SubscribeResponse,
 };
 
 use crate::{
+    backoff::Backoff,
+    control_session::{ControlSession, read_commands},
     error::ClientError,
     pinger::Pinger,
     quotes_listener::{QuotesListener, QuotesListenerEvent},
+    recorder::Recorder,
 };
 
+mod backoff;
+mod control_session;
 mod error;
 mod pinger;
 mod quotes_listener;
+mod recorder;
 
 #[derive(Parser, Debug)]
 struct Args {
-    server_address: SocketAddr,
+    /// server TCP address, host name or IP with port, e.g. `quotes.example.com:3000`
+    server_address: String,
     #[arg(short = 'p', long)]
     port: u16,
     #[arg(short = 't', long)]
     tickers: PathBuf,
+    /// local address to receive stream on, address of interface connected to server by default
+    #[arg(long)]
+    bind: Option<IpAddr>,
+    /// address server sends stream to, if it differs from bind one, e.g. behind port forwarding
+    #[arg(long)]
+    advertise: Option<IpAddr>,
+    /// subscribe without address, server streams to address authenticated pings come from
+    #[arg(long, conflicts_with = "advertise", requires = "psk_file")]
+    nat: bool,
+    /// heartbeats missed in a row before feed is stale and client reconnects
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    missed_heartbeats: u32,
+    /// request human-readable JSON lines instead of binary datagrams
+    #[arg(long)]
+    json: bool,
+    /// append received quotes and books to CSV file
+    #[arg(short = 'r', long)]
+    record: Option<PathBuf>,
+    /// file with secret shared with server, datagrams without valid tag are dropped
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+    /// file with access token for servers that check credentials
+    #[arg(long, conflicts_with = "user")]
+    token_file: Option<PathBuf>,
+    /// user name for servers that check credentials
+    #[arg(long, requires = "password_file")]
+    user: Option<String>,
+    /// file with password of the user
+    #[arg(long, requires = "user")]
+    password_file: Option<PathBuf>,
 }
 
 fn init_logger() -> Result<(), ClientError> {
@@ -51,9 +100,41 @@ fn main() {
     }
 }
 
+/// Settings every session connects and subscribes with
+struct ClientConfig {
+    server_address: String,
+    port: u16,
+    bind: Option<IpAddr>,
+    advertise: Option<IpAddr>,
+    nat: bool,
+    format: WireFormat,
+    missed_heartbeats: u32,
+    psk: Option<PreSharedKey>,
+    credentials: Option<Credentials>,
+}
+
+/// Stream parameters from server reply to subscribe request
+struct Subscribed {
+    decoder: SessionDecoder,
+    /// datagrams are sealed with pre-shared key
+    auth: Option<AuthParams>,
+    /// address to ping, None if server doesn't advertise it
+    source: Option<SocketAddr>,
+    /// idle time after which server sends heartbeat, None if it doesn't
+    heartbeat: Option<Duration>,
+}
+
+/// Why a session stopped streaming
+enum SessionEnd {
+    /// Ctrl-C or UNSUBSCRIBE, client exits
+    Stopped,
+    /// server is gone or stream went silent, client reconnects
+    FeedLost(String),
+}
+
 fn run_client() -> Result<(), ClientError> {
-    const PING_INTERVAL: Duration = Duration::from_millis(1000);
-    const MAX_ERRORS_PER_TICKER: usize = 3;
+    const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+    const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
     init_logger()?;
     let args = Args::parse();
 
@@ -65,60 +146,273 @@ fn run_client() -> Result<(), ClientError> {
         r.store(false, Ordering::SeqCst);
     })?;
 
-    let tickers = read_tickers_from_file(args.tickers)?;
+    let mut tickers = read_tickers_from_file(args.tickers)?;
+    let mut recorder = args.record.map(Recorder::create).transpose()?;
+    let psk = args.psk_file.map(PreSharedKey::from_file).transpose()?;
+    let credentials = match (args.token_file, args.user, args.password_file) {
+        (Some(token_file), ..) => Some(Credentials::token_from_file(token_file)?),
+        (None, Some(user), Some(password_file)) => {
+            Some(Credentials::password_from_file(&user, password_file)?)
+        }
+        _ => None,
+    };
+    let config = ClientConfig {
+        server_address: args.server_address,
+        port: args.port,
+        bind: args.bind,
+        advertise: args.advertise,
+        nat: args.nat,
+        format: if args.json {
+            WireFormat::Json
+        } else {
+            WireFormat::Binary
+        },
+        missed_heartbeats: args.missed_heartbeats,
+        psk,
+        credentials,
+    };
+
+    let command_rx = read_commands(running.clone());
+    let mut backoff = Backoff::new(RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+    let mut reconnecting = false;
+
+    while running.load(Ordering::SeqCst) {
+        match run_session(
+            &config,
+            &running,
+            &mut tickers,
+            &command_rx,
+            recorder.as_mut(),
+        ) {
+            Ok((SessionEnd::Stopped, _)) => break,
+            Ok((SessionEnd::FeedLost(reason), streamed)) => {
+                warn!("Feed lost: {reason}");
+                if streamed {
+                    backoff.reset();
+                }
+            }
+            // misconfigured client fails at once, restarted server is waited for
+            Err(e) if reconnecting && e.is_transient() => warn!("Unable to reconnect: {e}"),
+            Err(e) => return Err(e),
+        }
+
+        if tickers.is_empty() {
+            warn!("No tickers left to resubscribe, stopping");
+            break;
+        }
+
+        reconnecting = true;
+        let delay = backoff.next_delay();
+        info!("Reconnecting in {delay:?}");
+        wait_while_running(&running, delay);
+    }
+
+    Ok(())
+}
+
+/// Sleep for delay, waking up early on Ctrl-C
+fn wait_while_running(running: &AtomicBool, delay: Duration) {
+    const STEP: Duration = Duration::from_millis(100);
+
+    let deadline = Instant::now() + delay;
+    while running.load(Ordering::SeqCst) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        thread::sleep(remaining.min(STEP));
+    }
+}
+
+/// Connect, subscribe to tickers and print the stream until it stops.
+/// Tickers are updated with accepted control commands, so the next session resubscribes to them.
+/// Also returns whether any market data was received
+fn run_session(
+    config: &ClientConfig,
+    running: &AtomicBool,
+    tickers: &mut Vec<String>,
+    command_rx: &Receiver<ControlMessage>,
+    mut recorder: Option<&mut Recorder>,
+) -> Result<(SessionEnd, bool), ClientError> {
+    const PING_INTERVAL: Duration = Duration::from_millis(1000);
+    const MAX_ERRORS_PER_TICKER: usize = 3;
+    // servers without heartbeats send every ticker each second, challenge unverified streams as often
+    const FEED_TIMEOUT: Duration = Duration::from_secs(5);
+    const EVENT_TIMEOUT: Duration = Duration::from_millis(500);
+
     let max_errors = tickers.len() * MAX_ERRORS_PER_TICKER;
-    let tcp_stream = setup_connection(args.server_address)?;
+    let tcp_stream = setup_connection(&config.server_address)?;
 
-    debug!("Listenting to UDP socket on port {}", args.port);
-    let socket = Arc::new(UdpSocket::bind(format!("127.0.0.1:{}", args.port))?);
+    // interface that reaches server over TCP can receive its stream as well
+    let connected_ip = tcp_stream.local_addr()?.ip();
+    let bind_address = SocketAddr::new(config.bind.unwrap_or(connected_ip), config.port);
+    let advertised_address = (!config.nat).then(|| {
+        SocketAddr::new(
+            config
+                .advertise
+                .or(config.bind.filter(|ip| !ip.is_unspecified()))
+                .unwrap_or(connected_ip),
+            config.port,
+        )
+    });
+    debug!("Listenting to UDP socket on {bind_address}");
+    let socket = Arc::new(UdpSocket::bind(bind_address)?);
+
+    let Subscribed {
+        decoder,
+        auth,
+        source,
+        heartbeat,
+    } = request_data(
+        &tcp_stream,
+        advertised_address,
+        tickers.clone(),
+        config.format,
+        config.credentials.clone(),
+    )?;
+    debug!(
+        "Subscribed using protocol version {}, {} format",
+        decoder.version(),
+        decoder.format()
+    );
+    // server bound to all interfaces is reached at address client connected to
+    let source = match source {
+        Some(source) if source.ip().is_unspecified() => {
+            Some(SocketAddr::new(tcp_stream.peer_addr()?.ip(), source.port()))
+        }
+        source => source,
+    };
+    // stream to learned address doesn't start until the first ping
+    if config.nat && source.is_none() {
+        return Err(ClientError::NoStreamSource);
+    }
 
-    request_data(tcp_stream, args.port, tickers)?;
+    let (datagram_parser, ping_sealer) = match (auth, &config.psk) {
+        (Some(auth), Some(psk)) => {
+            debug!("Datagrams are authenticated, mode {}", auth.mode);
+            (
+                DatagramParser::new().with_opener(auth.opener(psk, Direction::ServerToClient)),
+                Some(auth.sealer(psk, Direction::ClientToServer)),
+            )
+        }
+        (None, None) => (DatagramParser::new(), None),
+        (Some(_), None) => {
+            return Err(ClientError::from(QuotesError::AuthError(
+                "server authenticates datagrams, pre-shared key is required".to_string(),
+            )));
+        }
+        (None, Some(_)) => {
+            return Err(ClientError::from(QuotesError::AuthError(
+                "server streams unauthenticated datagrams".to_string(),
+            )));
+        }
+    };
 
     let (event_tx, event_rx) = unbounded();
+    let (dictionary_tx, dictionary_rx) = unbounded();
+    let control_session = ControlSession::new(
+        tcp_stream,
+        command_rx.clone(),
+        tickers.clone(),
+        dictionary_tx,
+    )?;
 
-    let quotes_listener = QuotesListener::new(running.clone(), socket.clone(), event_tx);
-    let pinger = Pinger::new(running.clone(), socket.clone(), PING_INTERVAL);
+    // stops threads of this session only, client goes on with the next one
+    let active = Arc::new(AtomicBool::new(true));
+    let quotes_listener = QuotesListener::new(
+        active.clone(),
+        socket.clone(),
+        decoder,
+        datagram_parser,
+        dictionary_rx,
+        event_tx,
+    );
+    let pinger = Pinger::new(active.clone(), socket.clone(), ping_sealer, PING_INTERVAL);
 
+    // servers without advertised source are pinged at address of first datagram
     let mut ping_started = false;
+    if let Some(source) = source {
+        match pinger.start_ping(source) {
+            Ok(()) => ping_started = true,
+            Err(e) => warn!("Unable to start ping {e}"),
+        }
+    }
+    // quiet market is told apart from dead server by heartbeats sent while stream is idle
+    let stale_timeout = heartbeat.map(|interval| interval * config.missed_heartbeats);
     let mut error_count = 0;
+    let mut streamed = false;
+    let mut last_message = Instant::now();
 
-    while running.load(Ordering::SeqCst) {
-        match event_rx.recv() {
-            Ok(event) => match event {
-                QuotesListenerEvent::Message(server_message, address) => {
-                    if !ping_started {
-                        if let Err(e) = pinger.start_ping(address) {
-                            warn!("Unable to start ping {e}");
-                            break;
-                        };
-                        ping_started = true;
-                    }
+    let end = loop {
+        if !running.load(Ordering::SeqCst) {
+            break SessionEnd::Stopped;
+        }
+
+        let event = match event_rx.recv_timeout(EVENT_TIMEOUT) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => match stale_timeout {
+                Some(stale_timeout) if last_message.elapsed() >= stale_timeout => {
+                    break SessionEnd::FeedLost(format!(
+                        "feed stale, {} heartbeats missed",
+                        config.missed_heartbeats
+                    ));
+                }
+                None if last_message.elapsed() >= FEED_TIMEOUT => {
+                    break SessionEnd::FeedLost(format!("no datagrams for {FEED_TIMEOUT:?}"));
+                }
+                _ => continue,
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                break SessionEnd::FeedLost("quotes listener stopped".to_string());
+            }
+        };
+
+        match event {
+            QuotesListenerEvent::Message(server_message, address) => {
+                last_message = Instant::now();
+                if !ping_started {
+                    if let Err(e) = pinger.start_ping(address) {
+                        break SessionEnd::FeedLost(format!("unable to start ping {e}"));
+                    };
+                    ping_started = true;
+                }
 
-                    match server_message {
-                        ServerMessage::Quote(quote) => {
-                            error_count = 0;
-                            info!("{quote}")
+                match server_message {
+                    ServerMessage::Err(e) if e.code == ErrorCode::ShuttingDown => {
+                        break SessionEnd::FeedLost(format!(
+                            "server is shutting down: {}",
+                            e.detail
+                        ));
+                    }
+                    ServerMessage::Err(e) => warn!("SERVER ERROR {e}"),
+                    ServerMessage::Challenge(cookie) => {
+                        debug!("Server challenges stream address, echoing cookie");
+                        if let Err(e) = pinger.verify(cookie) {
+                            warn!("Unable to echo challenge {e}");
                         }
-                        ServerMessage::Err(e) => warn!("SERVER ERROR {e}"),
                     }
-                }
-                QuotesListenerEvent::Error(client_error) => {
-                    error_count += 1;
-                    warn!("Error event({error_count}): {client_error}");
-                    if error_count >= max_errors {
-                        warn!("Reached MAX_ERRORS, shutting down");
-                        break;
+                    ServerMessage::Heartbeat => trace!("Heartbeat from {address}"),
+                    market_data => {
+                        error_count = 0;
+                        streamed = true;
+                        if let Err(e) = show_market_data(market_data, recorder.as_deref_mut()) {
+                            warn!("Unable to record market data {e}");
+                        }
                     }
                 }
-            },
-            Err(e) => {
-                warn!("Event receive error {e}");
-                break;
+            }
+            QuotesListenerEvent::Sequence(anomaly) => warn!("Stream sequence {anomaly}"),
+            QuotesListenerEvent::Error(client_error) => {
+                error_count += 1;
+                warn!("Error event({error_count}): {client_error}");
+                if error_count >= max_errors {
+                    break SessionEnd::FeedLost("reached MAX_ERRORS".to_string());
+                }
             }
         }
-    }
+    };
 
-    running.store(false, Ordering::SeqCst);
+    active.store(false, Ordering::SeqCst);
 
     match pinger.shutdown() {
         Ok(()) => trace!("Pinger shut down corectly"),
@@ -130,32 +424,140 @@ fn run_client() -> Result<(), ClientError> {
         Err(e) => warn!("Quotes listener shutdown error: {e}"),
     }
 
-    Ok(())
+    match control_session.shutdown() {
+        Ok(changed) => {
+            trace!("Control session shut down corectly");
+            *tickers = changed;
+        }
+        Err(e) => warn!("Control session shutdown error: {e}"),
+    }
+
+    Ok((end, streamed))
+}
+
+fn show_market_data(
+    message: ServerMessage,
+    mut recorder: Option<&mut Recorder>,
+) -> Result<(), ClientError> {
+    let (mut quotes, mut books, mut trades) = (vec![], vec![], vec![]);
+    match message {
+        ServerMessage::Quote(quote) => quotes.push(quote),
+        ServerMessage::QuoteBatch(batch) => quotes = batch,
+        ServerMessage::TopOfBook(book) => books.push(book),
+        ServerMessage::BookBatch(batch) => books = batch,
+        ServerMessage::Trade(trade) => trades.push(trade),
+        ServerMessage::TradeBatch(batch) => trades = batch,
+        ServerMessage::Err(_) | ServerMessage::Challenge(_) | ServerMessage::Heartbeat => {}
+    }
+
+    for trade in trades {
+        info!("{trade}");
+        if let Some(recorder) = recorder.as_deref_mut() {
+            recorder.record_trade(&trade)?;
+        }
+    }
+
+    for quote in quotes {
+        info!("{quote}");
+        if let Some(recorder) = recorder.as_deref_mut() {
+            recorder.record_quote(&quote)?;
+        }
+    }
+    for book in books {
+        info!("{book}");
+        if let Some(recorder) = recorder.as_deref_mut() {
+            recorder.record_book(&book)?;
+        }
+    }
+
+    match recorder {
+        Some(recorder) => recorder.flush(),
+        None => Ok(()),
+    }
 }
 
-fn setup_connection(server_address: SocketAddr) -> Result<TcpStream, ClientError> {
+/// Connect to the first reachable address server name resolves to
+fn setup_connection(server_address: &str) -> Result<TcpStream, ClientError> {
     debug!("Connecting to {}...", server_address);
-    Ok(TcpStream::connect(server_address)?)
+    let stream = TcpStream::connect(server_address)?;
+    debug!("Connected to {}", stream.peer_addr()?);
+    Ok(stream)
 }
 
 fn request_data(
-    mut stream: TcpStream,
-    local_port: u16,
+    mut stream: &TcpStream,
+    local_address: Option<SocketAddr>,
     tickers: Vec<String>,
-) -> Result<(), ClientError> {
+    format: WireFormat,
+    credentials: Option<Credentials>,
+) -> Result<Subscribed, ClientError> {
+    const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
     debug!(
-        "Requesting data for tickers ({}) on port {local_port}",
-        tickers.join(",")
+        "Requesting data for tickers ({}) on {}",
+        tickers.join(","),
+        local_address.map_or("address learned from pings".to_string(), |address| address
+            .to_string())
     );
 
-    stream.write_all(
-        SubscribeMessage::new(
-            SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), local_port),
-            tickers,
-        )
-        .to_string()
-        .as_bytes(),
-    )?;
+    let mut message = SubscribeMessage::new(local_address, tickers)
+        .with_format(format)
+        .with_control();
+    if let Some(credentials) = credentials {
+        message = message.with_credentials(credentials);
+    }
+    writeln!(stream, "{message}")?;
 
-    Ok(())
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+
+    if response.is_empty() {
+        // servers without version negotiation drop the connection silently
+        return Err(ClientError::SubscribeRejected(ErrorMessage::new(
+            ErrorCode::Unspecified,
+            "connection closed without reply",
+        )));
+    }
+
+    match SubscribeResponse::try_from(response.as_str())? {
+        SubscribeResponse::Accepted {
+            version,
+            dictionary,
+            format: accepted_format,
+            auth,
+            rejected,
+            checksum,
+            max_payload,
+            source,
+            heartbeat_ms,
+        } => {
+            debug!("Received ticker dictionary {dictionary}");
+            for rejected in &rejected {
+                warn!("Ticker {} rejected: {}", rejected.ticker, rejected.code);
+            }
+            if dictionary.is_empty() {
+                return Err(ClientError::NoTickersAccepted(rejected));
+            }
+            info!(
+                "Streaming {} tickers, checksum {}, max payload {}",
+                dictionary.symbols().len(),
+                if checksum { "on" } else { "off" },
+                max_payload.map_or("unknown".to_string(), |max_payload| format!(
+                    "{max_payload} bytes"
+                ))
+            );
+            if accepted_format != format {
+                // servers without formats ignore the option and stream binary datagrams
+                warn!("Requested {format} format, server streams {accepted_format}");
+            }
+            Ok(Subscribed {
+                decoder: SessionDecoder::new(version, dictionary).with_format(accepted_format),
+                auth,
+                source,
+                heartbeat: heartbeat_ms.map(Duration::from_millis),
+            })
+        }
+        SubscribeResponse::Rejected(error) => Err(ClientError::SubscribeRejected(error)),
+    }
 }
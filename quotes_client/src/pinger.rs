@@ -7,7 +7,11 @@ use std::{
 
 use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded};
 use log::{trace, warn};
-use quotes_lib::{datagram::Datagram, subscribe_message::PingMessage};
+use quotes_lib::{
+    auth::FrameSealer,
+    datagram::{Datagram, DatagramRef},
+    subscribe_message::{PingMessage, VerifyMessage},
+};
 
 use crate::error::ClientError;
 
@@ -17,17 +21,23 @@ pub struct Pinger {
 }
 
 impl Pinger {
-    pub fn new(running: Arc<AtomicBool>, socket: Arc<UdpSocket>, interval: Duration) -> Self {
+    pub fn new(
+        running: Arc<AtomicBool>,
+        socket: Arc<UdpSocket>,
+        sealer: Option<FrameSealer>,
+        interval: Duration,
+    ) -> Self {
         let (command_tx, command_rx) = unbounded();
         Self {
             command_tx,
-            handle: Self::setup_thread(running, socket, command_rx, interval),
+            handle: Self::setup_thread(running, socket, sealer, command_rx, interval),
         }
     }
 
     fn setup_thread(
         running: Arc<AtomicBool>,
         socket: Arc<UdpSocket>,
+        mut sealer: Option<FrameSealer>,
         command_rx: Receiver<PingerCommand>,
         interval: Duration,
     ) -> JoinHandle<Result<(), ClientError>> {
@@ -37,6 +47,8 @@ impl Pinger {
             trace!("Starting pinger thread");
             let mut ping_address: Option<SocketAddr> = None;
             let mut error_count = 0;
+            let ping = Datagram::from(PingMessage);
+            let mut buf = [0u8; 128];
             while running.load(std::sync::atomic::Ordering::SeqCst) {
                 match command_rx.recv_timeout(interval) {
                     Ok(PingerCommand::Start(socket_addr)) => {
@@ -52,6 +64,23 @@ impl Pinger {
                             }
                         };
                     }
+                    Ok(PingerCommand::Verify(cookie)) => match ping_address {
+                        // echo goes through the same sealer, so its counter is not reused by pings
+                        Some(address) => {
+                            let verify = Datagram::from(VerifyMessage(cookie));
+                            let len = match sealer.as_mut() {
+                                Some(sealer) => {
+                                    sealer.encode_into(DatagramRef::from(&verify), &mut buf)?
+                                }
+                                None => verify.encode_into(&mut buf)?,
+                            };
+                            match socket.send_to(&buf[..len], address) {
+                                Ok(_) => trace!("Sent VERIFY to {address}"),
+                                Err(e) => warn!("Send verify error {e}"),
+                            }
+                        }
+                        None => warn!("Received Verify command before Start. Ignoring"),
+                    },
                     Err(RecvTimeoutError::Timeout) => {
                         // ping interval tick
                     }
@@ -62,9 +91,13 @@ impl Pinger {
                 }
 
                 if let Some(address) = ping_address {
-                    let buf: Vec<u8> = Datagram::new(PingMessage.into()).into();
+                    // every ping is sealed with new counter, so captured ones can't be replayed
+                    let len = match sealer.as_mut() {
+                        Some(sealer) => sealer.encode_into(DatagramRef::from(&ping), &mut buf)?,
+                        None => ping.encode_into(&mut buf)?,
+                    };
 
-                    if let Err(e) = socket.send_to(&buf, address) {
+                    if let Err(e) = socket.send_to(&buf[..len], address) {
                         error_count += 1;
                         warn!("Send ping error({error_count}) {e}");
                         if error_count >= MAX_ERRORS {
@@ -89,6 +122,13 @@ impl Pinger {
         Ok(())
     }
 
+    /// Echo stream challenge to address pings are sent to
+    pub fn verify(&self, cookie: u64) -> Result<(), ClientError> {
+        trace!("Echoing challenge");
+        self.command_tx.send(PingerCommand::Verify(cookie))?;
+        Ok(())
+    }
+
     pub fn shutdown(self) -> Result<(), ClientError> {
         trace!("Shutting down pinger");
 
@@ -98,4 +138,5 @@ impl Pinger {
 
 enum PingerCommand {
     Start(SocketAddr),
+    Verify(u64),
 }
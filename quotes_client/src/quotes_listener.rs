@@ -6,9 +6,16 @@ use std::{
     time::Duration,
 };
 
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, Sender};
 use log::{trace, warn};
-use quotes_lib::{datagram::DatagramParser, error::QuotesError, server_message::ServerMessage};
+use quotes_lib::{
+    datagram::DatagramParser,
+    error::QuotesError,
+    protocol::{ProtocolVersion, WireFormat},
+    sequence::{SequenceAnomaly, SequenceTracker},
+    server_message::ServerMessage,
+    session::{SessionDecoder, TickerDictionary},
+};
 
 use crate::error::ClientError;
 
@@ -20,22 +27,36 @@ impl QuotesListener {
     pub fn new(
         running: Arc<AtomicBool>,
         socket: Arc<UdpSocket>,
+        decoder: SessionDecoder,
+        datagram_parser: DatagramParser,
+        dictionary_rx: Receiver<TickerDictionary>,
         event_tx: Sender<QuotesListenerEvent>,
     ) -> Self {
         Self {
-            handle: Self::setup_thread(running, socket, event_tx),
+            handle: Self::setup_thread(
+                running,
+                socket,
+                decoder,
+                datagram_parser,
+                dictionary_rx,
+                event_tx,
+            ),
         }
     }
 
     fn setup_thread(
         running: Arc<AtomicBool>,
         socket: Arc<UdpSocket>,
+        mut decoder: SessionDecoder,
+        mut datagram_parser: DatagramParser,
+        dictionary_rx: Receiver<TickerDictionary>,
         event_tx: Sender<QuotesListenerEvent>,
     ) -> JoinHandle<Result<(), ClientError>> {
         const READ_TIMEOUT: Duration = Duration::from_millis(2000);
 
-        let mut datagram_parser = DatagramParser::new();
-        let mut buf = [0u8; 2048];
+        let mut sequence_tracker = SequenceTracker::new();
+        // big enough for any UDP datagram
+        let mut buf = vec![0u8; u16::MAX as usize];
 
         thread::spawn(move || {
             trace!("Starting quotes listener thread");
@@ -44,22 +65,45 @@ impl QuotesListener {
             while running.load(std::sync::atomic::Ordering::SeqCst) {
                 match socket.recv_from(&mut buf) {
                     Ok((len, address)) => {
-                        let data = datagram_parser.parse(&buf[..len]);
-                        let is_err = data.is_err();
-
-                        let datagrams = data.unwrap_or_else(|dgs| dgs);
-
-                        let mut messages = datagrams
-                            .into_iter()
-                            .map(|dg| match ServerMessage::try_from(dg) {
-                                Ok(msg) => QuotesListenerEvent::Message(msg, address),
-                                Err(e) => QuotesListenerEvent::from(e),
-                            })
-                            .collect::<Vec<_>>();
-
-                        if is_err {
-                            messages
-                                .push(QuotesListenerEvent::from(QuotesError::ParseDatagramError));
+                        // tickers added over control session get ids after known ones
+                        while let Ok(dictionary) = dictionary_rx.try_recv() {
+                            if let Err(e) = decoder.update_dictionary(dictionary) {
+                                warn!("Unable to update ticker dictionary {e}");
+                            }
+                        }
+
+                        let mut messages = vec![];
+
+                        let mut on_payload = |payload: &[u8]| match decoder.decode(payload) {
+                            Ok(msg) => {
+                                // legacy protocol has no sequence numbers to track
+                                if decoder.version() >= ProtocolVersion::V2
+                                    && let Some(anomaly) = sequence_tracker.track(msg.sequence)
+                                {
+                                    messages.push(QuotesListenerEvent::Sequence(anomaly));
+                                }
+                                messages.push(QuotesListenerEvent::Message(msg.message, address));
+                            }
+                            Err(e) => messages.push(QuotesListenerEvent::from(e)),
+                        };
+
+                        let parse_error = match decoder.format() {
+                            // datagrams are decoded straight from receive buffer
+                            WireFormat::Binary => datagram_parser
+                                .parse_with(&buf[..len], |dg| on_payload(dg.data))
+                                .err(),
+                            // JSON lines are not framed, every line is a message
+                            _ => {
+                                buf[..len]
+                                    .split(|b| *b == b'\n')
+                                    .filter(|line| !line.trim_ascii().is_empty())
+                                    .for_each(on_payload);
+                                None
+                            }
+                        };
+
+                        if let Some(e) = parse_error {
+                            messages.push(QuotesListenerEvent::from(e));
                         }
 
                         for message in messages {
@@ -69,7 +113,10 @@ impl QuotesListener {
                             }
                         }
                     }
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => {
+                    // platforms report read timeout as either kind
+                    Err(ref e)
+                        if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+                    {
                         // read timeout to check commands
                         continue;
                     }
@@ -84,6 +131,20 @@ impl QuotesListener {
                 }
             }
 
+            if datagram_parser.checksum_failures() > 0 {
+                warn!(
+                    "Dropped {} datagrams with checksum mismatch",
+                    datagram_parser.checksum_failures()
+                );
+            }
+
+            if datagram_parser.auth_failures() > 0 {
+                warn!(
+                    "Dropped {} unauthenticated datagrams",
+                    datagram_parser.auth_failures()
+                );
+            }
+
             trace!("Pinger thread finished successfully");
             Ok(())
         })
@@ -98,6 +159,7 @@ impl QuotesListener {
 
 pub enum QuotesListenerEvent {
     Message(ServerMessage, SocketAddr),
+    Sequence(SequenceAnomaly),
     Error(ClientError),
 }
 
@@ -0,0 +1,64 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use quotes_lib::{quote::Quote, top_of_book::TopOfBook, trade::Trade};
+
+use crate::error::ClientError;
+
+/// Appends received market data to a CSV file, one row per record:
+/// `quote,<ticker>,<timestamp>,<price>,<volume>` or
+/// `book,<ticker>,<timestamp>,<bid>,<bid size>,<ask>,<ask size>` or
+/// `trade,<ticker>,<timestamp>,<price>,<size>,<aggressor side>,<trade id>`
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: PathBuf) -> Result<Self, ClientError> {
+        let file = File::options().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record_quote(&mut self, quote: &Quote) -> Result<(), ClientError> {
+        writeln!(
+            self.writer,
+            "quote,{},{},{},{}",
+            quote.ticker, quote.timestamp, quote.price, quote.volume
+        )?;
+        Ok(())
+    }
+
+    pub fn record_book(&mut self, book: &TopOfBook) -> Result<(), ClientError> {
+        writeln!(
+            self.writer,
+            "book,{},{},{},{},{},{}",
+            book.ticker(),
+            book.timestamp(),
+            book.bid().price,
+            book.bid().size,
+            book.ask().price,
+            book.ask().size
+        )?;
+        Ok(())
+    }
+
+    pub fn record_trade(&mut self, trade: &Trade) -> Result<(), ClientError> {
+        writeln!(
+            self.writer,
+            "trade,{},{},{},{},{},{}",
+            trade.ticker, trade.timestamp, trade.price, trade.size, trade.aggressor, trade.trade_id
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), ClientError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,535 @@
+//! Datagram authentication with a pre-shared key
+//!
+//! Server picks random salt for every subscription and sends it in subscribe reply.
+//! Both sides derive a separate key for each direction from the pre-shared key and the salt,
+//! so frames can't be replayed into another session or reflected back to the sender.
+//!
+//! Protected frame data is `[counter: u64][payload][tag]`, counter grows with every frame
+//! and receiver drops frames with counters it has already seen.
+//! Tag covers frame header, counter and payload
+use std::{
+    fmt::{Debug, Display},
+    path::PathBuf,
+};
+
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    datagram::{Datagram, DatagramRef},
+    error::QuotesError,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const KEY_SIZE: usize = 32;
+
+/// Secret shared by server and client out of band
+#[derive(Clone)]
+pub struct PreSharedKey {
+    key: [u8; KEY_SIZE],
+}
+
+impl PreSharedKey {
+    const DERIVATION_LABEL: &[u8] = b"QDTG datagram key";
+
+    /// Create key from secret of any length
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: Sha256::digest(secret).into(),
+        }
+    }
+
+    /// Read secret from file, surrounding whitespace is ignored
+    pub fn from_file(path: PathBuf) -> Result<Self, QuotesError> {
+        let secret = std::fs::read(path)?;
+        let secret = secret.trim_ascii();
+        if secret.is_empty() {
+            return Err(QuotesError::AuthError(
+                "Pre-shared key file is empty".to_string(),
+            ));
+        }
+
+        Ok(Self::new(secret))
+    }
+
+    fn derive(&self, salt: &[u8; AuthParams::SALT_SIZE], direction: Direction) -> [u8; KEY_SIZE] {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(Self::DERIVATION_LABEL);
+        mac.update(&[direction as u8]);
+        mac.update(salt);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+/// Never prints the key itself
+impl Debug for PreSharedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PreSharedKey(..)")
+    }
+}
+
+/// Direction of datagrams, each one is protected with its own key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Market data stream
+    ServerToClient = 1,
+    /// Pings
+    ClientToServer = 2,
+}
+
+/// Protection of datagrams in authenticated session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum AuthMode {
+    /// HMAC-SHA256 tag, payload stays readable
+    Hmac,
+    /// ChaCha20-Poly1305, payload is encrypted
+    Encrypt,
+}
+
+impl Display for AuthMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthMode::Hmac => write!(f, "hmac"),
+            AuthMode::Encrypt => write!(f, "encrypt"),
+        }
+    }
+}
+
+impl TryFrom<&str> for AuthMode {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "hmac" => Ok(AuthMode::Hmac),
+            "encrypt" => Ok(AuthMode::Encrypt),
+            other => Err(QuotesError::AuthError(format!(
+                "Unsupported authentication mode {other}"
+            ))),
+        }
+    }
+}
+
+/// Authentication of a session chosen by server, written as `<mode>:<salt in hex>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "String", try_from = "String")
+)]
+pub struct AuthParams {
+    /// protection of datagrams
+    pub mode: AuthMode,
+    /// random value unique for session
+    pub salt: [u8; AuthParams::SALT_SIZE],
+}
+
+impl AuthParams {
+    /// Count of salt bytes
+    pub const SALT_SIZE: usize = 16;
+
+    /// Create new AuthParams
+    pub fn new(mode: AuthMode, salt: [u8; Self::SALT_SIZE]) -> Self {
+        Self { mode, salt }
+    }
+
+    /// Protects datagrams sent in given direction
+    pub fn sealer(&self, key: &PreSharedKey, direction: Direction) -> FrameSealer {
+        FrameSealer {
+            cipher: Cipher::new(self.mode, key.derive(&self.salt, direction)),
+            counter: 0,
+        }
+    }
+
+    /// Verifies datagrams received from given direction
+    pub fn opener(&self, key: &PreSharedKey, direction: Direction) -> FrameOpener {
+        FrameOpener {
+            cipher: Cipher::new(self.mode, key.derive(&self.salt, direction)),
+            replay_window: ReplayWindow::default(),
+        }
+    }
+}
+
+impl Display for AuthParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.mode)?;
+        self.salt
+            .iter()
+            .try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl TryFrom<&str> for AuthParams {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let error = || QuotesError::AuthError(format!("Invalid authentication parameters {value}"));
+
+        let (mode, salt_hex) = value.split_once(':').ok_or_else(error)?;
+        let salt = decode_hex(salt_hex).ok_or_else(error)?;
+
+        Ok(Self::new(AuthMode::try_from(mode)?, salt))
+    }
+}
+
+impl From<AuthParams> for String {
+    fn from(value: AuthParams) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for AuthParams {
+    type Error = QuotesError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_str())
+    }
+}
+
+/// Fixed-size byte array from hex string, None if length or digits are wrong
+pub(crate) fn decode_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != 2 * N || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (byte, hex) in bytes.iter_mut().zip(text.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+pub(crate) const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+enum Cipher {
+    Hmac(HmacSha256),
+    Encrypt(Box<ChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(mode: AuthMode, key: [u8; KEY_SIZE]) -> Self {
+        match mode {
+            AuthMode::Hmac => Cipher::Hmac(
+                <HmacSha256 as Mac>::new_from_slice(&key).expect("HMAC accepts keys of any length"),
+            ),
+            AuthMode::Encrypt => Cipher::Encrypt(Box::new(ChaCha20Poly1305::new(&key.into()))),
+        }
+    }
+
+    /// Counters never repeat within a key, so they are safe to use as nonces
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[Nonce::default().len() - COUNTER_SIZE..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn hmac_tag(mac: &HmacSha256, header: &[u8], payload: &[u8]) -> HmacSha256 {
+        let mut mac = mac.clone();
+        mac.update(header);
+        mac.update(payload);
+        mac
+    }
+}
+
+/// Adds counter and tag to outgoing frames, encrypting payload if required
+pub struct FrameSealer {
+    cipher: Cipher,
+    counter: u64,
+}
+
+impl FrameSealer {
+    /// Count of bytes protection adds to frame data
+    pub const OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+
+    /// Same as Datagram::frame_into, but frame is protected
+    pub fn frame_into(
+        &mut self,
+        buf: &mut [u8],
+        checksum: bool,
+        write_data: impl FnOnce(&mut [u8]) -> Result<usize, QuotesError>,
+    ) -> Result<usize, QuotesError> {
+        Datagram::frame_into_with(buf, checksum, Some(self), write_data)
+    }
+
+    /// Encode protected frame with given datagram data into buffer
+    pub fn encode_into(
+        &mut self,
+        datagram: DatagramRef<'_>,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        self.frame_into(buf, datagram.checksum, |data| {
+            Datagram::copy_data(datagram.data, data)
+        })
+    }
+
+    /// Seal frame without CRC trailer, header is already written
+    /// and payload is placed after space reserved for counter
+    pub(crate) fn seal(&mut self, frame: &mut [u8]) {
+        let counter = self.counter;
+        self.counter += 1;
+
+        let (header, rest) = frame.split_at_mut(Datagram::PREFIX_LEN + COUNTER_SIZE);
+        header[Datagram::PREFIX_LEN..].copy_from_slice(&counter.to_be_bytes());
+        let (payload, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+
+        match &self.cipher {
+            Cipher::Hmac(mac) => {
+                let mac = Cipher::hmac_tag(mac, header, payload)
+                    .finalize()
+                    .into_bytes();
+                tag.copy_from_slice(&mac[..TAG_SIZE]);
+            }
+            Cipher::Encrypt(cipher) => {
+                let sealed = cipher
+                    .encrypt_in_place_detached(&Cipher::nonce(counter), header, payload)
+                    .expect("Frame payload is far below cipher limit");
+                tag.copy_from_slice(&sealed);
+            }
+        }
+    }
+}
+
+/// Verifies incoming frames and drops replayed ones
+pub struct FrameOpener {
+    cipher: Cipher,
+    replay_window: ReplayWindow,
+}
+
+impl FrameOpener {
+    /// Verify frame without CRC trailer, returns payload.
+    /// Encrypted payload is decrypted into scratch buffer, verified one is borrowed from frame
+    pub(crate) fn open<'a>(
+        &mut self,
+        frame: &'a [u8],
+        scratch: &'a mut Vec<u8>,
+    ) -> Option<&'a [u8]> {
+        if frame.len() < Datagram::PREFIX_LEN + FrameSealer::OVERHEAD {
+            return None;
+        }
+
+        let (header, rest) = frame.split_at(Datagram::PREFIX_LEN + COUNTER_SIZE);
+        let (payload, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+        let mut counter = [0u8; COUNTER_SIZE];
+        counter.copy_from_slice(&header[Datagram::PREFIX_LEN..]);
+        let counter = u64::from_be_bytes(counter);
+        if !self.replay_window.is_fresh(counter) {
+            return None;
+        }
+
+        let payload = match &self.cipher {
+            Cipher::Hmac(mac) => {
+                Cipher::hmac_tag(mac, header, payload)
+                    .verify_truncated_left(tag)
+                    .ok()?;
+                payload
+            }
+            Cipher::Encrypt(cipher) => {
+                scratch.clear();
+                scratch.extend_from_slice(payload);
+                cipher
+                    .decrypt_in_place_detached(
+                        &Cipher::nonce(counter),
+                        header,
+                        scratch,
+                        Tag::from_slice(tag),
+                    )
+                    .ok()?;
+                scratch.as_slice()
+            }
+        };
+
+        self.replay_window.accept(counter);
+        Some(payload)
+    }
+}
+
+/// Counters seen recently, frames reordered by network within the window are still accepted
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// bit `n` is set if counter `highest - n` was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    const SIZE: u64 = u64::BITS as u64;
+
+    fn is_fresh(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let age = highest - counter;
+                age < Self::SIZE && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.seen |= 1 << (highest - counter),
+            _ => {
+                let shift = self.highest.map_or(Self::SIZE, |highest| counter - highest);
+                self.seen = if shift >= Self::SIZE {
+                    0
+                } else {
+                    self.seen << shift
+                };
+                self.seen |= 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+    use crate::datagram::DatagramParser;
+
+    fn params(mode: AuthMode) -> AuthParams {
+        AuthParams::new(mode, [7; AuthParams::SALT_SIZE])
+    }
+
+    fn seal(sealer: &mut FrameSealer, data: &[u8], checksum: bool) -> Vec<u8> {
+        let mut buf = [0u8; 128];
+        let datagram = DatagramRef { data, checksum };
+        let len = sealer.encode_into(datagram, &mut buf).expect("Should fit");
+        buf[..len].to_vec()
+    }
+
+    fn received(parser: &mut DatagramParser, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut datas = vec![];
+        parser
+            .parse_with(bytes, |dg| datas.push(dg.data.to_vec()))
+            .expect("Should parse");
+        datas
+    }
+
+    #[test]
+    fn test_params_roundtrip() {
+        for mode in [AuthMode::Hmac, AuthMode::Encrypt] {
+            let params = params(mode);
+            assert_eq!(
+                AuthParams::try_from(params.to_string().as_str()).expect("Should parse"),
+                params
+            );
+        }
+
+        for text in [
+            "hmac",
+            "hmac:0707",
+            "rot13:07070707070707070707070707070707",
+        ] {
+            assert!(AuthParams::try_from(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let key = PreSharedKey::new(b"secret");
+
+        for mode in [AuthMode::Hmac, AuthMode::Encrypt] {
+            for checksum in [false, true] {
+                let mut sealer = params(mode).sealer(&key, Direction::ServerToClient);
+                let opener = params(mode).opener(&key, Direction::ServerToClient);
+                let mut parser = DatagramParser::new().with_opener(opener);
+
+                let bytes = seal(&mut sealer, b"hello", checksum);
+                assert_eq!(
+                    bytes.windows(5).any(|window| window == b"hello"),
+                    mode == AuthMode::Hmac
+                );
+                assert_eq!(received(&mut parser, &bytes), vec![b"hello".to_vec()]);
+                assert_eq!(parser.auth_failures(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_forged_frames() {
+        let key = PreSharedKey::new(b"secret");
+
+        for mode in [AuthMode::Hmac, AuthMode::Encrypt] {
+            let mut sealer = params(mode).sealer(&key, Direction::ServerToClient);
+            let mut parser = DatagramParser::new()
+                .with_opener(params(mode).opener(&key, Direction::ServerToClient));
+
+            let mut tampered = seal(&mut sealer, b"hello", false);
+            let last = tampered.len() - 1;
+            tampered[last] ^= 1;
+            let plain = Vec::<u8>::try_from(Datagram::new(b"hello".to_vec()))
+                .expect("Should encode datagram");
+            let wrong_key = seal(
+                &mut params(mode).sealer(&PreSharedKey::new(b"guess"), Direction::ServerToClient),
+                b"hello",
+                false,
+            );
+            let reflected = seal(
+                &mut params(mode).sealer(&key, Direction::ClientToServer),
+                b"hello",
+                false,
+            );
+            let other_session = seal(
+                &mut AuthParams::new(mode, [8; AuthParams::SALT_SIZE])
+                    .sealer(&key, Direction::ServerToClient),
+                b"hello",
+                false,
+            );
+
+            for (i, bytes) in [tampered, plain, wrong_key, reflected, other_session]
+                .iter()
+                .enumerate()
+            {
+                assert!(received(&mut parser, bytes).is_empty(), "frame {i}");
+                assert_eq!(parser.auth_failures(), i + 1);
+            }
+
+            // parser is still usable after dropped frames
+            let bytes = seal(&mut sealer, b"valid", false);
+            assert_eq!(received(&mut parser, &bytes), vec![b"valid".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn test_rejects_replay() {
+        let key = PreSharedKey::new(b"secret");
+        let mut sealer = params(AuthMode::Hmac).sealer(&key, Direction::ClientToServer);
+        let mut parser = DatagramParser::new()
+            .with_opener(params(AuthMode::Hmac).opener(&key, Direction::ClientToServer));
+
+        let first = seal(&mut sealer, b"1", false);
+        let second = seal(&mut sealer, b"2", false);
+
+        // reordered frames are fine, repeated ones are not
+        assert_eq!(received(&mut parser, &second), vec![b"2".to_vec()]);
+        assert_eq!(received(&mut parser, &first), vec![b"1".to_vec()]);
+        assert!(received(&mut parser, &first).is_empty());
+        assert!(received(&mut parser, &second).is_empty());
+        assert_eq!(parser.auth_failures(), 2);
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+
+        window.accept(100);
+        assert!(!window.is_fresh(100));
+        assert!(window.is_fresh(99));
+        assert!(window.is_fresh(101));
+
+        window.accept(100 + ReplayWindow::SIZE);
+        // too old to tell whether it was seen
+        assert!(!window.is_fresh(100));
+        assert!(window.is_fresh(101));
+    }
+}
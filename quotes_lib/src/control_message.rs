@@ -0,0 +1,121 @@
+//! Commands changing live subscription, sent over TCP connection kept open after subscribe
+use std::fmt::Display;
+
+use crate::{error::QuotesError, pattern::TickerPattern, subscribe_message::VerifyMessage};
+
+/// Client command for its existing stream, server replies to every command except unsubscribe
+/// with [`SubscribeResponse`](crate::subscribe_response::SubscribeResponse)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ControlMessage {
+    /// Stream given tickers in addition to current ones
+    Add(Vec<String>),
+    /// Stop streaming given tickers
+    Remove(Vec<String>),
+    /// Stream only given tickers
+    Replace(Vec<String>),
+    /// Stop the stream, server closes connection
+    Unsubscribe,
+    /// Echo cookie of stream challenge, for clients that can't send it over UDP
+    Verify(u64),
+}
+
+impl ControlMessage {
+    const ADD_HEADER: &str = "ADD";
+    const REMOVE_HEADER: &str = "REMOVE";
+    const REPLACE_HEADER: &str = "REPLACE";
+    const UNSUBSCRIBE_HEADER: &str = "UNSUBSCRIBE";
+
+    fn tickers(value: &str) -> Result<Vec<String>, QuotesError> {
+        let tickers = value
+            .split(",")
+            .map(|ticker| ticker.to_string())
+            .collect::<Vec<_>>();
+        if tickers.iter().any(String::is_empty) {
+            return Err(QuotesError::ParseClientMessageError(format!(
+                "Malformed ticker list {value:?}"
+            )));
+        }
+        TickerPattern::check_selectors(&tickers)?;
+
+        Ok(tickers)
+    }
+}
+
+impl Display for ControlMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (header, tickers) = match self {
+            ControlMessage::Add(tickers) => (Self::ADD_HEADER, tickers),
+            ControlMessage::Remove(tickers) => (Self::REMOVE_HEADER, tickers),
+            ControlMessage::Replace(tickers) => (Self::REPLACE_HEADER, tickers),
+            ControlMessage::Unsubscribe => return write!(f, "{}", Self::UNSUBSCRIBE_HEADER),
+            ControlMessage::Verify(cookie) => return write!(f, "{}", VerifyMessage(*cookie)),
+        };
+        write!(f, "{header} {}", tickers.join(","))
+    }
+}
+
+impl TryFrom<&str> for ControlMessage {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let parts = value.split_whitespace().collect::<Vec<_>>();
+
+        match parts[..] {
+            [Self::ADD_HEADER, tickers] => Ok(ControlMessage::Add(Self::tickers(tickers)?)),
+            [Self::REMOVE_HEADER, tickers] => Ok(ControlMessage::Remove(Self::tickers(tickers)?)),
+            [Self::REPLACE_HEADER, tickers] => Ok(ControlMessage::Replace(Self::tickers(tickers)?)),
+            [Self::UNSUBSCRIBE_HEADER] => Ok(ControlMessage::Unsubscribe),
+            [VerifyMessage::HEADER, _] => {
+                Ok(ControlMessage::Verify(VerifyMessage::try_from(value)?.0))
+            }
+            _ => Err(QuotesError::ParseClientMessageError(format!(
+                "Unexpected control message {:?}",
+                value.trim()
+            ))),
+        }
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_control_roundtrip() {
+        let tickers = vec!["AAPL".to_string(), "MSFT".to_string()];
+
+        for message in [
+            ControlMessage::Add(tickers.clone()),
+            ControlMessage::Remove(tickers.clone()),
+            ControlMessage::Replace(tickers),
+            ControlMessage::Unsubscribe,
+            ControlMessage::Verify(42),
+        ] {
+            assert_eq!(
+                ControlMessage::try_from(format!("{message}\n").as_str()).expect("Should parse"),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_control() {
+        for text in [
+            "",
+            "ADD",
+            "ADD AAPL,,MSFT",
+            "ADD AAPL MSFT",
+            "UNSUBSCRIBE AAPL",
+            "VERIFY cookie",
+            "PING",
+            &format!("ADD {}", "*".repeat(TickerPattern::MAX_LEN + 1)),
+        ] {
+            assert!(ControlMessage::try_from(text).is_err(), "{text:?}");
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Client credentials presented on subscribe and the server-side store they are checked against
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    path::PathBuf,
+};
+
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::{auth, error::QuotesError};
+
+/// Credentials sent as options of subscribe message, values can't contain whitespace
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Credentials {
+    /// Bearer token issued to client, `<client>.<secret>`
+    Token(String),
+    /// Named user with password
+    Password {
+        /// user name
+        user: String,
+        /// plain password, only its salted hash is stored by server
+        password: String,
+    },
+}
+
+impl Credentials {
+    pub(crate) const TOKEN_OPTION: &str = "token";
+    pub(crate) const USER_OPTION: &str = "user";
+    pub(crate) const PASSWORD_OPTION: &str = "password";
+    const TOKEN_SPLITTER: char = '.';
+
+    /// Create token credentials
+    pub fn token(token: &str) -> Result<Self, QuotesError> {
+        Ok(Credentials::Token(Self::checked(token, "token")?))
+    }
+
+    /// Create user credentials
+    pub fn password(user: &str, password: &str) -> Result<Self, QuotesError> {
+        Ok(Credentials::Password {
+            user: Self::checked(user, "user")?,
+            password: Self::checked(password, "password")?,
+        })
+    }
+
+    /// Read token from file, surrounding whitespace is ignored
+    pub fn token_from_file(path: PathBuf) -> Result<Self, QuotesError> {
+        Self::token(&std::fs::read_to_string(path)?)
+    }
+
+    /// Read password of given user from file, surrounding whitespace is ignored
+    pub fn password_from_file(user: &str, path: PathBuf) -> Result<Self, QuotesError> {
+        Self::password(user, &std::fs::read_to_string(path)?)
+    }
+
+    /// User name, None for tokens
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Credentials::Token(_) => None,
+            Credentials::Password { user, .. } => Some(user),
+        }
+    }
+
+    /// Build credentials from options of subscribe message, None if there are none
+    pub(crate) fn from_options(
+        token: Option<&str>,
+        user: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Option<Self>, QuotesError> {
+        match (token, user, password) {
+            (None, None, None) => Ok(None),
+            (Some(token), None, None) => Self::token(token).map(Some),
+            (None, Some(user), Some(password)) => Self::password(user, password).map(Some),
+            _ => Err(QuotesError::ParseClientMessageError(
+                "Credentials need either token or both user and password".to_string(),
+            )),
+        }
+    }
+
+    fn checked(value: &str, name: &str) -> Result<String, QuotesError> {
+        let value = value.trim();
+        if value.is_empty() || value.contains(char::is_whitespace) {
+            return Err(QuotesError::ParseClientMessageError(format!(
+                "Credentials {name} must be non-empty and without whitespace"
+            )));
+        }
+
+        Ok(value.to_string())
+    }
+}
+
+/// Written as subscribe message options
+impl Display for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Token(token) => write!(f, "{}={token}", Self::TOKEN_OPTION),
+            Credentials::Password { user, password } => write!(
+                f,
+                "{}={user} {}={password}",
+                Self::USER_OPTION,
+                Self::PASSWORD_OPTION
+            ),
+        }
+    }
+}
+
+/// Never prints secrets
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Token(_) => write!(f, "Token(..)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {user:?}, .. }}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SecretKind {
+    Token,
+    Password,
+}
+
+impl TryFrom<&str> for SecretKind {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            Credentials::TOKEN_OPTION => Ok(SecretKind::Token),
+            Credentials::PASSWORD_OPTION => Ok(SecretKind::Password),
+            other => Err(QuotesError::ConfigError(format!(
+                "Unknown secret kind {other}"
+            ))),
+        }
+    }
+}
+
+/// Salted slow hash of secret, `pbkdf2-sha256$<iterations>$<salt in hex>$<hash in hex>`
+#[derive(Debug, Clone)]
+struct SecretHash {
+    iterations: u32,
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl SecretHash {
+    const SCHEME: &str = "pbkdf2-sha256";
+
+    /// Derive hash of secret with the same salt and compare in constant time
+    fn matches(&self, secret: &str) -> bool {
+        let mut derived = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(secret.as_bytes(), &self.salt, self.iterations, &mut derived);
+        derived.ct_eq(&self.hash).into()
+    }
+}
+
+impl TryFrom<&str> for SecretHash {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let error = || QuotesError::ConfigError(format!("Malformed secret hash {value}"));
+
+        let parts = value.split('$').collect::<Vec<_>>();
+        let [scheme, iterations, salt, hash] = parts[..] else {
+            return Err(error());
+        };
+        if scheme != Self::SCHEME {
+            return Err(QuotesError::ConfigError(format!(
+                "Unsupported secret hash {scheme}, expected {}",
+                Self::SCHEME
+            )));
+        }
+
+        Ok(Self {
+            iterations: iterations
+                .parse()
+                .ok()
+                .filter(|iterations| *iterations > 0)
+                .ok_or_else(error)?,
+            salt: auth::decode_hex(salt).ok_or_else(error)?,
+            hash: auth::decode_hex(hash).ok_or_else(error)?,
+        })
+    }
+}
+
+/// Known clients, one per line: `<client> token|password pbkdf2-sha256$<iterations>$<salt>$<hash>`,
+/// salt is 16 bytes and hash is 32 bytes in hex. Empty lines and lines starting with `#` are skipped.
+/// Client has at most one secret of each kind, token entries hash only the secret after the client name
+#[derive(Debug, Clone, Default)]
+pub struct CredentialsStore {
+    entries: HashMap<(SecretKind, String), SecretHash>,
+}
+
+impl CredentialsStore {
+    /// Read store from file
+    pub fn from_file(path: PathBuf) -> Result<Self, QuotesError> {
+        Self::try_from(std::fs::read_to_string(path)?.as_str())
+    }
+
+    /// Count of known clients
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Store has no clients, so every subscription is rejected
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check credentials, returns name of client they belong to
+    pub fn verify(&self, credentials: Option<&Credentials>) -> Result<&str, QuotesError> {
+        let credentials = credentials
+            .ok_or_else(|| QuotesError::AccessDenied("Credentials required".to_string()))?;
+
+        let (kind, client, secret, error) = match credentials {
+            Credentials::Token(token) => {
+                let (client, secret) = token
+                    .rsplit_once(Credentials::TOKEN_SPLITTER)
+                    .unwrap_or_default();
+                (SecretKind::Token, client, secret, "Invalid token")
+            }
+            Credentials::Password { user, password } => (
+                SecretKind::Password,
+                user.as_str(),
+                password.as_str(),
+                "Invalid user or password",
+            ),
+        };
+
+        // hash is slow on purpose, so it is derived at most once, for the entry of named client
+        self.entries
+            .get_key_value(&(kind, client.to_string()))
+            .filter(|(_, hash)| hash.matches(secret))
+            .map(|((_, client), _)| client.as_str())
+            .ok_or_else(|| QuotesError::AccessDenied(error.to_string()))
+    }
+}
+
+impl TryFrom<&str> for CredentialsStore {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut entries = HashMap::new();
+        for (index, line) in value.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+            let [client, kind, secret] = parts[..] else {
+                return Err(QuotesError::ConfigError(format!(
+                    "Malformed credentials line {}",
+                    index + 1
+                )));
+            };
+
+            let key = (SecretKind::try_from(kind)?, client.to_string());
+            if entries.insert(key, SecretHash::try_from(secret)?).is_some() {
+                return Err(QuotesError::ConfigError(format!(
+                    "Duplicate {kind} of {client} on line {}",
+                    index + 1
+                )));
+            }
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    // hashes of "secret" and "hunter2", few iterations to keep tests fast
+    const STORE: &str = "
+        # client kind hash
+        feed-bot token pbkdf2-sha256$1000$00112233445566778899aabbccddeeff$588372775fb38b5711b6f36b465e293dc7b793e008550ee8a10bb26a8e7f85b8
+        alice password pbkdf2-sha256$1000$ffeeddccbbaa99887766554433221100$cf92a6c7f4f3ed28383cbd47720e856a54d96fb60739b4e6da41ef1b6370a0db
+    ";
+
+    #[test]
+    fn test_verify() {
+        let store = CredentialsStore::try_from(STORE).expect("Should parse");
+        assert_eq!(store.len(), 2);
+
+        let token = Credentials::token("feed-bot.secret").expect("Should be valid token");
+        let password = Credentials::password("alice", "hunter2").expect("Should be valid password");
+        assert_eq!(
+            store.verify(Some(&token)).expect("Should accept token"),
+            "feed-bot"
+        );
+        assert_eq!(
+            store
+                .verify(Some(&password))
+                .expect("Should accept password"),
+            "alice"
+        );
+
+        for credentials in [
+            None,
+            Some(Credentials::token("secret")),
+            Some(Credentials::token("feed-bot.hunter2")),
+            Some(Credentials::token("alice.hunter2")),
+            Some(Credentials::password("alice", "secret")),
+            Some(Credentials::password("feed-bot", "secret")),
+            Some(Credentials::password("bob", "hunter2")),
+        ]
+        .map(|credentials| credentials.map(|credentials| credentials.expect("Should be valid")))
+        {
+            assert!(
+                matches!(
+                    store.verify(credentials.as_ref()),
+                    Err(QuotesError::AccessDenied(_))
+                ),
+                "{credentials:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_store() {
+        for text in [
+            "alice password",
+            "alice pin pbkdf2-sha256$1000$ffeeddccbbaa99887766554433221100$cf92a6c7f4f3ed28383cbd47720e856a54d96fb60739b4e6da41ef1b6370a0db",
+            "alice password pbkdf2-sha256$0$ffeeddccbbaa99887766554433221100$cf92a6c7f4f3ed28383cbd47720e856a54d96fb60739b4e6da41ef1b6370a0db",
+            "alice password pbkdf2-sha256$1000$ffee$cf92a6c7f4f3ed28383cbd47720e856a54d96fb60739b4e6da41ef1b6370a0db",
+            // unsalted SHA-256 of "hunter2"
+            "alice password f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7",
+            "alice password pbkdf2-sha256$1000$ffeeddccbbaa99887766554433221100$cf92a6c7f4f3ed28383cbd47720e856a54d96fb60739b4e6da41ef1b6370a0db
+             alice password pbkdf2-sha256$1000$00112233445566778899aabbccddeeff$588372775fb38b5711b6f36b465e293dc7b793e008550ee8a10bb26a8e7f85b8",
+        ] {
+            assert!(
+                matches!(
+                    CredentialsStore::try_from(text),
+                    Err(QuotesError::ConfigError(_))
+                ),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_secrets_in_debug() {
+        let credentials =
+            Credentials::password("alice", "hunter2").expect("Should be valid password");
+        assert!(!format!("{credentials:?}").contains("hunter2"));
+        assert!(Credentials::token("two words").is_err());
+    }
+}
@@ -1,25 +1,215 @@
 //! Module for reading and writing messages as datagrams
-
-use crate::{server_message::ServerMessage, subscribe_message::PingMessage};
+//!
+//! Frame layout: `QDTG` header, big-endian `u16` word with flags in high bits
+//! and data length in low bits, data, optional big-endian CRC32 trailer.
+//! Frames of authenticated sessions carry counter and tag around data, see [crate::auth].
+//! Only authenticated sessions take the second high bit as a flag marking sealed frames,
+//! for the others it stays a part of data length
+
+use crate::{
+    auth::{self, FrameOpener, FrameSealer},
+    error::QuotesError,
+    protocol::ProtocolVersion,
+    server_message::SequencedMessage,
+    subscribe_message::{PingMessage, VerifyMessage},
+};
 
 /// Struct to wrap data as datagrams
 #[derive(Debug, PartialEq, Eq)]
 pub struct Datagram {
     /// data to send
     pub data: Vec<u8>,
+    /// frame carries CRC32 trailer
+    pub checksum: bool,
+}
+
+/// Datagram borrowed from the buffer it was parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatagramRef<'a> {
+    /// data carried by frame
+    pub data: &'a [u8],
+    /// frame carries CRC32 trailer
+    pub checksum: bool,
+}
+
+impl DatagramRef<'_> {
+    /// Encode frame into buffer, returns count of bytes written
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, QuotesError> {
+        Datagram::frame_into(buf, self.checksum, |data| {
+            Datagram::copy_data(self.data, data)
+        })
+    }
+
+    fn bytes_length(&self) -> usize {
+        Datagram::overhead(self.checksum) + self.data.len()
+    }
+}
+
+impl<'a> From<&'a Datagram> for DatagramRef<'a> {
+    fn from(value: &'a Datagram) -> Self {
+        Self {
+            data: &value.data,
+            checksum: value.checksum,
+        }
+    }
+}
+
+impl From<DatagramRef<'_>> for Datagram {
+    fn from(value: DatagramRef<'_>) -> Self {
+        Self {
+            data: value.data.to_vec(),
+            checksum: value.checksum,
+        }
+    }
 }
 
 impl Datagram {
     /// Create new datagram
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data }
+        Self {
+            data,
+            checksum: false,
+        }
+    }
+
+    /// Create new datagram protected by CRC32 trailer
+    pub fn with_checksum(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            checksum: true,
+        }
     }
 
     const HEADER: &[u8; 4] = b"QDTG";
+    const LENGTH_SIZE: usize = 2;
+    const CHECKSUM_SIZE: usize = 4;
+
+    const CHECKSUM_FLAG: u16 = 0x8000;
+    const AUTH_FLAG: u16 = 0x4000;
+    const LENGTH_MASK: u16 = 0x7FFF;
+    const SEALED_LENGTH_MASK: u16 = 0x3FFF;
+
+    /// Bytes before data: header and length word
+    pub(crate) const PREFIX_LEN: usize = Self::HEADER.len() + Self::LENGTH_SIZE;
+
+    /// Max data length that fits into frame length field
+    pub const MAX_DATA_LEN: usize = Self::LENGTH_MASK as usize;
+
+    /// Max data length, counter and tag included, that fits into sealed frame length field
+    pub const MAX_SEALED_DATA_LEN: usize = Self::SEALED_LENGTH_MASK as usize;
+
+    /// Max length of the whole frame, checksum included
+    pub const MAX_FRAME_LEN: usize =
+        Self::HEADER.len() + Self::LENGTH_SIZE + Self::MAX_DATA_LEN + Self::CHECKSUM_SIZE;
+
+    /// Count of bytes frame adds to data
+    pub fn overhead(checksum: bool) -> usize {
+        let checksum_len = if checksum { Self::CHECKSUM_SIZE } else { 0 };
+        Self::HEADER.len() + Self::LENGTH_SIZE + checksum_len
+    }
+
+    /// Encode frame into buffer, returns count of bytes written
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, QuotesError> {
+        DatagramRef::from(self).encode_into(buf)
+    }
+
+    /// Frame data straight into buffer, so it doesn't have to be encoded anywhere else first.
+    /// `write_data` gets the part of buffer reserved for data and returns count of bytes written,
+    /// the whole frame length is returned
+    pub fn frame_into(
+        buf: &mut [u8],
+        checksum: bool,
+        write_data: impl FnOnce(&mut [u8]) -> Result<usize, QuotesError>,
+    ) -> Result<usize, QuotesError> {
+        Self::frame_into_with(buf, checksum, None, write_data)
+    }
+
+    /// Frame data into buffer, sealing it if sealer is given
+    pub(crate) fn frame_into_with(
+        buf: &mut [u8],
+        checksum: bool,
+        sealer: Option<&mut FrameSealer>,
+        write_data: impl FnOnce(&mut [u8]) -> Result<usize, QuotesError>,
+    ) -> Result<usize, QuotesError> {
+        let (auth_overhead, payload_start, max_data_len) = match sealer {
+            Some(_) => (
+                FrameSealer::OVERHEAD,
+                Self::PREFIX_LEN + auth::COUNTER_SIZE,
+                Self::MAX_SEALED_DATA_LEN,
+            ),
+            None => (0, Self::PREFIX_LEN, Self::MAX_DATA_LEN),
+        };
+        let overhead = Self::overhead(checksum) + auth_overhead;
+        if buf.len() < overhead {
+            return Err(QuotesError::BufferTooSmall(format!(
+                "frame needs at least {overhead} bytes, {} available",
+                buf.len()
+            )));
+        }
+
+        let payload_capacity = (buf.len() - overhead).min(max_data_len - auth_overhead);
+        let payload_len = write_data(&mut buf[payload_start..payload_start + payload_capacity])?;
+        let data_len = payload_len + auth_overhead;
+        let data_end = Self::PREFIX_LEN + data_len;
+
+        buf[..Self::HEADER.len()].copy_from_slice(Self::HEADER);
+        buf[Self::HEADER.len()..Self::PREFIX_LEN].copy_from_slice(
+            &Self::length_word(data_len, checksum, sealer.is_some())?.to_be_bytes(),
+        );
+
+        if let Some(sealer) = sealer {
+            sealer.seal(&mut buf[..data_end]);
+        }
+
+        if !checksum {
+            return Ok(data_end);
+        }
+
+        let frame_end = data_end + Self::CHECKSUM_SIZE;
+        let checksum = crc32(&buf[..data_end]);
+        buf[data_end..frame_end].copy_from_slice(&checksum.to_be_bytes());
+        Ok(frame_end)
+    }
+
+    /// Copy ready data into the part of buffer reserved for it
+    pub(crate) fn copy_data(data: &[u8], buf: &mut [u8]) -> Result<usize, QuotesError> {
+        if buf.len() < data.len() {
+            return Err(QuotesError::BufferTooSmall(format!(
+                "{} data bytes don't fit into {}",
+                data.len(),
+                buf.len()
+            )));
+        }
+
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    /// Fails if data length doesn't fit into length field, the frame would be corrupted otherwise
+    fn length_word(data_len: usize, checksum: bool, auth: bool) -> Result<u16, QuotesError> {
+        let max_data_len = if auth {
+            Self::MAX_SEALED_DATA_LEN
+        } else {
+            Self::MAX_DATA_LEN
+        };
+        if data_len > max_data_len {
+            return Err(QuotesError::EncodeError(format!(
+                "Datagram data of {data_len} bytes is too long, max is {max_data_len}"
+            )));
+        }
+
+        let mut word = data_len as u16;
+        if checksum {
+            word |= Self::CHECKSUM_FLAG;
+        }
+        if auth {
+            word |= Self::AUTH_FLAG;
+        }
+        Ok(word)
+    }
 
     fn bytes_length(&self) -> usize {
-        let data_len = (self.data.len() as u16).to_be_bytes();
-        Self::HEADER.len() + data_len.len() + self.data.len()
+        Self::overhead(self.checksum) + self.data.len()
     }
 
     fn seek_header(buf: &[u8]) -> Option<usize> {
@@ -28,23 +218,32 @@ impl Datagram {
     }
 }
 
-impl From<Datagram> for Vec<u8> {
-    fn from(value: Datagram) -> Self {
-        let data_len = (value.data.len() as u16).to_be_bytes();
-        let mut buffer =
-            Vec::with_capacity(Datagram::HEADER.len() + data_len.len() + value.data.len());
+/// Fails if data is longer than [`Datagram::MAX_DATA_LEN`]
+impl TryFrom<Datagram> for Vec<u8> {
+    type Error = QuotesError;
+
+    fn try_from(value: Datagram) -> Result<Self, Self::Error> {
+        let length_word = Datagram::length_word(value.data.len(), value.checksum, false)?;
+        let mut buffer = Vec::with_capacity(value.bytes_length());
 
         buffer.extend_from_slice(Datagram::HEADER);
-        buffer.extend_from_slice(&data_len);
+        buffer.extend_from_slice(&length_word.to_be_bytes());
         buffer.extend_from_slice(&value.data);
 
-        buffer
+        if value.checksum {
+            let checksum = crc32(&buffer);
+            buffer.extend_from_slice(&checksum.to_be_bytes());
+        }
+
+        Ok(buffer)
     }
 }
 
-impl From<ServerMessage> for Datagram {
-    fn from(value: ServerMessage) -> Self {
-        Datagram::new(value.into())
+impl TryFrom<SequencedMessage> for Datagram {
+    type Error = QuotesError;
+
+    fn try_from(value: SequencedMessage) -> Result<Self, Self::Error> {
+        Ok(Datagram::new(value.encode(ProtocolVersion::LATEST)?))
     }
 }
 
@@ -54,18 +253,68 @@ impl From<PingMessage> for Datagram {
     }
 }
 
+impl From<VerifyMessage> for Datagram {
+    fn from(value: VerifyMessage) -> Self {
+        Datagram::new(value.into())
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    // reflected IEEE 802.3 polynomial
+    const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32 (IEEE) checksum of data
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(u32::MAX, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
 #[derive(Debug, PartialEq, Eq)]
-enum ParseResult {
-    Datagram(Datagram),
+enum ParseResult<'a> {
+    Datagram(DatagramRef<'a>),
+    /// frame of authenticated session, without CRC trailer
+    Sealed {
+        frame: &'a [u8],
+        checksum: bool,
+    },
     NotEnoughBytes,
     Error,
+    ChecksumMismatch {
+        frame_len: usize,
+    },
 }
 
-impl From<&[u8]> for ParseResult {
-    fn from(value: &[u8]) -> Self {
-        const DATA_LEN_SIZE: usize = 2;
+impl<'a> From<&'a [u8]> for ParseResult<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        Self::parse(value, false)
+    }
+}
 
-        let mandatory_len = Datagram::HEADER.len() + DATA_LEN_SIZE;
+impl<'a> ParseResult<'a> {
+    /// Parse frame at the start of value, `sealed_frames` tells if the second high bit
+    /// of length word is the flag of sealed frames
+    fn parse(value: &'a [u8], sealed_frames: bool) -> Self {
+        let mandatory_len = Datagram::PREFIX_LEN;
         if value.len() < mandatory_len {
             return ParseResult::NotEnoughBytes;
         }
@@ -74,22 +323,52 @@ impl From<&[u8]> for ParseResult {
             return ParseResult::Error;
         }
 
-        let mut data_len_bytes = [0u8; DATA_LEN_SIZE];
-        data_len_bytes.copy_from_slice(
-            &value[Datagram::HEADER.len()..Datagram::HEADER.len() + DATA_LEN_SIZE],
-        );
-
-        let data_len = u16::from_be_bytes(data_len_bytes) as usize;
-
-        let total_len = data_len + mandatory_len;
+        let mut length_bytes = [0u8; Datagram::LENGTH_SIZE];
+        length_bytes.copy_from_slice(&value[Datagram::HEADER.len()..mandatory_len]);
+
+        let length_word = u16::from_be_bytes(length_bytes);
+        let checksum = length_word & Datagram::CHECKSUM_FLAG != 0;
+        let auth = sealed_frames && length_word & Datagram::AUTH_FLAG != 0;
+        let length_mask = if sealed_frames {
+            Datagram::SEALED_LENGTH_MASK
+        } else {
+            Datagram::LENGTH_MASK
+        };
+        let data_len = (length_word & length_mask) as usize;
+
+        let data_end = mandatory_len + data_len;
+        let total_len = if checksum {
+            data_end + Datagram::CHECKSUM_SIZE
+        } else {
+            data_end
+        };
 
         if value.len() < total_len {
             return ParseResult::NotEnoughBytes;
         }
 
-        ParseResult::Datagram(Datagram::new(
-            value[mandatory_len..mandatory_len + data_len].to_vec(),
-        ))
+        if checksum {
+            let mut checksum_bytes = [0u8; Datagram::CHECKSUM_SIZE];
+            checksum_bytes.copy_from_slice(&value[data_end..total_len]);
+
+            if u32::from_be_bytes(checksum_bytes) != crc32(&value[..data_end]) {
+                return ParseResult::ChecksumMismatch {
+                    frame_len: total_len,
+                };
+            }
+        }
+
+        if auth {
+            return ParseResult::Sealed {
+                frame: &value[..data_end],
+                checksum,
+            };
+        }
+
+        ParseResult::Datagram(DatagramRef {
+            data: &value[mandatory_len..data_end],
+            checksum,
+        })
     }
 }
 
@@ -98,6 +377,20 @@ impl From<&[u8]> for ParseResult {
 pub struct DatagramParser {
     /// leftover partial data from previous read
     buffer: Vec<u8>,
+    /// verification of frames, kept apart from buffer so both can be borrowed at once
+    checks: FrameChecks,
+}
+
+#[derive(Default)]
+struct FrameChecks {
+    /// count of frames dropped because of checksum mismatch
+    checksum_failures: usize,
+    /// verifies frames of authenticated session
+    opener: Option<FrameOpener>,
+    /// count of frames dropped because of failed authentication
+    auth_failures: usize,
+    /// decrypted data of the last frame
+    scratch: Vec<u8>,
 }
 
 impl DatagramParser {
@@ -106,33 +399,93 @@ impl DatagramParser {
         Default::default()
     }
 
+    /// Accept only frames sealed for opener's session.
+    /// Frames failing verification are dropped and counted without error,
+    /// so forged frames can't break the stream
+    pub fn with_opener(mut self, opener: FrameOpener) -> Self {
+        self.checks.opener = Some(opener);
+        self
+    }
+
+    /// Count of frames dropped because of checksum mismatch
+    pub fn checksum_failures(&self) -> usize {
+        self.checks.checksum_failures
+    }
+
+    /// Count of frames dropped because they were not sealed for this session
+    pub fn auth_failures(&self) -> usize {
+        self.checks.auth_failures
+    }
+
     /// Parse datagrams contained in given data
     /// returns OK(datagrams) if parsed all data successfully or waiting for more data
-    /// return Err(datagrams) if unable to parse some datagram
-    pub fn parse(&mut self, data: &[u8]) -> Result<Vec<Datagram>, Vec<Datagram>> {
-        self.buffer.extend_from_slice(data);
-
+    /// return Err((datagrams, error)) if unable to parse some datagram
+    pub fn parse(&mut self, data: &[u8]) -> Result<Vec<Datagram>, (Vec<Datagram>, QuotesError)> {
         let mut datagrams = vec![];
 
-        loop {
-            let header_position = Datagram::seek_header(&self.buffer);
+        match self.parse_with(data, |datagram| datagrams.push(datagram.into())) {
+            Ok(()) => Ok(datagrams),
+            Err(e) => Err((datagrams, e)),
+        }
+    }
+
+    /// Parse datagrams contained in given data, passing each to `on_datagram`
+    /// without copying it out of receive buffer.
+    /// Data is buffered only if it ends with partial frame or previous data did.
+    /// Stops on the first malformed frame, the rest of data is parsed on the next call
+    pub fn parse_with(
+        &mut self,
+        data: &[u8],
+        on_datagram: impl FnMut(DatagramRef<'_>),
+    ) -> Result<(), QuotesError> {
+        if self.buffer.is_empty() {
+            let (consumed, result) = Self::scan(data, &mut self.checks, on_datagram);
+            self.buffer.extend_from_slice(&data[consumed..]);
+            return result;
+        }
+
+        self.buffer.extend_from_slice(data);
+        let (consumed, result) = Self::scan(&self.buffer, &mut self.checks, on_datagram);
+        self.buffer.drain(0..consumed);
+        result
+    }
+
+    /// Parse frames in buf, returns count of bytes that can be thrown away
+    fn scan(
+        buf: &[u8],
+        checks: &mut FrameChecks,
+        mut on_datagram: impl FnMut(DatagramRef<'_>),
+    ) -> (usize, Result<(), QuotesError>) {
+        let mut position = 0;
 
-            if let Some(index) = header_position {
+        while position < buf.len() {
+            match Datagram::seek_header(&buf[position..]) {
                 // found header, can throw away everything before it
-                self.buffer.drain(0..index);
-            } else if self.buffer.len() > Datagram::HEADER.len() - 1 {
-                // header not found, throw away everything before last HEADER.len() - 1 bytes so parsing will return NotEnoughBytes
-                self.buffer
-                    .drain(0..self.buffer.len() - (Datagram::HEADER.len() - 1));
+                Some(index) => position += index,
+                None => {
+                    // header not found, keep only last HEADER.len() - 1 bytes, they may start one
+                    let tail_start = buf.len().saturating_sub(Datagram::HEADER.len() - 1);
+                    return (tail_start.max(position), Ok(()));
+                }
             }
 
-            let result = ParseResult::from(self.buffer.as_slice());
-            match result {
+            match ParseResult::parse(&buf[position..], checks.opener.is_some()) {
                 ParseResult::Datagram(datagram) => {
-                    self.buffer.drain(0..datagram.bytes_length());
-                    datagrams.push(datagram);
-                    if self.buffer.is_empty() {
-                        break;
+                    position += datagram.bytes_length();
+                    match checks.opener {
+                        Some(_) => checks.auth_failures += 1,
+                        None => on_datagram(datagram),
+                    }
+                }
+                ParseResult::Sealed { frame, checksum } => {
+                    position += frame.len() + Datagram::overhead(checksum) - Datagram::PREFIX_LEN;
+                    let opened = checks
+                        .opener
+                        .as_mut()
+                        .and_then(|opener| opener.open(frame, &mut checks.scratch));
+                    match opened {
+                        Some(data) => on_datagram(DatagramRef { data, checksum }),
+                        None => checks.auth_failures += 1,
                     }
                 }
                 ParseResult::NotEnoughBytes => {
@@ -140,16 +493,28 @@ impl DatagramParser {
                     break;
                 }
                 ParseResult::Error => {
-                    if self.buffer.len() >= Datagram::HEADER.len() {
-                        self.buffer.drain(0..Datagram::HEADER.len());
-                    }
-
-                    return Err(datagrams);
+                    return (
+                        position + Datagram::HEADER.len(),
+                        Err(QuotesError::ParseDatagramError(
+                            "Malformed frame header".to_string(),
+                        )),
+                    );
+                }
+                ParseResult::ChecksumMismatch { frame_len } => {
+                    checks.checksum_failures += 1;
+
+                    return (
+                        position + frame_len,
+                        Err(QuotesError::ParseDatagramError(format!(
+                            "Checksum mismatch, {} corrupted frames dropped so far",
+                            checks.checksum_failures
+                        ))),
+                    );
                 }
             }
         }
 
-        Ok(datagrams)
+        (position, Ok(()))
     }
 }
 
@@ -162,11 +527,14 @@ mod tests {
         let data = vec![1, 2, 3, 4];
         let datagram = Datagram::new(data.clone());
 
-        let bytes: Vec<u8> = datagram.into();
+        let bytes = Vec::<u8>::try_from(datagram).expect("Should encode datagram");
 
         assert_eq!(
             ParseResult::from(bytes.as_slice()),
-            ParseResult::Datagram(Datagram::new(data))
+            ParseResult::Datagram(DatagramRef {
+                data: &data,
+                checksum: false
+            })
         )
     }
 
@@ -175,7 +543,7 @@ mod tests {
         let data = vec![1, 2, 3, 4];
         let datagram = Datagram::new(data.clone());
 
-        let bytes: Vec<u8> = datagram.into();
+        let bytes = Vec::<u8>::try_from(datagram).expect("Should encode datagram");
 
         assert_eq!(
             ParseResult::from(&bytes[0..bytes.len() - 1]),
@@ -187,7 +555,7 @@ mod tests {
         let data = vec![1, 2, 3, 4];
         let datagram = Datagram::new(data.clone());
 
-        let mut bytes: Vec<u8> = datagram.into();
+        let mut bytes = Vec::<u8>::try_from(datagram).expect("Should encode datagram");
         bytes[3] = u8::MAX;
 
         assert_eq!(ParseResult::from(bytes.as_slice()), ParseResult::Error)
@@ -201,7 +569,7 @@ mod tests {
 
         for data in datas.iter() {
             let datagram = Datagram::new(data.clone());
-            let bytes: Vec<u8> = datagram.into();
+            let bytes = Vec::<u8>::try_from(datagram).expect("Should encode datagram");
             buffer.extend_from_slice(&bytes);
         }
 
@@ -223,7 +591,7 @@ mod tests {
         let mut parser = DatagramParser::new();
 
         let datagram1 = Datagram::new(datas[0].clone());
-        let bytes1: Vec<u8> = datagram1.into();
+        let bytes1 = Vec::<u8>::try_from(datagram1).expect("Should encode datagram");
 
         let result1 = parser
             .parse(&bytes1[0..&bytes1.len() - 1])
@@ -236,7 +604,7 @@ mod tests {
         buffer.push(bytes1[bytes1.len() - 1]);
 
         let datagram2 = Datagram::new(datas[1].clone());
-        let bytes2: Vec<u8> = datagram2.into();
+        let bytes2 = Vec::<u8>::try_from(datagram2).expect("Should encode datagram");
 
         buffer.extend_from_slice(&bytes2);
 
@@ -249,4 +617,146 @@ mod tests {
 
         assert_eq!(datas, result_datas)
     }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_parse_datagram_with_checksum() {
+        let data = vec![1, 2, 3, 4];
+        let bytes = Vec::<u8>::try_from(Datagram::with_checksum(data.clone()))
+            .expect("Should encode datagram");
+
+        assert_eq!(
+            ParseResult::from(bytes.as_slice()),
+            ParseResult::Datagram(DatagramRef {
+                data: &data,
+                checksum: true
+            })
+        )
+    }
+
+    #[test]
+    fn test_parse_checksum_not_enough_bytes() {
+        let bytes = Vec::<u8>::try_from(Datagram::with_checksum(vec![1, 2, 3, 4]))
+            .expect("Should encode datagram");
+
+        assert_eq!(
+            ParseResult::from(&bytes[0..bytes.len() - 1]),
+            ParseResult::NotEnoughBytes
+        )
+    }
+
+    #[test]
+    fn test_parse_corrupted_payload() {
+        let first = Vec::<u8>::try_from(Datagram::with_checksum(vec![1, 2, 3, 4]))
+            .expect("Should encode datagram");
+        let second = Vec::<u8>::try_from(Datagram::with_checksum(vec![5, 6, 7]))
+            .expect("Should encode datagram");
+
+        let mut corrupted = first.clone();
+        corrupted[7] ^= 0x10;
+
+        let mut parser = DatagramParser::new();
+        let (datagrams, error) = parser
+            .parse(&corrupted)
+            .expect_err("Should detect checksum mismatch");
+
+        assert!(datagrams.is_empty());
+        assert!(matches!(error, QuotesError::ParseDatagramError(_)));
+        assert_eq!(parser.checksum_failures(), 1);
+
+        let result_datas = parser
+            .parse(&second)
+            .expect("Should parse successfully")
+            .into_iter()
+            .map(|dg| dg.data)
+            .collect::<Vec<_>>();
+
+        assert_eq!(result_datas, vec![vec![5, 6, 7]]);
+    }
+
+    #[test]
+    fn test_encode_into_matches_vec() {
+        for datagram in [
+            Datagram::new(vec![1, 2, 3, 4]),
+            Datagram::with_checksum(vec![5, 6, 7]),
+        ] {
+            let mut buf = [0u8; 32];
+            let len = datagram.encode_into(&mut buf).expect("Should fit");
+            assert!(matches!(
+                datagram.encode_into(&mut buf[..len - 1]),
+                Err(QuotesError::BufferTooSmall(_))
+            ));
+
+            let bytes = Vec::<u8>::try_from(datagram).expect("Should encode datagram");
+            assert_eq!(&buf[..len], bytes.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_frame_into() {
+        let mut buf = [0u8; 16];
+        let len = Datagram::frame_into(&mut buf, true, |data| {
+            data[..3].copy_from_slice(&[7, 8, 9]);
+            Ok(3)
+        })
+        .expect("Should fit");
+
+        let bytes = Vec::<u8>::try_from(Datagram::with_checksum(vec![7, 8, 9]))
+            .expect("Should encode datagram");
+        assert_eq!(&buf[..len], bytes.as_slice());
+    }
+
+    #[test]
+    fn test_parse_with_borrows_receive_buffer() {
+        let first =
+            Vec::<u8>::try_from(Datagram::new(vec![1, 2, 3])).expect("Should encode datagram");
+        let second = Vec::<u8>::try_from(Datagram::with_checksum(vec![4, 5]))
+            .expect("Should encode datagram");
+        let mut buffer = first.clone();
+        buffer.extend_from_slice(&second);
+
+        let mut parser = DatagramParser::new();
+        let mut datas = vec![];
+        parser
+            .parse_with(&buffer[..buffer.len() - 1], |dg| {
+                // points into given data, nothing was copied
+                assert!(buffer.as_ptr_range().contains(&dg.data.as_ptr()));
+                datas.push(dg.data.to_vec());
+            })
+            .expect("Should parse successfully");
+        assert_eq!(datas, vec![vec![1, 2, 3]]);
+
+        parser
+            .parse_with(&buffer[buffer.len() - 1..], |dg| {
+                datas.push(dg.data.to_vec());
+            })
+            .expect("Should parse successfully");
+        assert_eq!(datas, vec![vec![1, 2, 3], vec![4, 5]]);
+    }
+
+    #[test]
+    fn test_long_unsealed_datagram() {
+        // length above sealed frame limit sets the bit used as sealed flag
+        let data = vec![7; Datagram::MAX_SEALED_DATA_LEN + 1];
+        let bytes =
+            Vec::<u8>::try_from(Datagram::new(data.clone())).expect("Should encode datagram");
+
+        let datagrams = DatagramParser::new()
+            .parse(&bytes)
+            .expect("Should parse successfully");
+        assert_eq!(datagrams, vec![Datagram::new(data)]);
+    }
+
+    #[test]
+    fn test_too_long_datagram() {
+        let datagram = Datagram::new(vec![0; Datagram::MAX_DATA_LEN + 1]);
+        assert!(matches!(
+            Vec::<u8>::try_from(datagram),
+            Err(QuotesError::EncodeError(_))
+        ));
+    }
 }
@@ -0,0 +1,127 @@
+//! Tickers each client is licensed to receive
+use std::path::PathBuf;
+
+use crate::{error::QuotesError, pattern::TickerPattern};
+
+/// Allowed tickers per client, one rule per line: `<client> <pattern>,<pattern>...`.
+/// Client is the name from credentials store, `*` applies to every client including anonymous ones.
+/// Rules of the same client add up, tickers not matched by any rule are denied.
+/// Empty lines and lines starting with `#` are skipped
+#[derive(Debug, Clone, Default)]
+pub struct Entitlements {
+    rules: Vec<(String, Vec<TickerPattern>)>,
+}
+
+impl Entitlements {
+    const ANY_CLIENT: &str = "*";
+
+    /// Read entitlements from file
+    pub fn from_file(path: PathBuf) -> Result<Self, QuotesError> {
+        Self::try_from(std::fs::read_to_string(path)?.as_str())
+    }
+
+    /// Client may receive ticker
+    pub fn allows(&self, client: Option<&str>, ticker: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(rule_client, _)| {
+                rule_client == Self::ANY_CLIENT || Some(rule_client.as_str()) == client
+            })
+            .flat_map(|(_, patterns)| patterns)
+            .any(|pattern| pattern.matches(ticker))
+    }
+
+    /// Split requested tickers into allowed and denied ones, keeping their order
+    pub fn split(&self, client: Option<&str>, tickers: Vec<String>) -> (Vec<String>, Vec<String>) {
+        tickers
+            .into_iter()
+            .partition(|ticker| self.allows(client, ticker))
+    }
+}
+
+impl TryFrom<&str> for Entitlements {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let rules = value
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(index, line)| {
+                let error = || {
+                    QuotesError::ConfigError(format!("Malformed entitlements line {}", index + 1))
+                };
+
+                let parts = line.split_whitespace().collect::<Vec<_>>();
+                let [client, patterns] = parts[..] else {
+                    return Err(error());
+                };
+
+                let patterns = patterns
+                    .split(',')
+                    .map(TickerPattern::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| error())?;
+                Ok((client.to_string(), patterns))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { rules })
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    const RULES: &str = "
+        # client patterns
+        *      AAPL
+        alice  MSFT,NV*
+        alice  TSLA
+        risk   *
+    ";
+
+    fn tickers(list: &[&str]) -> Vec<String> {
+        list.iter().map(|ticker| ticker.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let entitlements = Entitlements::try_from(RULES).expect("Should parse");
+        let requested = tickers(&["AAPL", "MSFT", "NVDA", "TSLA", "JPM"]);
+
+        assert_eq!(
+            entitlements.split(Some("alice"), requested.clone()),
+            (
+                tickers(&["AAPL", "MSFT", "NVDA", "TSLA"]),
+                tickers(&["JPM"])
+            )
+        );
+        assert_eq!(
+            entitlements.split(None, requested.clone()),
+            (
+                tickers(&["AAPL"]),
+                tickers(&["MSFT", "NVDA", "TSLA", "JPM"])
+            )
+        );
+        assert_eq!(
+            entitlements.split(Some("risk"), requested.clone()),
+            (requested, vec![])
+        );
+    }
+
+    #[test]
+    fn test_malformed_rules() {
+        for text in ["alice", "alice AAPL MSFT", "alice AAPL,,MSFT"] {
+            assert!(
+                matches!(
+                    Entitlements::try_from(text),
+                    Err(QuotesError::ConfigError(_))
+                ),
+                "{text}"
+            );
+        }
+    }
+}
@@ -16,7 +16,23 @@ pub enum QuotesError {
     /// Problem parsing server message
     ParseServerMessageError(String),
     /// Unable to parse datagram
-    ParseDatagramError,
+    ParseDatagramError(String),
+    /// Peer requested protocol version that is not supported
+    UnsupportedProtocolVersion(String),
+    /// Value doesn't fit field of wire format, e.g. too long ticker
+    EncodeError(String),
+    /// Peer requested wire format that is not supported
+    UnsupportedWireFormat(String),
+    /// Top-of-book with bid price above ask price
+    CrossedBook(String),
+    /// Caller-provided buffer can't hold encoded message
+    BufferTooSmall(String),
+    /// Datagram authentication is misconfigured or failed
+    AuthError(String),
+    /// Client credentials are missing or invalid
+    AccessDenied(String),
+    /// Configuration file is malformed
+    ConfigError(String),
 }
 
 impl From<ParseFloatError> for QuotesError {
@@ -47,9 +63,21 @@ impl Display for QuotesError {
             QuotesError::ParseServerMessageError(reason) => {
                 write!(f, "Parse server message error: {reason}")
             }
-            QuotesError::ParseDatagramError => {
-                write!(f, "Unable to parse datagram")
+            QuotesError::ParseDatagramError(reason) => {
+                write!(f, "Unable to parse datagram: {reason}")
             }
+            QuotesError::UnsupportedProtocolVersion(versions) => {
+                write!(f, "Unsupported protocol version: {versions}")
+            }
+            QuotesError::EncodeError(reason) => write!(f, "Unable to encode: {reason}"),
+            QuotesError::UnsupportedWireFormat(format) => {
+                write!(f, "Unsupported wire format: {format}")
+            }
+            QuotesError::CrossedBook(reason) => write!(f, "Crossed book: {reason}"),
+            QuotesError::BufferTooSmall(reason) => write!(f, "Buffer too small: {reason}"),
+            QuotesError::AuthError(reason) => write!(f, "Authentication error: {reason}"),
+            QuotesError::AccessDenied(reason) => write!(f, "Access denied: {reason}"),
+            QuotesError::ConfigError(reason) => write!(f, "Configuration error: {reason}"),
         }
     }
 }
@@ -0,0 +1,254 @@
+//! Errors reported by server to clients, with codes clients can react to
+use std::fmt::Display;
+
+use crate::{error::QuotesError, protocol::ProtocolVersion, sink::Sink};
+
+/// Kind of error reported by server, serialized as number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "u16", from = "u16")
+)]
+pub enum ErrorCode {
+    /// Peer didn't say, e.g. legacy peers sending plain text
+    Unspecified,
+    /// Request can't be parsed
+    InvalidRequest,
+    /// No common protocol version
+    UnsupportedVersion,
+    /// Requested wire format is not supported
+    UnsupportedFormat,
+    /// Credentials are missing or invalid
+    AccessDenied,
+    /// Datagram authentication required by server isn't possible for this request
+    AuthRequired,
+    /// Server doesn't know some of requested tickers
+    UnknownTicker,
+    /// Client isn't licensed for some of requested tickers
+    NotEntitled,
+    /// Another subscription already streams to the same address
+    AddressInUse,
+    /// Client sends requests too often
+    RateLimited,
+    /// Server is stopping, stream ends
+    ShuttingDown,
+    /// Server failed to handle request
+    Internal,
+    /// Code added in newer protocol, kept as is
+    Other(UnknownCode),
+}
+
+/// Code this library doesn't know, it never equals code of another variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UnknownCode(u16);
+
+impl UnknownCode {
+    /// Numeric code
+    pub fn get(self) -> u16 {
+        self.0
+    }
+}
+
+impl ErrorCode {
+    const KNOWN: [ErrorCode; 12] = [
+        ErrorCode::Unspecified,
+        ErrorCode::InvalidRequest,
+        ErrorCode::UnsupportedVersion,
+        ErrorCode::UnsupportedFormat,
+        ErrorCode::AccessDenied,
+        ErrorCode::AuthRequired,
+        ErrorCode::UnknownTicker,
+        ErrorCode::NotEntitled,
+        ErrorCode::AddressInUse,
+        ErrorCode::RateLimited,
+        ErrorCode::ShuttingDown,
+        ErrorCode::Internal,
+    ];
+
+    /// Count of bytes in binary encoding
+    pub(crate) const ENCODED_SIZE: usize = 2;
+}
+
+/// Codes are part of protocol, so they never change, codes of removed variants are not reused
+impl From<ErrorCode> for u16 {
+    fn from(value: ErrorCode) -> Self {
+        match value {
+            ErrorCode::Unspecified => 0,
+            ErrorCode::InvalidRequest => 1,
+            ErrorCode::UnsupportedVersion => 2,
+            ErrorCode::UnsupportedFormat => 3,
+            ErrorCode::AccessDenied => 4,
+            ErrorCode::AuthRequired => 5,
+            ErrorCode::UnknownTicker => 6,
+            ErrorCode::NotEntitled => 7,
+            ErrorCode::AddressInUse => 8,
+            ErrorCode::RateLimited => 9,
+            ErrorCode::ShuttingDown => 10,
+            ErrorCode::Internal => 11,
+            ErrorCode::Other(code) => code.get(),
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(value: u16) -> Self {
+        ErrorCode::KNOWN
+            .into_iter()
+            .find(|code| u16::from(*code) == value)
+            .unwrap_or(ErrorCode::Other(UnknownCode(value)))
+    }
+}
+
+/// Code of library error reported to peer
+impl From<&QuotesError> for ErrorCode {
+    fn from(value: &QuotesError) -> Self {
+        match value {
+            QuotesError::ParseQuoteError(_)
+            | QuotesError::ParseClientMessageError(_)
+            | QuotesError::ParseServerMessageError(_)
+            | QuotesError::ParseDatagramError(_) => ErrorCode::InvalidRequest,
+            QuotesError::UnsupportedProtocolVersion(_) => ErrorCode::UnsupportedVersion,
+            QuotesError::UnsupportedWireFormat(_) => ErrorCode::UnsupportedFormat,
+            QuotesError::AuthError(_) => ErrorCode::AuthRequired,
+            QuotesError::AccessDenied(_) => ErrorCode::AccessDenied,
+            QuotesError::IoError(_)
+            | QuotesError::ConfigError(_)
+            | QuotesError::EncodeError(_)
+            | QuotesError::CrossedBook(_)
+            | QuotesError::BufferTooSmall(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Snake case name, e.g. `not_entitled`
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ErrorCode::Unspecified => "unspecified",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+            ErrorCode::UnsupportedFormat => "unsupported_format",
+            ErrorCode::AccessDenied => "access_denied",
+            ErrorCode::AuthRequired => "auth_required",
+            ErrorCode::UnknownTicker => "unknown_ticker",
+            ErrorCode::NotEntitled => "not_entitled",
+            ErrorCode::AddressInUse => "address_in_use",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::ShuttingDown => "shutting_down",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Other(code) => return write!(f, "code_{}", code.get()),
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Error reported by server: code for programs and detail text for people
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorMessage {
+    /// kind of error
+    pub code: ErrorCode,
+    /// human-readable description
+    pub detail: String,
+}
+
+impl ErrorMessage {
+    /// Create new ErrorMessage
+    pub fn new(code: ErrorCode, detail: impl Into<String>) -> Self {
+        Self {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    /// V3 encoding `[code: u16][detail: utf8]`, older peers only know plain text
+    pub(crate) fn write(
+        &self,
+        version: ProtocolVersion,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        if version >= ProtocolVersion::V3 {
+            sink.put(&u16::from(self.code).to_be_bytes())?;
+        }
+        sink.put(self.detail.as_bytes())
+    }
+
+    /// Decode encoding of given version, code of errors older than V3 is unspecified
+    pub(crate) fn decode(value: &[u8], version: ProtocolVersion) -> Result<Self, QuotesError> {
+        let (code, detail) = if version >= ProtocolVersion::V3 {
+            if value.len() < ErrorCode::ENCODED_SIZE {
+                return Err(QuotesError::ParseServerMessageError(
+                    "Not enough bytes for error code".to_string(),
+                ));
+            }
+            let (code, detail) = value.split_at(ErrorCode::ENCODED_SIZE);
+            (
+                ErrorCode::from(u16::from_be_bytes([code[0], code[1]])),
+                detail,
+            )
+        } else {
+            (ErrorCode::Unspecified, value)
+        };
+
+        let detail = String::from_utf8(detail.to_vec())
+            .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))?;
+        Ok(Self { code, detail })
+    }
+}
+
+impl From<&QuotesError> for ErrorMessage {
+    fn from(value: &QuotesError) -> Self {
+        Self::new(ErrorCode::from(value), value.to_string())
+    }
+}
+
+impl Display for ErrorMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.code, self.detail)
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_code_roundtrip() {
+        for code in ErrorCode::KNOWN {
+            assert_eq!(ErrorCode::from(u16::from(code)), code);
+        }
+        assert_eq!(ErrorCode::from(1000), ErrorCode::Other(UnknownCode(1000)));
+        assert_eq!(u16::from(ErrorCode::from(1000)), 1000);
+
+        // codes are stable, peers compare them as numbers
+        assert_eq!(u16::from(ErrorCode::UnsupportedVersion), 2);
+        assert_eq!(u16::from(ErrorCode::AccessDenied), 4);
+        assert_eq!(u16::from(ErrorCode::RateLimited), 9);
+        assert_eq!(u16::from(ErrorCode::ShuttingDown), 10);
+
+        let mut codes = ErrorCode::KNOWN.map(u16::from);
+        codes.sort();
+        assert!(codes.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let message = ErrorMessage::new(ErrorCode::NotEntitled, "MSFT");
+
+        for (version, code) in [
+            (ProtocolVersion::V1, ErrorCode::Unspecified),
+            (ProtocolVersion::V2, ErrorCode::Unspecified),
+            (ProtocolVersion::V3, ErrorCode::NotEntitled),
+        ] {
+            let mut bytes = vec![];
+            message.write(version, &mut bytes).expect("Should write");
+            assert_eq!(
+                ErrorMessage::decode(&bytes, version).expect("Should decode"),
+                ErrorMessage::new(code, "MSFT")
+            );
+        }
+
+        assert!(ErrorMessage::decode(&[0], ProtocolVersion::V3).is_err());
+    }
+}
@@ -11,11 +11,26 @@ use std::{
 
 use crate::error::QuotesError;
 
+pub mod auth;
+pub mod control_message;
+pub mod credentials;
 pub mod datagram;
+pub mod entitlements;
 pub mod error;
+pub mod error_message;
+pub mod pattern;
+pub mod price;
+pub mod protocol;
 pub mod quote;
+pub mod sequence;
 pub mod server_message;
+pub mod session;
+mod sink;
 pub mod subscribe_message;
+pub mod subscribe_response;
+pub mod top_of_book;
+pub mod trade;
+pub mod universe;
 
 /// Read tickers list from file, one ticker per line
 pub fn read_tickers_from_file(file: PathBuf) -> Result<Vec<String>, QuotesError> {
@@ -0,0 +1,188 @@
+//! Ticker patterns with `*` wildcard
+use std::fmt::Display;
+
+use crate::error::QuotesError;
+
+/// Exact ticker or pattern where `*` matches any sequence of characters, e.g. `NV*` or `*`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "String", try_from = "String")
+)]
+pub struct TickerPattern(String);
+
+impl TickerPattern {
+    const WILDCARD: char = '*';
+    /// Longest ticker, pattern or group name accepted
+    pub const MAX_LEN: usize = 64;
+    /// Most selectors accepted in a single client message
+    pub const MAX_COUNT: usize = 1024;
+
+    /// Selectors of client message are within limits, so expanding them can't stall server
+    pub(crate) fn check_selectors(selectors: &[String]) -> Result<(), QuotesError> {
+        if selectors.len() > Self::MAX_COUNT {
+            return Err(QuotesError::ParseClientMessageError(format!(
+                "{} selectors, at most {} are accepted",
+                selectors.len(),
+                Self::MAX_COUNT
+            )));
+        }
+        match selectors
+            .iter()
+            .find(|selector| selector.len() > Self::MAX_LEN)
+        {
+            Some(selector) => Err(QuotesError::ParseClientMessageError(format!(
+                "Selector of {} bytes, at most {} are accepted",
+                selector.len(),
+                Self::MAX_LEN
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Pattern contains wildcard, so it may match many tickers
+    pub fn is_wildcard(&self) -> bool {
+        self.0.contains(Self::WILDCARD)
+    }
+
+    /// Ticker matches pattern
+    pub fn matches(&self, ticker: &str) -> bool {
+        Self::glob(self.0.as_bytes(), ticker.as_bytes())
+    }
+
+    /// Iterative matcher, only the last `*` is backtracked to, so time is O(pattern × ticker)
+    fn glob(pattern: &[u8], ticker: &[u8]) -> bool {
+        let (mut p, mut t) = (0, 0);
+        // pattern position after the last `*` and ticker position it resumes matching from
+        let mut star: Option<(usize, usize)> = None;
+
+        while t < ticker.len() {
+            match pattern.get(p) {
+                Some(b'*') => {
+                    p += 1;
+                    star = Some((p, t));
+                }
+                Some(byte) if *byte == ticker[t] => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => match star {
+                    // let the last `*` swallow one more byte
+                    Some((after_star, resume)) => {
+                        p = after_star;
+                        t = resume + 1;
+                        star = Some((after_star, t));
+                    }
+                    None => return false,
+                },
+            }
+        }
+
+        pattern[p..].iter().all(|byte| *byte == b'*')
+    }
+}
+
+impl Display for TickerPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Patterns are separated by commas in lists, so they can't contain commas or whitespace.
+/// Runs of `*` match the same as a single one and are collapsed
+impl TryFrom<&str> for TickerPattern {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if value.is_empty()
+            || value.len() > Self::MAX_LEN
+            || value.contains(|c: char| c == ',' || c.is_whitespace())
+        {
+            return Err(QuotesError::ParseClientMessageError(format!(
+                "Invalid ticker pattern {value:?}"
+            )));
+        }
+
+        let mut pattern = String::with_capacity(value.len());
+        for c in value.chars() {
+            if !(c == Self::WILDCARD && pattern.ends_with(Self::WILDCARD)) {
+                pattern.push(c);
+            }
+        }
+
+        Ok(Self(pattern))
+    }
+}
+
+impl From<TickerPattern> for String {
+    fn from(value: TickerPattern) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<String> for TickerPattern {
+    type Error = QuotesError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_str())
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let pattern = |text| TickerPattern::try_from(text).expect("Should parse");
+
+        assert!(pattern("AAPL").matches("AAPL"));
+        assert!(!pattern("AAPL").matches("AAP"));
+        assert!(!pattern("AAPL").is_wildcard());
+        assert!(pattern("*").matches("BRK.B"));
+        assert!(pattern("NV*").matches("NVDA"));
+        assert!(pattern("NV*").matches("NV"));
+        assert!(!pattern("NV*").matches("ANVX"));
+        assert!(pattern("*.B").matches("BRK.B"));
+        assert!(pattern("A*L").matches("AAPL"));
+
+        assert!(pattern("A**P*L").matches("AAPL"));
+        assert_eq!(pattern("A**P***L").to_string(), "A*P*L");
+        assert!(pattern("*A*A*").matches("BANANA"));
+        assert!(!pattern("*A*A*A*A*").matches("BANANA"));
+
+        for text in ["", "A,B", "A B", &"A".repeat(TickerPattern::MAX_LEN + 1)] {
+            assert!(TickerPattern::try_from(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn test_pathological_pattern() {
+        // recursive matcher tried every split of ticker for every star
+        let stars = format!("{}!", "*".repeat(80));
+        assert!(!TickerPattern::glob(stars.as_bytes(), b"GOOGL"));
+
+        let alternating = format!("{}!", "G*".repeat(2000));
+        let ticker = "G".repeat(3000);
+        assert!(!TickerPattern::glob(
+            alternating.as_bytes(),
+            ticker.as_bytes()
+        ));
+        assert!(TickerPattern::glob(
+            &alternating.as_bytes()[..4000],
+            ticker.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn test_check_selectors() {
+        let selectors = |count, len| vec!["A".repeat(len); count];
+
+        assert!(TickerPattern::check_selectors(&selectors(TickerPattern::MAX_COUNT, 4)).is_ok());
+        assert!(
+            TickerPattern::check_selectors(&selectors(TickerPattern::MAX_COUNT + 1, 4)).is_err()
+        );
+        assert!(TickerPattern::check_selectors(&selectors(1, TickerPattern::MAX_LEN + 1)).is_err());
+    }
+}
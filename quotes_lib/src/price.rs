@@ -0,0 +1,373 @@
+//! Fixed-point decimal price module
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    ops::{Add, Mul, Neg, Sub},
+    str::FromStr,
+};
+
+use crate::error::QuotesError;
+
+/// Exact decimal price: `mantissa * 10^-scale`.
+/// Scale is chosen per instrument, e.g. 2 for prices quoted in cents.
+/// Serialized as decimal string to keep it exact, e.g. `"123.45"`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "String", try_from = "String")
+)]
+pub struct Price {
+    mantissa: i64,
+    scale: u8,
+}
+
+impl Price {
+    /// Max supported count of digits after decimal point
+    pub const MAX_SCALE: u8 = 18;
+
+    /// Create price equal to `mantissa * 10^-scale`, scale is capped at MAX_SCALE
+    pub fn new(mantissa: i64, scale: u8) -> Self {
+        Self {
+            mantissa,
+            scale: scale.min(Self::MAX_SCALE),
+        }
+    }
+
+    /// Integer mantissa
+    pub fn mantissa(&self) -> i64 {
+        self.mantissa
+    }
+
+    /// Count of digits after decimal point
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// Same value with given scale, None if precision would be lost or mantissa overflows
+    pub fn rescale(&self, scale: u8) -> Option<Self> {
+        if scale > Self::MAX_SCALE {
+            return None;
+        }
+
+        let mantissa = match scale.cmp(&self.scale) {
+            Ordering::Equal => self.mantissa,
+            Ordering::Greater => self
+                .mantissa
+                .checked_mul(10i64.checked_pow((scale - self.scale) as u32)?)?,
+            Ordering::Less => {
+                let divisor = 10i64.pow((self.scale - scale) as u32);
+                if self.mantissa % divisor != 0 {
+                    return None;
+                }
+                self.mantissa / divisor
+            }
+        };
+
+        Some(Self { mantissa, scale })
+    }
+
+    /// Same value with trailing zeros of mantissa removed
+    pub fn normalized(&self) -> Self {
+        let mut result = *self;
+        while result.scale > 0 && result.mantissa % 10 == 0 {
+            result.mantissa /= 10;
+            result.scale -= 1;
+        }
+        result
+    }
+
+    /// Nearest price with given scale, for values coming from legacy float fields
+    pub fn from_f64(value: f64, scale: u8) -> Self {
+        let scale = scale.min(Self::MAX_SCALE);
+        let mantissa = (value * 10f64.powi(scale as i32)).round() as i64;
+        Self { mantissa, scale }
+    }
+
+    /// Approximate float value, only for peers that don't support decimal prices
+    pub fn to_f64(&self) -> f64 {
+        self.mantissa as f64 / 10f64.powi(self.scale as i32)
+    }
+
+    /// Sum of prices with scale of the most precise one, None on overflow
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        let (lhs, rhs) = Self::aligned(self, rhs)?;
+        Some(Self {
+            mantissa: lhs.mantissa.checked_add(rhs.mantissa)?,
+            scale: lhs.scale,
+        })
+    }
+
+    /// Difference of prices with scale of the most precise one, None on overflow
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        let (lhs, rhs) = Self::aligned(self, rhs)?;
+        Some(Self {
+            mantissa: lhs.mantissa.checked_sub(rhs.mantissa)?,
+            scale: lhs.scale,
+        })
+    }
+
+    /// Negated price, None on overflow
+    pub fn checked_neg(self) -> Option<Self> {
+        Some(Self {
+            mantissa: self.mantissa.checked_neg()?,
+            scale: self.scale,
+        })
+    }
+
+    /// Price multiplied by integer quantity, None on overflow
+    pub fn checked_mul(self, rhs: i64) -> Option<Self> {
+        Some(Self {
+            mantissa: self.mantissa.checked_mul(rhs)?,
+            scale: self.scale,
+        })
+    }
+
+    /// Count of bytes in binary encoding
+    pub(crate) const ENCODED_SIZE: usize = 9;
+
+    /// Binary encoding `[mantissa: i64][scale: u8]`, mantissa is big-endian
+    pub(crate) fn to_be_bytes(self) -> [u8; Self::ENCODED_SIZE] {
+        let mut bytes = [0u8; Self::ENCODED_SIZE];
+        bytes[..8].copy_from_slice(&self.mantissa.to_be_bytes());
+        bytes[8] = self.scale;
+        bytes
+    }
+
+    /// Decode binary encoding, scales above MAX_SCALE are rejected
+    pub(crate) fn from_be_bytes(bytes: [u8; Self::ENCODED_SIZE]) -> Result<Self, QuotesError> {
+        let scale = bytes[8];
+        if scale > Self::MAX_SCALE {
+            return Err(QuotesError::ParseQuoteError(format!(
+                "Unsupported price scale {scale}"
+            )));
+        }
+
+        let mut mantissa = [0u8; 8];
+        mantissa.copy_from_slice(&bytes[..8]);
+        Ok(Self {
+            mantissa: i64::from_be_bytes(mantissa),
+            scale,
+        })
+    }
+
+    fn aligned(lhs: Self, rhs: Self) -> Option<(Self, Self)> {
+        let scale = lhs.scale.max(rhs.scale);
+        Some((lhs.rescale(scale)?, rhs.rescale(scale)?))
+    }
+
+    /// Mantissa at MAX_SCALE, wide enough to compare any two prices exactly
+    fn wide_mantissa(&self) -> i128 {
+        self.mantissa as i128 * 10i128.pow((Self::MAX_SCALE - self.scale) as u32)
+    }
+}
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.wide_mantissa() == other.wide_mantissa()
+    }
+}
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.wide_mantissa().cmp(&other.wide_mantissa())
+    }
+}
+
+impl Neg for Price {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        self.checked_neg().expect("Price negation overflow")
+    }
+}
+
+impl Add for Price {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.checked_add(rhs).expect("Price addition overflow")
+    }
+}
+
+impl Sub for Price {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.checked_sub(rhs).expect("Price subtraction overflow")
+    }
+}
+
+impl Mul<i64> for Price {
+    type Output = Self;
+
+    fn mul(self, rhs: i64) -> Self::Output {
+        self.checked_mul(rhs)
+            .expect("Price multiplication overflow")
+    }
+}
+
+/// Prints all `scale` digits after decimal point, e.g. `123.40` for scale 2
+impl Display for Price {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let abs = self.mantissa.unsigned_abs();
+
+        if self.scale == 0 {
+            return write!(f, "{sign}{abs}");
+        }
+
+        let divisor = 10u64.pow(self.scale as u32);
+        write!(
+            f,
+            "{sign}{}.{:0width$}",
+            abs / divisor,
+            abs % divisor,
+            width = self.scale as usize
+        )
+    }
+}
+
+/// Parses decimal notation, scale equals count of digits after decimal point
+impl FromStr for Price {
+    type Err = QuotesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || QuotesError::ParseQuoteError(format!("Invalid price {s:?}"));
+
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+        if integer.is_empty()
+            || !integer.bytes().all(|b| b.is_ascii_digit())
+            || !fraction.bytes().all(|b| b.is_ascii_digit())
+            || fraction.len() > Self::MAX_SCALE as usize
+            || (digits.contains('.') && fraction.is_empty())
+        {
+            return Err(error());
+        }
+
+        let mantissa = format!("{integer}{fraction}")
+            .parse::<i64>()
+            .map_err(|_| error())?;
+
+        Ok(Self {
+            mantissa: if negative { -mantissa } else { mantissa },
+            scale: fraction.len() as u8,
+        })
+    }
+}
+
+impl From<Price> for String {
+    fn from(value: Price) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for Price {
+    type Error = QuotesError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Price::new(12345, 2).to_string(), "123.45");
+        assert_eq!(Price::new(12340, 2).to_string(), "123.40");
+        assert_eq!(Price::new(5, 3).to_string(), "0.005");
+        assert_eq!(Price::new(-5, 1).to_string(), "-0.5");
+        assert_eq!(Price::new(42, 0).to_string(), "42");
+        assert_eq!(Price::new(i64::MIN, 0).to_string(), "-9223372036854775808");
+    }
+
+    #[test]
+    fn test_parse() {
+        for text in ["123.45", "123.40", "0.005", "-0.5", "42"] {
+            let price = text.parse::<Price>().expect("Should parse");
+            assert_eq!(price.to_string(), text);
+        }
+
+        for text in [
+            "",
+            "-",
+            ".5",
+            "1.",
+            "1.2.3",
+            "abc",
+            "1e5",
+            "+1",
+            "0.1234567890123456789",
+        ] {
+            assert!(text.parse::<Price>().is_err(), "{text} should not parse");
+        }
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        let a = "0.1".parse::<Price>().unwrap();
+        let b = "0.2".parse::<Price>().unwrap();
+        assert_eq!((a + b).to_string(), "0.3");
+
+        let price = "123.45".parse::<Price>().unwrap();
+        let tick = "0.001".parse::<Price>().unwrap();
+        assert_eq!((price - tick).to_string(), "123.449");
+        assert_eq!((price * 3).to_string(), "370.35");
+        assert_eq!(Price::new(1, 0).checked_add(Price::new(i64::MAX, 0)), None);
+    }
+
+    #[test]
+    fn test_min_mantissa() {
+        let min = Price::new(i64::MIN, 0);
+        assert_eq!(min.checked_neg(), None);
+        assert_eq!(Price::new(0, 0).checked_sub(min), None);
+        assert_eq!(
+            Price::new(-1, 0).checked_sub(min),
+            Some(Price::new(i64::MAX, 0))
+        );
+        assert_eq!(min.checked_sub(Price::new(1, 0)), None);
+        assert_eq!(-Price::new(-5, 1), Price::new(5, 1));
+    }
+
+    #[test]
+    fn test_compare_different_scales() {
+        assert_eq!(Price::new(1234, 1), Price::new(123400, 3));
+        assert!(Price::new(1234, 1) < Price::new(123401, 3));
+        assert_eq!(Price::new(123400, 3).normalized().scale(), 1);
+    }
+
+    #[test]
+    fn test_rescale() {
+        assert_eq!(
+            Price::new(12345, 2).rescale(4),
+            Some(Price::new(1234500, 4))
+        );
+        assert_eq!(Price::new(12340, 2).rescale(1), Some(Price::new(1234, 1)));
+        assert_eq!(Price::new(12345, 2).rescale(1), None);
+    }
+
+    #[test]
+    fn test_from_f64() {
+        assert_eq!(
+            Price::from_f64(123.45, 8).normalized().to_string(),
+            "123.45"
+        );
+        assert_eq!(Price::from_f64(0.1 + 0.2, 2).to_string(), "0.30");
+    }
+}
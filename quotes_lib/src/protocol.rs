@@ -0,0 +1,134 @@
+//! Protocol versions module
+use std::fmt::Display;
+
+use crate::error::QuotesError;
+
+/// Wire protocol versions negotiated during subscribe handshake
+/// Serialized as version number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "u8", try_from = "u8")
+)]
+pub enum ProtocolVersion {
+    /// Legacy protocol, quotes are encoded in splitter-separated format
+    V1 = 1,
+    /// Quotes are encoded in length-prefixed binary format
+    V2 = 2,
+    /// Adds top-of-book and trade messages, error codes, address challenges and heartbeats to V2
+    V3 = 3,
+}
+
+impl ProtocolVersion {
+    /// All versions supported by this library, from oldest to newest
+    pub const SUPPORTED: [ProtocolVersion; 3] = [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::V3,
+    ];
+
+    /// Newest supported version
+    pub const LATEST: ProtocolVersion = ProtocolVersion::V3;
+
+    /// Pick the newest version supported by both sides, None if there is no such version
+    pub fn negotiate(offered: &[u8]) -> Option<Self> {
+        offered
+            .iter()
+            .filter_map(|version| Self::try_from(*version).ok())
+            .max()
+    }
+}
+
+impl From<ProtocolVersion> for u8 {
+    fn from(value: ProtocolVersion) -> Self {
+        value as u8
+    }
+}
+
+impl TryFrom<u8> for ProtocolVersion {
+    type Error = QuotesError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::SUPPORTED
+            .into_iter()
+            .find(|version| u8::from(*version) == value)
+            .ok_or_else(|| QuotesError::UnsupportedProtocolVersion(value.to_string()))
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", u8::from(*self))
+    }
+}
+
+/// Encoding of messages in UDP stream, selected during subscribe handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[non_exhaustive]
+pub enum WireFormat {
+    /// Sequenced messages in binary datagram frames
+    #[default]
+    Binary,
+    /// One sequenced message per line encoded as JSON, human-readable for debugging
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl WireFormat {
+    const BINARY: &str = "binary";
+    #[cfg(feature = "json")]
+    const JSON: &str = "json";
+}
+
+impl Display for WireFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireFormat::Binary => write!(f, "{}", Self::BINARY),
+            #[cfg(feature = "json")]
+            WireFormat::Json => write!(f, "{}", Self::JSON),
+        }
+    }
+}
+
+impl TryFrom<&str> for WireFormat {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            Self::BINARY => Ok(WireFormat::Binary),
+            #[cfg(feature = "json")]
+            Self::JSON => Ok(WireFormat::Json),
+            other => Err(QuotesError::UnsupportedWireFormat(other.to_string())),
+        }
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_negotiate_picks_newest_common() {
+        assert_eq!(
+            ProtocolVersion::negotiate(&[1, 2, 7]),
+            Some(ProtocolVersion::V2)
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(&[3, 1, 2]),
+            Some(ProtocolVersion::V3)
+        );
+        assert_eq!(ProtocolVersion::negotiate(&[1]), Some(ProtocolVersion::V1));
+    }
+
+    #[test]
+    fn test_negotiate_no_common() {
+        assert_eq!(ProtocolVersion::negotiate(&[0, 7]), None);
+        assert_eq!(ProtocolVersion::negotiate(&[]), None);
+    }
+}
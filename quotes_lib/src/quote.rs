@@ -1,15 +1,21 @@
 //! Stock quote module
 use std::{array::TryFromSliceError, fmt::Display};
 
-use crate::error::QuotesError;
+use crate::{
+    error::QuotesError,
+    price::Price,
+    session::{TickerDictionary, split_ticker, split_ticker_id, write_ticker},
+    sink::{self, Sink},
+};
 
 /// Quote structure
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Quote {
     /// Ticker
     pub ticker: String,
     /// Last price
-    pub price: f64,
+    pub price: Price,
     /// Volume in units
     pub volume: u32,
     /// Unix timestamp in millis
@@ -26,22 +32,28 @@ impl Display for Quote {
     }
 }
 
-impl Quote {
-    const SPLITTER: u8 = b'|';
+/// Quote decoded without allocating, ticker is borrowed from the received bytes
+/// or, for compact quotes, from the session dictionary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuoteRef<'a> {
+    /// Ticker
+    pub ticker: &'a str,
+    /// Last price
+    pub price: Price,
+    /// Volume in units
+    pub volume: u32,
+    /// Unix timestamp in millis
+    pub timestamp: u64,
 }
 
-impl From<&Quote> for Vec<u8> {
-    fn from(value: &Quote) -> Self {
-        let mut data = vec![];
-        data.extend_from_slice(value.ticker.as_bytes());
-        data.push(Quote::SPLITTER);
-        data.extend_from_slice(&value.price.to_be_bytes());
-        data.push(Quote::SPLITTER);
-        data.extend_from_slice(&value.volume.to_be_bytes());
-        data.push(Quote::SPLITTER);
-        data.extend_from_slice(&value.timestamp.to_be_bytes());
-
-        data
+impl From<QuoteRef<'_>> for Quote {
+    fn from(value: QuoteRef<'_>) -> Self {
+        Self {
+            ticker: value.ticker.to_string(),
+            price: value.price,
+            volume: value.volume,
+            timestamp: value.timestamp,
+        }
     }
 }
 
@@ -55,38 +67,472 @@ macro_rules! slice_as_bytes {
     }};
 }
 
-impl TryFrom<&[u8]> for Quote {
-    type Error = QuotesError;
+impl Quote {
+    /// Splitter used by legacy format, kept only for decoding
+    const SPLITTER: u8 = b'|';
 
-    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let parts = value
-            .split(|b| *b == Quote::SPLITTER)
-            .filter(|part| !part.is_empty())
-            .collect::<Vec<_>>();
-
-        if parts.len() != 4
-            || parts[0].is_empty()
-            || parts[1].len() != 8
-            || parts[2].len() != 4
-            || parts[3].len() != 8
-        {
+    /// Version byte of the length-prefixed binary format.
+    /// Legacy format starts with ticker text, so it never begins with this byte
+    pub const FORMAT_VERSION: u8 = 3;
+
+    /// Version byte of binary format with ticker replaced by session dictionary id
+    pub const COMPACT_FORMAT_VERSION: u8 = 4;
+
+    /// Binary formats with f64 price, kept only for decoding
+    const FLOAT_FORMAT_VERSION: u8 = 1;
+    const COMPACT_FLOAT_FORMAT_VERSION: u8 = 2;
+
+    /// Scale of prices decoded from f64 fields, trailing zeros are trimmed afterwards
+    const FLOAT_PRICE_SCALE: u8 = 8;
+
+    const TICKER_LEN_SIZE: usize = 2;
+    const TICKER_ID_SIZE: usize = 2;
+    const FLOAT_PRICE_SIZE: usize = 8;
+    const PRICE_SIZE: usize = Price::ENCODED_SIZE;
+    const VOLUME_SIZE: usize = 4;
+    const TIMESTAMP_SIZE: usize = 8;
+    const NUMERIC_FIELDS_SIZE: usize = Self::PRICE_SIZE + Self::VOLUME_SIZE + Self::TIMESTAMP_SIZE;
+
+    /// Encode quote in legacy splitter-separated format.
+    /// Numeric fields may contain splitter byte, so prefer binary format for new peers
+    pub fn to_legacy_bytes(&self) -> Result<Vec<u8>, QuotesError> {
+        sink::encode_to_vec(
+            self.ticker.len()
+                + 3
+                + Self::FLOAT_PRICE_SIZE
+                + Self::VOLUME_SIZE
+                + Self::TIMESTAMP_SIZE,
+            |sink| self.write_legacy(sink),
+        )
+    }
+
+    /// Encode quote in compact binary format:
+    /// `[version: u8][ticker id: u16][price][volume: u32][timestamp: u64]`,
+    /// ticker id must be resolved by peer with the same session dictionary
+    pub fn to_compact_bytes(&self, ticker_id: u16) -> Result<Vec<u8>, QuotesError> {
+        sink::encode_to_vec(
+            1 + Self::TICKER_ID_SIZE + Self::NUMERIC_FIELDS_SIZE,
+            |sink| self.write_compact(ticker_id, sink),
+        )
+    }
+
+    /// Encode quote in length-prefixed binary format into buffer, returns count of bytes written
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write_binary(sink))
+    }
+
+    /// Encode quote in compact binary format into buffer, returns count of bytes written
+    pub fn encode_compact_into(
+        &self,
+        ticker_id: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write_compact(ticker_id, sink))
+    }
+
+    /// Decode quote in any format, resolving compact ticker ids with dictionary
+    pub fn decode_with(value: &[u8], dictionary: &TickerDictionary) -> Result<Self, QuotesError> {
+        QuoteRef::decode_with(value, dictionary).map(Quote::from)
+    }
+
+    pub(crate) fn write_legacy(&self, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        sink.put(self.ticker.as_bytes())?;
+        sink.put(&[Quote::SPLITTER])?;
+        sink.put(&self.price.to_f64().to_be_bytes())?;
+        sink.put(&[Quote::SPLITTER])?;
+        sink.put(&self.volume.to_be_bytes())?;
+        sink.put(&[Quote::SPLITTER])?;
+        sink.put(&self.timestamp.to_be_bytes())
+    }
+
+    pub(crate) fn write_binary(&self, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        sink.put(&[Self::FORMAT_VERSION])?;
+        write_ticker(sink, &self.ticker)?;
+        self.write_numeric_fields(sink)
+    }
+
+    pub(crate) fn write_compact(
+        &self,
+        ticker_id: u16,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        sink.put(&[Self::COMPACT_FORMAT_VERSION])?;
+        sink.put(&ticker_id.to_be_bytes())?;
+        self.write_numeric_fields(sink)
+    }
+
+    fn write_numeric_fields(&self, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        sink.put(&self.price.to_be_bytes())?;
+        sink.put(&self.volume.to_be_bytes())?;
+        sink.put(&self.timestamp.to_be_bytes())
+    }
+
+    fn price_from_float(price: f64) -> Price {
+        Price::from_f64(price, Self::FLOAT_PRICE_SCALE).normalized()
+    }
+}
+
+impl<'a> QuoteRef<'a> {
+    /// Decode quote in any format, resolving compact ticker ids with dictionary
+    pub fn decode_with(
+        value: &'a [u8],
+        dictionary: &'a TickerDictionary,
+    ) -> Result<Self, QuotesError> {
+        let decimal = match value.first() {
+            Some(&Quote::COMPACT_FORMAT_VERSION) => true,
+            Some(&Quote::COMPACT_FLOAT_FORMAT_VERSION) => false,
+            _ => return Self::try_from(value),
+        };
+
+        let (ticker, rest) = split_ticker_id(&value[1..], dictionary)?;
+        Self::decode_numeric_fields(ticker, rest, decimal)
+    }
+
+    fn decode_numeric_fields(
+        ticker: &'a str,
+        value: &[u8],
+        decimal: bool,
+    ) -> Result<Self, QuotesError> {
+        let price_end = if decimal {
+            Quote::PRICE_SIZE
+        } else {
+            Quote::FLOAT_PRICE_SIZE
+        };
+        let volume_end = price_end + Quote::VOLUME_SIZE;
+        let timestamp_end = volume_end + Quote::TIMESTAMP_SIZE;
+
+        if value.len() != timestamp_end {
             return Err(QuotesError::ParseQuoteError(
                 "Incorrect data format".to_string(),
             ));
         }
 
-        let ticker_bytes: Vec<u8> = parts[0].to_vec();
+        let price = if decimal {
+            Price::from_be_bytes(slice_as_bytes!(&value[..price_end], 9)?)?
+        } else {
+            Quote::price_from_float(f64::from_be_bytes(slice_as_bytes!(&value[..price_end], 8)?))
+        };
 
-        let price_pytes = slice_as_bytes!(parts[1], 8)?;
-        let volume_pytes = slice_as_bytes!(parts[2], 4)?;
-        let timestamp_pytes = slice_as_bytes!(parts[3], 8)?;
+        Ok(Self {
+            ticker,
+            price,
+            volume: u32::from_be_bytes(slice_as_bytes!(&value[price_end..volume_end], 4)?),
+            timestamp: u64::from_be_bytes(slice_as_bytes!(&value[volume_end..timestamp_end], 8)?),
+        })
+    }
+
+    fn decode_binary(value: &'a [u8], decimal: bool) -> Result<Self, QuotesError> {
+        let (ticker, rest) = split_ticker(&value[1..])?;
+        Self::decode_numeric_fields(ticker, rest, decimal)
+    }
+
+    fn decode_legacy(value: &'a [u8]) -> Result<Self, QuotesError> {
+        let mut parts = value
+            .split(|b| *b == Quote::SPLITTER)
+            .filter(|part| !part.is_empty());
+
+        let (Some(ticker), Some(price), Some(volume), Some(timestamp), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return Err(QuotesError::ParseQuoteError(
+                "Incorrect data format".to_string(),
+            ));
+        };
+
+        if price.len() != Quote::FLOAT_PRICE_SIZE
+            || volume.len() != Quote::VOLUME_SIZE
+            || timestamp.len() != Quote::TIMESTAMP_SIZE
+        {
+            return Err(QuotesError::ParseQuoteError(
+                "Incorrect data format".to_string(),
+            ));
+        }
 
         Ok(Self {
-            ticker: String::from_utf8(ticker_bytes)
+            ticker: std::str::from_utf8(ticker)
                 .map_err(|e| QuotesError::ParseQuoteError(e.to_string()))?,
-            price: f64::from_be_bytes(price_pytes),
-            volume: u32::from_be_bytes(volume_pytes),
-            timestamp: u64::from_be_bytes(timestamp_pytes),
+            price: Quote::price_from_float(f64::from_be_bytes(slice_as_bytes!(price, 8)?)),
+            volume: u32::from_be_bytes(slice_as_bytes!(volume, 4)?),
+            timestamp: u64::from_be_bytes(slice_as_bytes!(timestamp, 8)?),
         })
     }
 }
+
+/// Encodes quote in length-prefixed binary format:
+/// `[version: u8][ticker len: u16][ticker][price][volume: u32][timestamp: u64]`,
+/// price is `[mantissa: i64][scale: u8]`, all numbers are big-endian.
+/// Fails for tickers longer than `u16::MAX` bytes
+impl TryFrom<&Quote> for Vec<u8> {
+    type Error = QuotesError;
+
+    fn try_from(value: &Quote) -> Result<Self, Self::Error> {
+        sink::encode_to_vec(
+            1 + Quote::TICKER_LEN_SIZE + value.ticker.len() + Quote::NUMERIC_FIELDS_SIZE,
+            |sink| value.write_binary(sink),
+        )
+    }
+}
+
+/// Decodes quote in binary format, falls back to legacy splitter-separated format
+impl<'a> TryFrom<&'a [u8]> for QuoteRef<'a> {
+    type Error = QuotesError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        match value.first() {
+            Some(&Quote::FORMAT_VERSION) => Self::decode_binary(value, true),
+            Some(&Quote::FLOAT_FORMAT_VERSION) => Self::decode_binary(value, false),
+            Some(&Quote::COMPACT_FORMAT_VERSION | &Quote::COMPACT_FLOAT_FORMAT_VERSION) => {
+                Err(QuotesError::ParseQuoteError(
+                    "Compact quote requires ticker dictionary".to_string(),
+                ))
+            }
+            _ => Self::decode_legacy(value),
+        }
+    }
+}
+
+/// Decodes quote in binary format, falls back to legacy splitter-separated format
+impl TryFrom<&[u8]> for Quote {
+    type Error = QuotesError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        QuoteRef::try_from(value).map(Quote::from)
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    fn sample_quote() -> Quote {
+        Quote {
+            ticker: "AAPL".to_string(),
+            price: Price::new(12345, 2),
+            volume: 1500,
+            timestamp: 1_700_000_000_000,
+        }
+    }
+
+    fn assert_same(expected: &Quote, actual: &Quote) {
+        assert_eq!(expected.ticker, actual.ticker);
+        assert_eq!(expected.price.mantissa(), actual.price.mantissa());
+        assert_eq!(expected.price.scale(), actual.price.scale());
+        assert_eq!(expected.volume, actual.volume);
+        assert_eq!(expected.timestamp, actual.timestamp);
+    }
+
+    fn assert_roundtrip(quote: &Quote) {
+        let bytes = Vec::<u8>::try_from(quote).expect("Should encode quote");
+        let decoded = Quote::try_from(bytes.as_slice()).expect("Should decode quote");
+        assert_same(quote, &decoded);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        assert_roundtrip(&sample_quote());
+    }
+
+    #[test]
+    fn test_every_price_byte() {
+        for position in 0..8 {
+            for value in 0..=u8::MAX {
+                let mut bytes = [0u8; 8];
+                bytes[position] = value;
+                let quote = Quote {
+                    price: Price::new(i64::from_be_bytes(bytes), 2),
+                    ..sample_quote()
+                };
+                assert_roundtrip(&quote);
+            }
+        }
+
+        for scale in 0..=Price::MAX_SCALE {
+            let quote = Quote {
+                price: Price::new(12345, scale),
+                ..sample_quote()
+            };
+            assert_roundtrip(&quote);
+        }
+    }
+
+    #[test]
+    fn test_invalid_price_scale() {
+        let mut bytes = Vec::<u8>::try_from(&sample_quote()).expect("Should encode quote");
+        // scale is the last byte of price, followed by volume and timestamp
+        let scale_position = bytes.len() - 4 - 8 - 1;
+        for scale in Price::MAX_SCALE + 1..=u8::MAX {
+            bytes[scale_position] = scale;
+            assert!(Quote::try_from(bytes.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_every_volume_byte() {
+        for position in 0..4 {
+            for value in 0..=u8::MAX {
+                let mut bytes = [0u8; 4];
+                bytes[position] = value;
+                let quote = Quote {
+                    volume: u32::from_be_bytes(bytes),
+                    ..sample_quote()
+                };
+                assert_roundtrip(&quote);
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_timestamp_byte() {
+        for position in 0..8 {
+            for value in 0..=u8::MAX {
+                let mut bytes = [0u8; 8];
+                bytes[position] = value;
+                let quote = Quote {
+                    timestamp: u64::from_be_bytes(bytes),
+                    ..sample_quote()
+                };
+                assert_roundtrip(&quote);
+            }
+        }
+    }
+
+    #[test]
+    fn test_splitter_in_every_field() {
+        let splitter = Quote::SPLITTER;
+        let quote = Quote {
+            ticker: "A|B".to_string(),
+            price: Price::new(
+                i64::from_be_bytes([splitter; 8]),
+                splitter % Price::MAX_SCALE,
+            ),
+            volume: u32::from_be_bytes([splitter; 4]),
+            timestamp: u64::from_be_bytes([splitter; 8]),
+        };
+        assert_roundtrip(&quote);
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let quote = sample_quote();
+        let dictionary = TickerDictionary::new(&["MSFT".to_string(), "AAPL".to_string()]);
+        let id = dictionary
+            .id(&quote.ticker)
+            .expect("Ticker should be in dictionary");
+
+        let bytes = quote.to_compact_bytes(id).expect("Should encode quote");
+        let decoded = Quote::decode_with(&bytes, &dictionary).expect("Should decode quote");
+        assert_same(&quote, &decoded);
+
+        assert!(Quote::try_from(bytes.as_slice()).is_err());
+        assert!(Quote::decode_with(&bytes, &TickerDictionary::default()).is_err());
+    }
+
+    #[test]
+    fn test_decode_float_binary() {
+        let quote = sample_quote();
+        let mut bytes = vec![Quote::FLOAT_FORMAT_VERSION];
+        bytes.extend_from_slice(&(quote.ticker.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(quote.ticker.as_bytes());
+        bytes.extend_from_slice(&123.45f64.to_be_bytes());
+        bytes.extend_from_slice(&quote.volume.to_be_bytes());
+        bytes.extend_from_slice(&quote.timestamp.to_be_bytes());
+
+        let decoded = Quote::try_from(bytes.as_slice()).expect("Should decode quote");
+        assert_same(&quote, &decoded);
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        let quote = sample_quote();
+        let decoded = Quote::try_from(
+            quote
+                .to_legacy_bytes()
+                .expect("Should encode quote")
+                .as_slice(),
+        )
+        .expect("Should decode quote");
+        assert_same(&quote, &decoded);
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let bytes = Vec::<u8>::try_from(&sample_quote()).expect("Should encode quote");
+        for len in 0..bytes.len() {
+            assert!(Quote::try_from(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode_trailing_bytes() {
+        let mut bytes = Vec::<u8>::try_from(&sample_quote()).expect("Should encode quote");
+        bytes.push(0);
+        assert!(Quote::try_from(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_quote_ref_borrows_ticker() {
+        let quote = sample_quote();
+        let bytes = Vec::<u8>::try_from(&quote).expect("Should encode quote");
+
+        let decoded = QuoteRef::try_from(bytes.as_slice()).expect("Should decode quote");
+        assert!(bytes.as_ptr_range().contains(&decoded.ticker.as_ptr()));
+        assert_eq!(Quote::from(decoded), quote);
+
+        let dictionary = TickerDictionary::new(&["AAPL".to_string()]);
+        let compact = quote.to_compact_bytes(0).expect("Should encode quote");
+        let decoded = QuoteRef::decode_with(&compact, &dictionary).expect("Should decode quote");
+        assert_eq!(decoded.ticker, "AAPL");
+        assert_eq!(Quote::from(decoded), quote);
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let quote = sample_quote();
+        let mut buf = [0u8; 64];
+
+        let len = quote.encode_into(&mut buf).expect("Should fit");
+        assert_eq!(
+            &buf[..len],
+            Vec::<u8>::try_from(&quote)
+                .expect("Should encode quote")
+                .as_slice()
+        );
+
+        let len = quote.encode_compact_into(7, &mut buf).expect("Should fit");
+        assert_eq!(
+            &buf[..len],
+            quote
+                .to_compact_bytes(7)
+                .expect("Should encode quote")
+                .as_slice()
+        );
+
+        assert!(matches!(
+            quote.encode_into(&mut buf[..10]),
+            Err(QuotesError::BufferTooSmall(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_too_long_ticker() {
+        let quote = Quote {
+            ticker: "A".repeat(u16::MAX as usize + 1),
+            ..sample_quote()
+        };
+        let mut buf = vec![0u8; quote.ticker.len() + 64];
+
+        assert!(matches!(
+            quote.encode_into(&mut buf),
+            Err(QuotesError::EncodeError(_))
+        ));
+        assert!(matches!(
+            Vec::<u8>::try_from(&quote),
+            Err(QuotesError::EncodeError(_))
+        ));
+        assert!(quote.encode_compact_into(7, &mut buf).is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+//! Sequence numbers tracking for received streams
+use std::{collections::BTreeSet, fmt::Display};
+
+/// Irregularity detected in received sequence numbers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SequenceAnomaly {
+    /// Messages from `expected` up to `received` (exclusive) were not received
+    Gap {
+        /// next sequence number that was expected
+        expected: u64,
+        /// sequence number actually received
+        received: u64,
+    },
+    /// Message was already received before
+    Duplicate(u64),
+    /// Message previously reported as missing arrived late
+    Reordered(u64),
+    /// Too many duplicates in a row, tracking restarted from `received`
+    Resync {
+        /// next sequence number that was expected
+        expected: u64,
+        /// sequence number tracking continues from
+        received: u64,
+    },
+}
+
+impl Display for SequenceAnomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SequenceAnomaly::Gap { expected, received } => write!(
+                f,
+                "gap: expected #{expected}, received #{received}, {} messages missing",
+                received - expected
+            ),
+            SequenceAnomaly::Duplicate(sequence) => write!(f, "duplicate #{sequence}"),
+            SequenceAnomaly::Reordered(sequence) => write!(f, "late arrival #{sequence}"),
+            SequenceAnomaly::Resync { expected, received } => write!(
+                f,
+                "resync: expected #{expected}, continuing from #{received}"
+            ),
+        }
+    }
+}
+
+/// Tracks sequence numbers of a single stream to detect lost, duplicated and reordered messages
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    /// next expected sequence number
+    next: u64,
+    /// sequence numbers reported as missing, that may still arrive
+    missing: BTreeSet<u64>,
+    /// count of duplicates received in a row
+    duplicates: usize,
+}
+
+impl SequenceTracker {
+    /// Max count of missing sequence numbers remembered to recognize late arrivals
+    const MAX_MISSING: usize = 1024;
+    /// Duplicates in a row after which the stream is assumed to have jumped back, e.g. after
+    /// a single corrupted far-ahead sequence number
+    const RESYNC_AFTER: usize = 8;
+
+    /// Create new tracker expecting stream to start from 0
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register received sequence number, returns anomaly if it is not the expected one
+    pub fn track(&mut self, sequence: u64) -> Option<SequenceAnomaly> {
+        if sequence < self.next {
+            return self.track_past(sequence);
+        }
+        self.duplicates = 0;
+
+        let expected = self.next;
+        // the last sequence number leaves tracker expecting it again, stream can't go further
+        self.next = sequence.saturating_add(1);
+        if sequence == expected {
+            return None;
+        }
+
+        let remembered_from = expected.max(sequence.saturating_sub(Self::MAX_MISSING as u64));
+        self.missing.extend(remembered_from..sequence);
+        while self.missing.len() > Self::MAX_MISSING {
+            self.missing.pop_first();
+        }
+
+        Some(SequenceAnomaly::Gap {
+            expected,
+            received: sequence,
+        })
+    }
+
+    /// Sequence number below the expected one is either a late arrival or a duplicate
+    fn track_past(&mut self, sequence: u64) -> Option<SequenceAnomaly> {
+        if self.missing.remove(&sequence) {
+            self.duplicates = 0;
+            return Some(SequenceAnomaly::Reordered(sequence));
+        }
+
+        self.duplicates += 1;
+        if self.duplicates < Self::RESYNC_AFTER {
+            return Some(SequenceAnomaly::Duplicate(sequence));
+        }
+
+        let expected = self.next;
+        self.duplicates = 0;
+        self.missing.clear();
+        self.next = sequence.saturating_add(1);
+        Some(SequenceAnomaly::Resync {
+            expected,
+            received: sequence,
+        })
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_in_order() {
+        let mut tracker = SequenceTracker::new();
+        for sequence in 0..10 {
+            assert_eq!(tracker.track(sequence), None);
+        }
+    }
+
+    #[test]
+    fn test_gap_and_late_arrival() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.track(0), None);
+        assert_eq!(
+            tracker.track(3),
+            Some(SequenceAnomaly::Gap {
+                expected: 1,
+                received: 3
+            })
+        );
+        assert_eq!(tracker.track(2), Some(SequenceAnomaly::Reordered(2)));
+        assert_eq!(tracker.track(2), Some(SequenceAnomaly::Duplicate(2)));
+        assert_eq!(tracker.track(4), None);
+        assert_eq!(tracker.track(1), Some(SequenceAnomaly::Reordered(1)));
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.track(0), None);
+        assert_eq!(tracker.track(0), Some(SequenceAnomaly::Duplicate(0)));
+    }
+
+    #[test]
+    fn test_huge_gap_is_bounded() {
+        let mut tracker = SequenceTracker::new();
+        assert!(tracker.track(u64::MAX - 1).is_some());
+        assert_eq!(tracker.missing.len(), SequenceTracker::MAX_MISSING);
+        assert_eq!(tracker.track(0), Some(SequenceAnomaly::Duplicate(0)));
+    }
+
+    #[test]
+    fn test_last_sequence() {
+        let mut tracker = SequenceTracker::new();
+        tracker.next = u64::MAX - 1;
+        assert_eq!(tracker.track(u64::MAX - 1), None);
+        assert_eq!(tracker.track(u64::MAX), None);
+        assert_eq!(tracker.track(5), Some(SequenceAnomaly::Duplicate(5)));
+
+        let mut tracker = SequenceTracker::new();
+        assert!(tracker.track(u64::MAX).is_some());
+    }
+
+    #[test]
+    fn test_resync_after_far_ahead_jump() {
+        let mut tracker = SequenceTracker::new();
+        for sequence in 0..3 {
+            assert_eq!(tracker.track(sequence), None);
+        }
+        assert_eq!(
+            tracker.track(1_000_000),
+            Some(SequenceAnomaly::Gap {
+                expected: 3,
+                received: 1_000_000
+            })
+        );
+        let last = 3 + SequenceTracker::RESYNC_AFTER as u64 - 1;
+        for sequence in 3..last {
+            assert_eq!(
+                tracker.track(sequence),
+                Some(SequenceAnomaly::Duplicate(sequence))
+            );
+        }
+        assert_eq!(
+            tracker.track(last),
+            Some(SequenceAnomaly::Resync {
+                expected: 1_000_001,
+                received: last
+            })
+        );
+        assert_eq!(tracker.track(last + 1), None);
+        assert!(tracker.missing.is_empty());
+    }
+}
@@ -1,73 +1,493 @@
 //! Server messages module
 use std::fmt::Display;
 
-use crate::{datagram::Datagram, error::QuotesError, quote::Quote};
+use crate::{
+    datagram::Datagram,
+    error::QuotesError,
+    error_message::ErrorMessage,
+    protocol::ProtocolVersion,
+    quote::{Quote, QuoteRef},
+    session::TickerDictionary,
+    sink::{self, Sink},
+    top_of_book::TopOfBook,
+    trade::Trade,
+};
 
 /// Server messages variants
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum ServerMessage {
     /// Message containing quote
     Quote(Quote),
-    /// Message with error description
-    Err(String),
+    /// Several quotes packed into a single message, requires V2
+    QuoteBatch(Vec<Quote>),
+    /// Best bid and ask of an instrument, requires V3
+    TopOfBook(TopOfBook),
+    /// Several books packed into a single message, requires V3
+    BookBatch(Vec<TopOfBook>),
+    /// Trade print, requires V3
+    Trade(Trade),
+    /// Several trades packed into a single message, requires V3
+    TradeBatch(Vec<Trade>),
+    /// Error reported by server, peers older than V3 get only its detail text
+    #[cfg_attr(feature = "serde", serde(rename = "error"))]
+    Err(ErrorMessage),
+    /// Cookie client echoes back with [`VerifyMessage`](crate::subscribe_message::VerifyMessage)
+    /// to prove it receives stream at subscribed address, nothing else is streamed until it does.
+    /// Requires V3
+    Challenge(u64),
+    /// Sent when stream is idle for heartbeat interval, so client tells quiet market from dead server.
+    /// Requires V3
+    Heartbeat,
 }
 
 impl Display for ServerMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ServerMessage::Quote(quote) => write!(f, "QUOTE({quote})"),
+            ServerMessage::QuoteBatch(quotes) => write!(f, "QUOTE_BATCH({} quotes)", quotes.len()),
+            ServerMessage::TopOfBook(book) => write!(f, "TOP_OF_BOOK({book})"),
+            ServerMessage::BookBatch(books) => write!(f, "BOOK_BATCH({} books)", books.len()),
+            ServerMessage::Trade(trade) => write!(f, "TRADE({trade})"),
+            ServerMessage::TradeBatch(trades) => write!(f, "TRADE_BATCH({} trades)", trades.len()),
             ServerMessage::Err(message) => write!(f, "ERROR({message})"),
+            ServerMessage::Challenge(_) => write!(f, "CHALLENGE"),
+            ServerMessage::Heartbeat => write!(f, "HEARTBEAT"),
         }
     }
 }
 
 impl ServerMessage {
     const QUOTE_TYPE_CODE: u8 = 0;
+    const QUOTE_BATCH_TYPE_CODE: u8 = 1;
+    const TOP_OF_BOOK_TYPE_CODE: u8 = 2;
+    const BOOK_BATCH_TYPE_CODE: u8 = 3;
+    const TRADE_TYPE_CODE: u8 = 4;
+    const TRADE_BATCH_TYPE_CODE: u8 = 5;
+    const CHALLENGE_TYPE_CODE: u8 = 6;
+    const HEARTBEAT_TYPE_CODE: u8 = 7;
     const ERROR_TYPE_CODE: u8 = u8::MAX;
 
+    const TYPE_CODE_SIZE: usize = 1;
+    const COUNT_SIZE: usize = 2;
+    const LENGTH_SIZE: usize = 2;
+
     fn type_code(&self) -> u8 {
         match self {
             ServerMessage::Quote(_) => ServerMessage::QUOTE_TYPE_CODE,
+            ServerMessage::QuoteBatch(_) => ServerMessage::QUOTE_BATCH_TYPE_CODE,
+            ServerMessage::TopOfBook(_) => ServerMessage::TOP_OF_BOOK_TYPE_CODE,
+            ServerMessage::BookBatch(_) => ServerMessage::BOOK_BATCH_TYPE_CODE,
+            ServerMessage::Trade(_) => ServerMessage::TRADE_TYPE_CODE,
+            ServerMessage::TradeBatch(_) => ServerMessage::TRADE_BATCH_TYPE_CODE,
             ServerMessage::Err(_) => ServerMessage::ERROR_TYPE_CODE,
+            ServerMessage::Challenge(_) => ServerMessage::CHALLENGE_TYPE_CODE,
+            ServerMessage::Heartbeat => ServerMessage::HEARTBEAT_TYPE_CODE,
+        }
+    }
+
+    /// Oldest protocol version having message type, None for unknown types
+    fn since(type_code: u8) -> Option<ProtocolVersion> {
+        match type_code {
+            ServerMessage::QUOTE_TYPE_CODE | ServerMessage::ERROR_TYPE_CODE => {
+                Some(ProtocolVersion::V1)
+            }
+            ServerMessage::QUOTE_BATCH_TYPE_CODE => Some(ProtocolVersion::V2),
+            ServerMessage::TOP_OF_BOOK_TYPE_CODE
+            | ServerMessage::BOOK_BATCH_TYPE_CODE
+            | ServerMessage::TRADE_TYPE_CODE
+            | ServerMessage::TRADE_BATCH_TYPE_CODE
+            | ServerMessage::CHALLENGE_TYPE_CODE
+            | ServerMessage::HEARTBEAT_TYPE_CODE => Some(ProtocolVersion::V3),
+            _ => None,
+        }
+    }
+
+    /// Peer of given version can't send message types added later, such bytes are malformed
+    fn check_version(type_code: u8, version: ProtocolVersion) -> Result<(), QuotesError> {
+        match Self::since(type_code) {
+            Some(since) if since > version => Err(QuotesError::ParseServerMessageError(format!(
+                "Message code {type_code} requires protocol v{since}, session uses v{version}"
+            ))),
+            Some(_) => Ok(()),
+            None => Err(QuotesError::ParseServerMessageError(format!(
+                "Unexpected message code {type_code}"
+            ))),
+        }
+    }
+
+    /// Quotes of tickers found in dictionary are encoded with compact ticker id
+    fn write_quote(
+        quote: &Quote,
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        match version {
+            ProtocolVersion::V1 => quote.write_legacy(sink),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => {
+                match dictionary.and_then(|d| d.id(&quote.ticker)) {
+                    Some(id) => quote.write_compact(id, sink),
+                    None => quote.write_binary(sink),
+                }
+            }
         }
     }
 
-    fn content_bytes(&self) -> Vec<u8> {
+    /// Books have no legacy format, so they are encoded the same way for any version
+    fn write_book(
+        book: &TopOfBook,
+        dictionary: Option<&TickerDictionary>,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        match dictionary.and_then(|d| d.id(book.ticker())) {
+            Some(id) => book.write_compact(id, sink),
+            None => book.write_binary(sink),
+        }
+    }
+
+    /// Trades have no legacy format either
+    fn write_trade(
+        trade: &Trade,
+        dictionary: Option<&TickerDictionary>,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        match dictionary.and_then(|d| d.id(&trade.ticker)) {
+            Some(id) => trade.write_compact(id, sink),
+            None => trade.write_binary(sink),
+        }
+    }
+
+    /// Batch content: `[count: u16]` followed by `[len: u16][item]` for every item.
+    /// Item length is patched in after the item is written, so it is never encoded twice
+    fn write_batch<T, S: Sink>(
+        items: &[T],
+        sink: &mut S,
+        write_item: impl Fn(&T, &mut S) -> Result<(), QuotesError>,
+    ) -> Result<(), QuotesError> {
+        let count = u16::try_from(items.len()).map_err(|_| {
+            QuotesError::EncodeError(format!("Batch of {} items is too long", items.len()))
+        })?;
+        sink.put(&count.to_be_bytes())?;
+        for item in items {
+            let length_position = sink.position();
+            sink.put(&[0; Self::LENGTH_SIZE])?;
+            write_item(item, sink)?;
+
+            let len = sink.position() - length_position - Self::LENGTH_SIZE;
+            let len = u16::try_from(len).map_err(|_| {
+                QuotesError::EncodeError(format!("Batch item of {len} bytes is too long"))
+            })?;
+            sink.patch(length_position, &len.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    fn write_content(
+        &self,
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
         match self {
-            ServerMessage::Quote(quote) => quote.into(),
-            ServerMessage::Err(message) => message.as_bytes().to_vec(),
+            ServerMessage::Quote(quote) => Self::write_quote(quote, version, dictionary, sink),
+            ServerMessage::QuoteBatch(quotes) => Self::write_batch(quotes, sink, |quote, sink| {
+                Self::write_quote(quote, version, dictionary, sink)
+            }),
+            ServerMessage::TopOfBook(book) => Self::write_book(book, dictionary, sink),
+            ServerMessage::BookBatch(books) => Self::write_batch(books, sink, |book, sink| {
+                Self::write_book(book, dictionary, sink)
+            }),
+            ServerMessage::Trade(trade) => Self::write_trade(trade, dictionary, sink),
+            ServerMessage::TradeBatch(trades) => Self::write_batch(trades, sink, |trade, sink| {
+                Self::write_trade(trade, dictionary, sink)
+            }),
+            ServerMessage::Err(message) => message.write(version, sink),
+            ServerMessage::Challenge(cookie) => sink.put(&cookie.to_be_bytes()),
+            ServerMessage::Heartbeat => Ok(()),
         }
     }
-}
 
-impl From<ServerMessage> for Vec<u8> {
-    fn from(value: ServerMessage) -> Self {
-        let mut result = vec![];
-        result.push(value.type_code());
-        result.extend_from_slice(&value.content_bytes());
-        result
+    pub(crate) fn write(
+        &self,
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        sink.put(&[self.type_code()])?;
+        self.write_content(version, dictionary, sink)
     }
-}
 
-impl TryFrom<Vec<u8>> for ServerMessage {
-    type Error = QuotesError;
+    /// Pack quotes into as few QuoteBatch messages as possible,
+    /// so that every encoded message is not longer than `max_len` bytes.
+    /// Quote that doesn't fit `max_len` on its own is sent in a batch of one,
+    /// so is quote that can't be encoded at all, encoding that batch reports the error
+    pub(crate) fn pack_quotes(
+        quotes: Vec<Quote>,
+        max_len: usize,
+        dictionary: Option<&TickerDictionary>,
+    ) -> Vec<ServerMessage> {
+        Self::pack(
+            quotes,
+            max_len,
+            |quote| {
+                sink::encoded_len(|sink| {
+                    Self::write_quote(quote, ProtocolVersion::V2, dictionary, sink)
+                })
+            },
+            ServerMessage::QuoteBatch,
+        )
+    }
 
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        if value.is_empty() {
+    /// Pack books into as few BookBatch messages as possible, same way as quotes
+    pub(crate) fn pack_books(
+        books: Vec<TopOfBook>,
+        max_len: usize,
+        dictionary: Option<&TickerDictionary>,
+    ) -> Vec<ServerMessage> {
+        Self::pack(
+            books,
+            max_len,
+            |book| sink::encoded_len(|sink| Self::write_book(book, dictionary, sink)),
+            ServerMessage::BookBatch,
+        )
+    }
+
+    /// Pack trades into as few TradeBatch messages as possible, same way as quotes
+    pub(crate) fn pack_trades(
+        trades: Vec<Trade>,
+        max_len: usize,
+        dictionary: Option<&TickerDictionary>,
+    ) -> Vec<ServerMessage> {
+        Self::pack(
+            trades,
+            max_len,
+            |trade| sink::encoded_len(|sink| Self::write_trade(trade, dictionary, sink)),
+            ServerMessage::TradeBatch,
+        )
+    }
+
+    fn pack<T>(
+        items: Vec<T>,
+        max_len: usize,
+        item_len: impl Fn(&T) -> Result<usize, QuotesError>,
+        batch_message: fn(Vec<T>) -> ServerMessage,
+    ) -> Vec<ServerMessage> {
+        let empty_len = Self::TYPE_CODE_SIZE + Self::COUNT_SIZE;
+
+        let mut batches = vec![];
+        let mut batch = vec![];
+        let mut batch_len = empty_len;
+
+        for item in items {
+            let len = Self::LENGTH_SIZE + item_len(&item).unwrap_or(max_len);
+
+            if !batch.is_empty() && (batch_len + len > max_len || batch.len() == u16::MAX as usize)
+            {
+                batches.push(batch_message(std::mem::take(&mut batch)));
+                batch_len = empty_len;
+            }
+
+            batch.push(item);
+            batch_len += len;
+        }
+
+        if !batch.is_empty() {
+            batches.push(batch_message(batch));
+        }
+
+        batches
+    }
+
+    fn decode_quote(
+        value: &[u8],
+        dictionary: Option<&TickerDictionary>,
+    ) -> Result<Quote, QuotesError> {
+        match dictionary {
+            Some(dictionary) => Quote::decode_with(value, dictionary),
+            None => Quote::try_from(value),
+        }
+    }
+
+    fn decode_book(
+        value: &[u8],
+        dictionary: Option<&TickerDictionary>,
+    ) -> Result<TopOfBook, QuotesError> {
+        match dictionary {
+            Some(dictionary) => TopOfBook::decode_with(value, dictionary),
+            None => TopOfBook::try_from(value),
+        }
+    }
+
+    fn decode_trade(
+        value: &[u8],
+        dictionary: Option<&TickerDictionary>,
+    ) -> Result<Trade, QuotesError> {
+        match dictionary {
+            Some(dictionary) => Trade::decode_with(value, dictionary),
+            None => Trade::try_from(value),
+        }
+    }
+
+    fn decode_batch<T>(
+        value: &[u8],
+        decode_item: impl Fn(&[u8]) -> Result<T, QuotesError>,
+    ) -> Result<Vec<T>, QuotesError> {
+        let mut items = vec![];
+        Self::visit_batch(value, |item| {
+            items.push(decode_item(item)?);
+            Ok(())
+        })?;
+        Ok(items)
+    }
+
+    /// Pass every item of batch content to `visit_item` without copying it
+    fn visit_batch<'a>(
+        value: &'a [u8],
+        mut visit_item: impl FnMut(&'a [u8]) -> Result<(), QuotesError>,
+    ) -> Result<(), QuotesError> {
+        let error = |reason: &str| QuotesError::ParseServerMessageError(reason.to_string());
+
+        if value.len() < Self::COUNT_SIZE {
+            return Err(error("Not enough bytes for batch size"));
+        }
+
+        let count = u16::from_be_bytes([value[0], value[1]]) as usize;
+        let mut rest = &value[Self::COUNT_SIZE..];
+
+        for _ in 0..count {
+            if rest.len() < Self::LENGTH_SIZE {
+                return Err(error("Not enough bytes for item length"));
+            }
+            let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            rest = &rest[Self::LENGTH_SIZE..];
+
+            if rest.len() < len {
+                return Err(error("Not enough bytes for item"));
+            }
+            let (item, tail) = rest.split_at(len);
+            visit_item(item)?;
+            rest = tail;
+        }
+
+        if !rest.is_empty() {
+            return Err(error("Unexpected bytes after batch"));
+        }
+
+        Ok(())
+    }
+
+    /// Decode quotes of Quote and QuoteBatch messages without allocating.
+    /// Returns false for other messages, they have to be decoded with `decode_with`
+    pub(crate) fn visit_quotes<'a>(
+        value: &'a [u8],
+        version: ProtocolVersion,
+        dictionary: &'a TickerDictionary,
+        mut on_quote: impl FnMut(QuoteRef<'a>),
+    ) -> Result<bool, QuotesError> {
+        let Some((&type_code, content)) = value.split_first() else {
             return Err(QuotesError::ParseServerMessageError(
                 "Bytes are empty".to_string(),
             ));
-        }
-        match value[0] {
+        };
+        Self::check_version(type_code, version)?;
+
+        match type_code {
             ServerMessage::QUOTE_TYPE_CODE => {
-                Ok(ServerMessage::Quote(Quote::try_from(&value[1..])?))
+                on_quote(QuoteRef::decode_with(content, dictionary)?);
             }
-            ServerMessage::ERROR_TYPE_CODE => {
-                let message = String::from_utf8(value[1..].to_vec())
-                    .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))?;
+            ServerMessage::QUOTE_BATCH_TYPE_CODE => Self::visit_batch(content, |quote| {
+                on_quote(QuoteRef::decode_with(quote, dictionary)?);
+                Ok(())
+            })?,
+            _ => return Ok(false),
+        }
 
-                Ok(ServerMessage::Err(message))
-            }
+        Ok(true)
+    }
+
+    /// Encode message for peer using given protocol version,
+    /// fails if message content doesn't fit the wire format
+    pub fn encode(&self, version: ProtocolVersion) -> Result<Vec<u8>, QuotesError> {
+        self.encode_with(version, None)
+    }
+
+    /// Encode message for peer using given protocol version into buffer,
+    /// returns count of bytes written
+    pub fn encode_into(
+        &self,
+        version: ProtocolVersion,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write(version, None, sink))
+    }
+
+    pub(crate) fn encode_with(
+        &self,
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+    ) -> Result<Vec<u8>, QuotesError> {
+        sink::encode_to_vec(0, |sink| self.write(version, dictionary, sink))
+    }
+
+    /// Decode message received from peer using given protocol version.
+    /// Quote decoding accepts both legacy and binary formats regardless of version
+    pub fn decode(value: &[u8], version: ProtocolVersion) -> Result<Self, QuotesError> {
+        Self::decode_with(value, version, None)
+    }
+
+    pub(crate) fn decode_with(
+        value: &[u8],
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+    ) -> Result<Self, QuotesError> {
+        if value.is_empty() {
+            return Err(QuotesError::ParseServerMessageError(
+                "Bytes are empty".to_string(),
+            ));
+        }
+        Self::check_version(value[0], version)?;
+        match value[0] {
+            ServerMessage::QUOTE_TYPE_CODE => Ok(ServerMessage::Quote(Self::decode_quote(
+                &value[1..],
+                dictionary,
+            )?)),
+            ServerMessage::QUOTE_BATCH_TYPE_CODE => Ok(ServerMessage::QuoteBatch(
+                Self::decode_batch(&value[1..], |quote| Self::decode_quote(quote, dictionary))?,
+            )),
+            ServerMessage::TOP_OF_BOOK_TYPE_CODE => Ok(ServerMessage::TopOfBook(
+                Self::decode_book(&value[1..], dictionary)?,
+            )),
+            ServerMessage::BOOK_BATCH_TYPE_CODE => Ok(ServerMessage::BookBatch(
+                Self::decode_batch(&value[1..], |book| Self::decode_book(book, dictionary))?,
+            )),
+            ServerMessage::TRADE_TYPE_CODE => Ok(ServerMessage::Trade(Self::decode_trade(
+                &value[1..],
+                dictionary,
+            )?)),
+            ServerMessage::TRADE_BATCH_TYPE_CODE => Ok(ServerMessage::TradeBatch(
+                Self::decode_batch(&value[1..], |trade| Self::decode_trade(trade, dictionary))?,
+            )),
+            ServerMessage::ERROR_TYPE_CODE => Ok(ServerMessage::Err(ErrorMessage::decode(
+                &value[1..],
+                version,
+            )?)),
+            ServerMessage::CHALLENGE_TYPE_CODE => Ok(ServerMessage::Challenge(u64::from_be_bytes(
+                value[1..].try_into().map_err(|_| {
+                    QuotesError::ParseServerMessageError(format!(
+                        "Challenge of {} bytes",
+                        value.len() - 1
+                    ))
+                })?,
+            ))),
+            ServerMessage::HEARTBEAT_TYPE_CODE if value.len() == 1 => Ok(ServerMessage::Heartbeat),
+            ServerMessage::HEARTBEAT_TYPE_CODE => Err(QuotesError::ParseServerMessageError(
+                format!("Heartbeat of {} bytes", value.len() - 1),
+            )),
             other => Err(QuotesError::ParseServerMessageError(format!(
                 "Unexpected message code {other}"
             ))),
@@ -75,10 +495,537 @@ impl TryFrom<Vec<u8>> for ServerMessage {
     }
 }
 
-impl TryFrom<Datagram> for ServerMessage {
+impl TryFrom<ServerMessage> for Vec<u8> {
+    type Error = QuotesError;
+
+    fn try_from(value: ServerMessage) -> Result<Self, Self::Error> {
+        value.encode(ProtocolVersion::LATEST)
+    }
+}
+
+impl TryFrom<Vec<u8>> for ServerMessage {
+    type Error = QuotesError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::decode(&value, ProtocolVersion::LATEST)
+    }
+}
+
+/// Server message tagged with its position in the client stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequencedMessage {
+    /// per-subscription sequence number, grows by one with every message sent
+    pub sequence: u64,
+    /// message itself
+    pub message: ServerMessage,
+}
+
+impl SequencedMessage {
+    /// Count of bytes sequence number adds to V2 and later messages
+    pub(crate) const SEQUENCE_SIZE: usize = 8;
+
+    /// Create new SequencedMessage
+    pub fn new(sequence: u64, message: ServerMessage) -> Self {
+        Self { sequence, message }
+    }
+
+    /// Encode message for peer using given protocol version.
+    /// V1 peers don't know about sequence numbers, so it is omitted
+    pub fn encode(&self, version: ProtocolVersion) -> Result<Vec<u8>, QuotesError> {
+        self.encode_with(version, None)
+    }
+
+    /// Encode message for peer using given protocol version into buffer,
+    /// returns count of bytes written
+    pub fn encode_into(
+        &self,
+        version: ProtocolVersion,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        self.encode_into_with(version, None, buf)
+    }
+
+    pub(crate) fn encode_with(
+        &self,
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+    ) -> Result<Vec<u8>, QuotesError> {
+        sink::encode_to_vec(Self::SEQUENCE_SIZE + 1, |sink| {
+            self.write(version, dictionary, sink)
+        })
+    }
+
+    pub(crate) fn encode_into_with(
+        &self,
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write(version, dictionary, sink))
+    }
+
+    fn write(
+        &self,
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        match version {
+            ProtocolVersion::V1 => self.message.write(version, dictionary, sink),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => {
+                sink.put(&self.sequence.to_be_bytes())?;
+                self.message.write(version, dictionary, sink)
+            }
+        }
+    }
+
+    /// Decode message received from peer using given protocol version.
+    /// V1 messages carry no sequence number, it is decoded as 0
+    pub fn decode(value: &[u8], version: ProtocolVersion) -> Result<Self, QuotesError> {
+        Self::decode_with(value, version, None)
+    }
+
+    pub(crate) fn decode_with(
+        value: &[u8],
+        version: ProtocolVersion,
+        dictionary: Option<&TickerDictionary>,
+    ) -> Result<Self, QuotesError> {
+        match version {
+            ProtocolVersion::V1 => Ok(Self::new(
+                0,
+                ServerMessage::decode_with(value, version, dictionary)?,
+            )),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => {
+                let (sequence, message_bytes) = Self::split_sequence(value)?;
+
+                Ok(Self::new(
+                    sequence,
+                    ServerMessage::decode_with(message_bytes, version, dictionary)?,
+                ))
+            }
+        }
+    }
+
+    /// Split V2 sequence number off the message bytes
+    pub(crate) fn split_sequence(value: &[u8]) -> Result<(u64, &[u8]), QuotesError> {
+        if value.len() < Self::SEQUENCE_SIZE {
+            return Err(QuotesError::ParseServerMessageError(
+                "Not enough bytes for sequence number".to_string(),
+            ));
+        }
+
+        let (sequence_bytes, message_bytes) = value.split_at(Self::SEQUENCE_SIZE);
+        let mut sequence = [0u8; Self::SEQUENCE_SIZE];
+        sequence.copy_from_slice(sequence_bytes);
+
+        Ok((u64::from_be_bytes(sequence), message_bytes))
+    }
+}
+
+#[cfg(feature = "json")]
+impl SequencedMessage {
+    /// Encode message as a single JSON line terminated by `\n`
+    pub fn to_json_line(&self) -> Vec<u8> {
+        let mut data = serde_json::to_vec(self).expect("Server messages are always valid JSON");
+        data.push(b'\n');
+        data
+    }
+
+    /// Encode message as a single JSON line into buffer, returns count of bytes written
+    pub fn encode_json_line_into(&self, buf: &mut [u8]) -> Result<usize, QuotesError> {
+        let too_small = |e: std::io::Error| QuotesError::BufferTooSmall(e.to_string());
+
+        let mut cursor = std::io::Cursor::new(buf);
+        serde_json::to_writer(&mut cursor, self).map_err(|e| too_small(std::io::Error::from(e)))?;
+        std::io::Write::write_all(&mut cursor, b"\n").map_err(too_small)?;
+
+        Ok(cursor.position() as usize)
+    }
+
+    /// Decode message from a single JSON line, surrounding whitespace is ignored
+    pub fn from_json_line(value: &[u8]) -> Result<Self, QuotesError> {
+        serde_json::from_slice(value)
+            .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))
+    }
+}
+
+impl Display for SequencedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {}", self.sequence, self.message)
+    }
+}
+
+impl TryFrom<Datagram> for SequencedMessage {
     type Error = QuotesError;
 
     fn try_from(value: Datagram) -> Result<Self, Self::Error> {
-        Self::try_from(value.data)
+        Self::decode(&value.data, ProtocolVersion::LATEST)
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+    use crate::{error_message::ErrorCode, price::Price};
+
+    fn oops() -> ErrorMessage {
+        ErrorMessage::new(ErrorCode::Internal, "oops")
+    }
+
+    #[test]
+    fn test_sequenced_roundtrip() {
+        let message = SequencedMessage::new(u64::MAX - 1, ServerMessage::Err(oops()));
+        let decoded = SequencedMessage::decode(
+            &message.encode(ProtocolVersion::V3).expect("Should encode"),
+            ProtocolVersion::V3,
+        )
+        .expect("Should decode");
+
+        assert_eq!(decoded.sequence, message.sequence);
+        assert_eq!(decoded.message, ServerMessage::Err(oops()));
+    }
+
+    #[test]
+    fn test_v1_has_no_sequence() {
+        let message = SequencedMessage::new(42, ServerMessage::Err(oops()));
+        let bytes = message.encode(ProtocolVersion::V1).expect("Should encode");
+
+        assert_eq!(
+            bytes,
+            message
+                .message
+                .encode(ProtocolVersion::V1)
+                .expect("Should encode")
+        );
+        assert_eq!(
+            SequencedMessage::decode(&bytes, ProtocolVersion::V1)
+                .expect("Should decode")
+                .sequence,
+            0
+        );
+    }
+
+    fn quotes(count: usize) -> Vec<Quote> {
+        (0..count)
+            .map(|i| Quote {
+                ticker: format!("T{i}"),
+                price: Price::new(i as i64, 2),
+                volume: i as u32,
+                timestamp: i as u64,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let message = ServerMessage::QuoteBatch(quotes(3));
+        let decoded = ServerMessage::decode(
+            &message.encode(ProtocolVersion::V2).expect("Should encode"),
+            ProtocolVersion::V2,
+        )
+        .expect("Should decode");
+
+        let ServerMessage::QuoteBatch(decoded) = decoded else {
+            panic!("Expected batch, got {decoded}");
+        };
+        assert_eq!(
+            decoded.iter().map(|q| q.ticker.clone()).collect::<Vec<_>>(),
+            vec!["T0", "T1", "T2"]
+        );
+    }
+
+    #[test]
+    fn test_challenge_roundtrip() {
+        let bytes = ServerMessage::Challenge(0x0123_4567_89AB_CDEF)
+            .encode(ProtocolVersion::V3)
+            .expect("Should encode");
+
+        assert_eq!(bytes.len(), 9);
+        assert_eq!(
+            ServerMessage::decode(&bytes, ProtocolVersion::V3).expect("Should decode"),
+            ServerMessage::Challenge(0x0123_4567_89AB_CDEF)
+        );
+        assert!(ServerMessage::decode(&bytes[..8], ProtocolVersion::V3).is_err());
+        assert!(ServerMessage::decode(&bytes, ProtocolVersion::V2).is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_roundtrip() {
+        let bytes = ServerMessage::Heartbeat
+            .encode(ProtocolVersion::V3)
+            .expect("Should encode");
+
+        assert_eq!(bytes, [ServerMessage::HEARTBEAT_TYPE_CODE]);
+        assert_eq!(
+            ServerMessage::decode(&bytes, ProtocolVersion::V3).expect("Should decode"),
+            ServerMessage::Heartbeat
+        );
+        assert!(
+            ServerMessage::decode(
+                &[ServerMessage::HEARTBEAT_TYPE_CODE, 0],
+                ProtocolVersion::V3
+            )
+            .is_err()
+        );
+        assert!(ServerMessage::decode(&bytes, ProtocolVersion::V2).is_err());
+    }
+
+    #[test]
+    fn test_batch_truncated() {
+        let bytes = ServerMessage::QuoteBatch(quotes(2))
+            .encode(ProtocolVersion::V2)
+            .expect("Should encode");
+        assert!(ServerMessage::decode(&bytes[..bytes.len() - 1], ProtocolVersion::V2).is_err());
+    }
+
+    #[test]
+    fn test_pack_quotes_respects_max_len() {
+        const MAX_LEN: usize = 100;
+        let batches = ServerMessage::pack_quotes(quotes(20), MAX_LEN, None);
+
+        assert!(batches.len() > 1);
+
+        let mut tickers = vec![];
+        for batch in batches {
+            assert!(
+                batch
+                    .encode(ProtocolVersion::V2)
+                    .expect("Should encode")
+                    .len()
+                    <= MAX_LEN
+            );
+            let ServerMessage::QuoteBatch(quotes) = batch else {
+                panic!("Expected batch, got {batch}");
+            };
+            tickers.extend(quotes.into_iter().map(|q| q.ticker));
+        }
+
+        assert_eq!(
+            tickers,
+            quotes(20).into_iter().map(|q| q.ticker).collect::<Vec<_>>()
+        );
+    }
+
+    fn books(count: usize) -> Vec<TopOfBook> {
+        use crate::top_of_book::BookLevel;
+
+        (0..count)
+            .map(|i| {
+                TopOfBook::new(
+                    format!("T{i}"),
+                    BookLevel::new(Price::new(i as i64, 2), i as u32),
+                    BookLevel::new(Price::new(i as i64 + 1, 2), i as u32 + 1),
+                    i as u64,
+                )
+                .expect("Book should not be crossed")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_top_of_book_roundtrip() {
+        let book = books(2).remove(1);
+        let message = ServerMessage::TopOfBook(book.clone());
+        let decoded = ServerMessage::decode(
+            &message.encode(ProtocolVersion::V3).expect("Should encode"),
+            ProtocolVersion::V3,
+        )
+        .expect("Should decode");
+
+        assert!(matches!(decoded, ServerMessage::TopOfBook(decoded) if decoded == book));
+    }
+
+    #[test]
+    fn test_pack_books_respects_max_len() {
+        const MAX_LEN: usize = 150;
+        let batches = ServerMessage::pack_books(books(20), MAX_LEN, None);
+
+        assert!(batches.len() > 1);
+
+        let mut decoded_books = vec![];
+        for batch in batches {
+            let bytes = batch.encode(ProtocolVersion::V3).expect("Should encode");
+            assert!(bytes.len() <= MAX_LEN);
+            let ServerMessage::BookBatch(books) =
+                ServerMessage::decode(&bytes, ProtocolVersion::V3).expect("Should decode")
+            else {
+                panic!("Expected book batch");
+            };
+            decoded_books.extend(books);
+        }
+
+        assert_eq!(decoded_books, books(20));
+    }
+
+    #[test]
+    fn test_trade_batch_roundtrip() {
+        use crate::trade::Side;
+
+        let trades = (0..3)
+            .map(|i| Trade {
+                ticker: format!("T{i}"),
+                price: Price::new(i, 2),
+                size: i as u32,
+                aggressor: if i % 2 == 0 { Side::Buy } else { Side::Sell },
+                trade_id: i as u64,
+                timestamp: i as u64,
+            })
+            .collect::<Vec<_>>();
+
+        let single = ServerMessage::Trade(trades[0].clone());
+        let decoded = ServerMessage::decode(
+            &single.encode(ProtocolVersion::V3).expect("Should encode"),
+            ProtocolVersion::V3,
+        )
+        .expect("Should decode");
+        assert!(matches!(decoded, ServerMessage::Trade(trade) if trade == trades[0]));
+
+        let mut batches = ServerMessage::pack_trades(trades.clone(), 1400, None);
+        assert_eq!(batches.len(), 1);
+        let decoded = ServerMessage::decode(
+            &batches
+                .remove(0)
+                .encode(ProtocolVersion::V3)
+                .expect("Should encode"),
+            ProtocolVersion::V3,
+        )
+        .expect("Should decode");
+        assert!(matches!(decoded, ServerMessage::TradeBatch(decoded) if decoded == trades));
+    }
+
+    #[test]
+    fn test_pack_oversized_quote() {
+        let batches = ServerMessage::pack_quotes(quotes(2), 1, None);
+        assert_eq!(batches.len(), 2);
+    }
+
+    #[test]
+    fn test_decode_rejects_newer_types() {
+        let batch = ServerMessage::QuoteBatch(quotes(2))
+            .encode(ProtocolVersion::V2)
+            .expect("Should encode");
+        assert!(ServerMessage::decode(&batch, ProtocolVersion::V2).is_ok());
+        assert!(ServerMessage::decode(&batch, ProtocolVersion::V1).is_err());
+
+        let book = ServerMessage::TopOfBook(books(1).remove(0))
+            .encode(ProtocolVersion::V3)
+            .expect("Should encode");
+        assert!(ServerMessage::decode(&book, ProtocolVersion::V3).is_ok());
+        assert!(ServerMessage::decode(&book, ProtocolVersion::V2).is_err());
+
+        assert!(ServerMessage::decode(&[42], ProtocolVersion::V3).is_err());
+    }
+
+    #[test]
+    fn test_batch_too_long() {
+        let message = ServerMessage::QuoteBatch(quotes(u16::MAX as usize + 1));
+
+        assert!(matches!(
+            message.encode(ProtocolVersion::V2),
+            Err(QuotesError::EncodeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_pack_isolates_too_long_ticker() {
+        let mut quotes = quotes(3);
+        quotes[1].ticker = "A".repeat(u16::MAX as usize + 1);
+        let mut batches = ServerMessage::pack_quotes(quotes, 1400, None);
+        assert_eq!(batches.len(), 3);
+
+        assert!(matches!(
+            batches.remove(1).encode(ProtocolVersion::V2),
+            Err(QuotesError::EncodeError(_))
+        ));
+        for batch in batches {
+            assert!(batch.encode(ProtocolVersion::V2).is_ok());
+        }
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_matches_binary() {
+        use crate::trade::Side;
+
+        let trade = Trade {
+            ticker: "T1".to_string(),
+            price: Price::new(12340, 2),
+            size: 10,
+            aggressor: Side::Buy,
+            trade_id: 3,
+            timestamp: 4,
+        };
+        let messages = [
+            ServerMessage::Quote(quotes(2).remove(1)),
+            ServerMessage::QuoteBatch(quotes(3)),
+            ServerMessage::TopOfBook(books(2).remove(1)),
+            ServerMessage::BookBatch(books(3)),
+            ServerMessage::Trade(trade.clone()),
+            ServerMessage::TradeBatch(vec![trade.clone(), trade]),
+            ServerMessage::Err(oops()),
+            ServerMessage::Challenge(u64::MAX),
+            ServerMessage::Heartbeat,
+        ];
+
+        for message in messages {
+            let message = SequencedMessage::new(42, message);
+            let from_binary = SequencedMessage::decode(
+                &message.encode(ProtocolVersion::V3).expect("Should encode"),
+                ProtocolVersion::V3,
+            )
+            .expect("Should decode binary");
+            let json = serde_json::to_string(&message).expect("Should serialize");
+            let from_json: SequencedMessage =
+                serde_json::from_str(&json).expect("Should deserialize");
+
+            assert_eq!(from_json, from_binary, "{json}");
+            assert_eq!(from_json, message, "{json}");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_field_names() {
+        let message = SequencedMessage::new(1, ServerMessage::Quote(quotes(2).remove(1)));
+        assert_eq!(
+            serde_json::to_string(&message).expect("Should serialize"),
+            r#"{"sequence":1,"message":{"quote":{"ticker":"T1","price":"0.01","volume":1,"timestamp":1}}}"#
+        );
+
+        let book = serde_json::to_string(&ServerMessage::TopOfBook(books(1).remove(0)))
+            .expect("Should serialize");
+        assert_eq!(
+            book,
+            r#"{"top_of_book":{"ticker":"T0","bid":{"price":"0.00","size":0},"ask":{"price":"0.01","size":1},"timestamp":0}}"#
+        );
+
+        let crossed = book.replace(r#""0.01""#, r#""-1.00""#);
+        assert!(serde_json::from_str::<ServerMessage>(&crossed).is_err());
+    }
+
+    #[test]
+    fn test_encode_into_matches_encode() {
+        let messages = [
+            ServerMessage::QuoteBatch(quotes(3)),
+            ServerMessage::BookBatch(books(2)),
+            ServerMessage::Err(oops()),
+        ];
+        let mut buf = [0u8; 512];
+
+        for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            for message in messages.iter() {
+                let message = SequencedMessage::new(42, message.clone());
+                let len = message.encode_into(version, &mut buf).expect("Should fit");
+                assert_eq!(
+                    &buf[..len],
+                    message.encode(version).expect("Should encode").as_slice()
+                );
+
+                assert!(matches!(
+                    message.encode_into(version, &mut buf[..len - 1]),
+                    Err(QuotesError::BufferTooSmall(_))
+                ));
+            }
+        }
     }
 }
@@ -0,0 +1,662 @@
+//! Per-session encoding state shared by server and client
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    error::QuotesError,
+    protocol::{ProtocolVersion, WireFormat},
+    quote::{Quote, QuoteRef},
+    server_message::{SequencedMessage, ServerMessage},
+    sink::Sink,
+    top_of_book::TopOfBook,
+    trade::Trade,
+};
+
+/// Mapping of ticker symbols to compact ids, assigned by server on subscribe
+/// and sent to client over TCP connection before any quotes
+/// Serialized as list of tickers ordered by id
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "Vec<String>", from = "Vec<String>")
+)]
+pub struct TickerDictionary {
+    symbols: Vec<String>,
+    ids: HashMap<String, u16>,
+}
+
+impl TickerDictionary {
+    /// Assign ids to tickers in order of appearance, duplicates share the same id.
+    /// Tickers beyond u16 range are left without id and sent in full
+    pub fn new(tickers: &[String]) -> Self {
+        let mut dictionary = Self::default();
+        for ticker in tickers {
+            dictionary.insert(ticker.clone());
+        }
+        dictionary
+    }
+
+    /// Assign ids to new tickers after existing ones, ids of known tickers never change,
+    /// so messages encoded before the change are still decoded correctly
+    pub fn extend(&mut self, tickers: &[String]) {
+        for ticker in tickers {
+            self.insert(ticker.clone());
+        }
+    }
+
+    /// Every ticker of this dictionary has the same id in the other one
+    pub fn is_prefix_of(&self, other: &TickerDictionary) -> bool {
+        other.symbols.starts_with(&self.symbols)
+    }
+
+    fn insert(&mut self, ticker: String) {
+        if self.ids.contains_key(&ticker) {
+            return;
+        }
+        let Ok(id) = u16::try_from(self.symbols.len()) else {
+            return;
+        };
+        self.ids.insert(ticker.clone(), id);
+        self.symbols.push(ticker);
+    }
+
+    /// Id assigned to ticker
+    pub fn id(&self, ticker: &str) -> Option<u16> {
+        self.ids.get(ticker).copied()
+    }
+
+    /// Ticker with given id
+    pub fn symbol(&self, id: u16) -> Option<&str> {
+        self.symbols.get(id as usize).map(String::as_str)
+    }
+
+    /// All tickers ordered by id
+    pub fn symbols(&self) -> &[String] {
+        &self.symbols
+    }
+
+    /// Dictionary has no tickers
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+/// Formats dictionary as `id:ticker` pairs separated by comma
+impl Display for TickerDictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pairs = self
+            .symbols
+            .iter()
+            .enumerate()
+            .map(|(id, ticker)| format!("{id}:{ticker}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", pairs.join(","))
+    }
+}
+
+impl From<TickerDictionary> for Vec<String> {
+    fn from(value: TickerDictionary) -> Self {
+        value.symbols
+    }
+}
+
+impl From<Vec<String>> for TickerDictionary {
+    fn from(value: Vec<String>) -> Self {
+        Self::new(&value)
+    }
+}
+
+impl TryFrom<&str> for TickerDictionary {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut dictionary = Self::default();
+        if value.is_empty() {
+            return Ok(dictionary);
+        }
+
+        for pair in value.split(",") {
+            let (id, ticker) = pair.split_once(":").ok_or_else(|| {
+                QuotesError::ParseServerMessageError(format!("Malformed dictionary entry {pair}"))
+            })?;
+            let id = id
+                .parse::<u16>()
+                .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))?;
+
+            if id as usize != dictionary.symbols.len() || dictionary.ids.contains_key(ticker) {
+                return Err(QuotesError::ParseServerMessageError(format!(
+                    "Unexpected dictionary entry {pair}"
+                )));
+            }
+            dictionary.insert(ticker.to_string());
+        }
+
+        Ok(dictionary)
+    }
+}
+
+const TICKER_LEN_SIZE: usize = 2;
+const TICKER_ID_SIZE: usize = 2;
+
+/// Write ticker header of binary formats: `[ticker len: u16][ticker]`,
+/// fails for tickers longer than `u16::MAX` bytes
+pub(crate) fn write_ticker(sink: &mut impl Sink, ticker: &str) -> Result<(), QuotesError> {
+    let len = u16::try_from(ticker.len()).map_err(|_| {
+        QuotesError::EncodeError(format!("Ticker of {} bytes is too long", ticker.len()))
+    })?;
+    sink.put(&len.to_be_bytes())?;
+    sink.put(ticker.as_bytes())
+}
+
+/// Split ticker header off the binary format, returning ticker and the rest of bytes
+pub(crate) fn split_ticker(value: &[u8]) -> Result<(&str, &[u8]), QuotesError> {
+    if value.len() < TICKER_LEN_SIZE {
+        return Err(QuotesError::ParseQuoteError(
+            "Not enough bytes for ticker length".to_string(),
+        ));
+    }
+
+    let ticker_len = u16::from_be_bytes([value[0], value[1]]) as usize;
+    let ticker_end = TICKER_LEN_SIZE + ticker_len;
+    if ticker_len == 0 || value.len() < ticker_end {
+        return Err(QuotesError::ParseQuoteError(
+            "Incorrect data format".to_string(),
+        ));
+    }
+
+    let ticker = std::str::from_utf8(&value[TICKER_LEN_SIZE..ticker_end])
+        .map_err(|e| QuotesError::ParseQuoteError(e.to_string()))?;
+
+    Ok((ticker, &value[ticker_end..]))
+}
+
+/// Split compact ticker header `[ticker id: u16]` off the binary format,
+/// resolving ticker id with dictionary
+pub(crate) fn split_ticker_id<'a, 'd>(
+    value: &'a [u8],
+    dictionary: &'d TickerDictionary,
+) -> Result<(&'d str, &'a [u8]), QuotesError> {
+    if value.len() < TICKER_ID_SIZE {
+        return Err(QuotesError::ParseQuoteError(
+            "Not enough bytes for ticker id".to_string(),
+        ));
+    }
+
+    let ticker_id = u16::from_be_bytes([value[0], value[1]]);
+    let ticker = dictionary
+        .symbol(ticker_id)
+        .ok_or_else(|| QuotesError::ParseQuoteError(format!("Unknown ticker id {ticker_id}")))?;
+
+    Ok((ticker, &value[TICKER_ID_SIZE..]))
+}
+
+/// Server side of a session, assigns sequence numbers and encodes messages for a single client
+#[derive(Debug)]
+pub struct SessionEncoder {
+    version: ProtocolVersion,
+    dictionary: TickerDictionary,
+    format: WireFormat,
+    sequence: u64,
+}
+
+impl SessionEncoder {
+    /// Create new binary encoder, first message gets sequence number 0
+    pub fn new(version: ProtocolVersion, dictionary: TickerDictionary) -> Self {
+        Self {
+            version,
+            dictionary,
+            format: WireFormat::default(),
+            sequence: 0,
+        }
+    }
+
+    /// Encode messages in given wire format
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Negotiated protocol version
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Negotiated wire format
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Sequence number of next encoded message
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Assign ids to tickers added to session
+    pub fn extend_dictionary(&mut self, tickers: &[String]) {
+        self.dictionary.extend(tickers);
+    }
+
+    /// Encode message with next sequence number,
+    /// sequence number is consumed only when message is encoded
+    pub fn encode(&mut self, message: ServerMessage) -> Result<Vec<u8>, QuotesError> {
+        let message = SequencedMessage::new(self.sequence, message);
+
+        let data = match self.format {
+            WireFormat::Binary => message.encode_with(self.version, Some(&self.dictionary))?,
+            #[cfg(feature = "json")]
+            WireFormat::Json => message.to_json_line(),
+        };
+        self.sequence += 1;
+
+        Ok(data)
+    }
+
+    /// Encode message with next sequence number into buffer, returns count of bytes written.
+    /// Sequence number is consumed only if message fits into buffer
+    pub fn encode_into(
+        &mut self,
+        message: ServerMessage,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        let message = SequencedMessage::new(self.sequence, message);
+
+        let len = match self.format {
+            WireFormat::Binary => {
+                message.encode_into_with(self.version, Some(&self.dictionary), buf)?
+            }
+            #[cfg(feature = "json")]
+            WireFormat::Json => message.encode_json_line_into(buf)?,
+        };
+        self.sequence += 1;
+
+        Ok(len)
+    }
+
+    /// JSON lines are not batched, so every message stays readable on its own
+    fn is_batched(&self) -> bool {
+        self.format == WireFormat::Binary && self.version >= ProtocolVersion::V2
+    }
+
+    /// Split quotes into messages supported by peer,
+    /// so that every message encoded by this encoder fits into `max_len` bytes
+    pub fn pack_quotes(&self, quotes: Vec<Quote>, max_len: usize) -> Vec<ServerMessage> {
+        if !self.is_batched() {
+            return quotes.into_iter().map(ServerMessage::Quote).collect();
+        }
+
+        ServerMessage::pack_quotes(
+            quotes,
+            max_len.saturating_sub(SequencedMessage::SEQUENCE_SIZE),
+            Some(&self.dictionary),
+        )
+    }
+
+    /// Split trades into messages that fit into `max_len` bytes.
+    /// Binary peers older than V3 can't decode trades, so nothing is sent to them
+    pub fn pack_trades(&self, trades: Vec<Trade>, max_len: usize) -> Vec<ServerMessage> {
+        if self.format != WireFormat::Binary {
+            return trades.into_iter().map(ServerMessage::Trade).collect();
+        }
+
+        match self.version {
+            ProtocolVersion::V1 | ProtocolVersion::V2 => vec![],
+            ProtocolVersion::V3 => ServerMessage::pack_trades(
+                trades,
+                max_len.saturating_sub(SequencedMessage::SEQUENCE_SIZE),
+                Some(&self.dictionary),
+            ),
+        }
+    }
+
+    /// Split books into messages that fit into `max_len` bytes.
+    /// Binary peers older than V3 can't decode books, so nothing is sent to them
+    pub fn pack_books(&self, books: Vec<TopOfBook>, max_len: usize) -> Vec<ServerMessage> {
+        if self.format != WireFormat::Binary {
+            return books.into_iter().map(ServerMessage::TopOfBook).collect();
+        }
+
+        match self.version {
+            ProtocolVersion::V1 | ProtocolVersion::V2 => vec![],
+            ProtocolVersion::V3 => ServerMessage::pack_books(
+                books,
+                max_len.saturating_sub(SequencedMessage::SEQUENCE_SIZE),
+                Some(&self.dictionary),
+            ),
+        }
+    }
+}
+
+/// Client side of a session, decodes messages using dictionary received on subscribe
+#[derive(Debug)]
+pub struct SessionDecoder {
+    version: ProtocolVersion,
+    dictionary: TickerDictionary,
+    format: WireFormat,
+}
+
+impl SessionDecoder {
+    /// Create new binary decoder
+    pub fn new(version: ProtocolVersion, dictionary: TickerDictionary) -> Self {
+        Self {
+            version,
+            dictionary,
+            format: WireFormat::default(),
+        }
+    }
+
+    /// Decode messages in given wire format
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Negotiated protocol version
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Negotiated wire format
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Replace dictionary with the one extended by server, ids of known tickers must stay the same
+    pub fn update_dictionary(&mut self, dictionary: TickerDictionary) -> Result<(), QuotesError> {
+        if !self.dictionary.is_prefix_of(&dictionary) {
+            return Err(QuotesError::ParseServerMessageError(format!(
+                "Dictionary {dictionary} changes ids of {}",
+                self.dictionary
+            )));
+        }
+
+        self.dictionary = dictionary;
+        Ok(())
+    }
+
+    /// Decode message, restoring tickers of compact quotes
+    pub fn decode(&self, value: &[u8]) -> Result<SequencedMessage, QuotesError> {
+        match self.format {
+            WireFormat::Binary => {
+                SequencedMessage::decode_with(value, self.version, Some(&self.dictionary))
+            }
+            #[cfg(feature = "json")]
+            WireFormat::Json => SequencedMessage::from_json_line(value),
+        }
+    }
+
+    /// Decode quotes straight from received bytes without allocating, passing each to `on_quote`.
+    /// Returns sequence number of Quote and QuoteBatch messages, or None for any other message
+    /// and for JSON lines, those have to be decoded with `decode`.
+    /// On error quotes decoded before the broken one are already passed to `on_quote`
+    pub fn decode_quotes<'a>(
+        &'a self,
+        value: &'a [u8],
+        on_quote: impl FnMut(QuoteRef<'a>),
+    ) -> Result<Option<u64>, QuotesError> {
+        if self.format != WireFormat::Binary {
+            return Ok(None);
+        }
+
+        let (sequence, message) = match self.version {
+            // legacy messages carry no sequence number, it is decoded as 0
+            ProtocolVersion::V1 => (0, value),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => SequencedMessage::split_sequence(value)?,
+        };
+
+        let is_quotes =
+            ServerMessage::visit_quotes(message, self.version, &self.dictionary, on_quote)?;
+        Ok(is_quotes.then_some(sequence))
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+    use crate::{
+        error_message::{ErrorCode, ErrorMessage},
+        price::Price,
+    };
+
+    fn tickers() -> Vec<String> {
+        ["AAPL", "MSFT", "AAPL", "TSLA"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_dictionary_ids() {
+        let dictionary = TickerDictionary::new(&tickers());
+
+        assert_eq!(dictionary.symbols(), ["AAPL", "MSFT", "TSLA"]);
+        assert_eq!(dictionary.id("TSLA"), Some(2));
+        assert_eq!(dictionary.symbol(1), Some("MSFT"));
+        assert_eq!(dictionary.id("GOOG"), None);
+    }
+
+    #[test]
+    fn test_dictionary_roundtrip() {
+        let dictionary = TickerDictionary::new(&tickers());
+        let parsed = TickerDictionary::try_from(dictionary.to_string().as_str())
+            .expect("Should parse dictionary");
+
+        assert_eq!(parsed, dictionary);
+        assert_eq!(
+            TickerDictionary::try_from("").expect("Should parse"),
+            TickerDictionary::default()
+        );
+        assert!(TickerDictionary::try_from("1:AAPL").is_err());
+    }
+
+    #[test]
+    fn test_dictionary_update() {
+        let dictionary = TickerDictionary::new(&tickers());
+        let mut encoder = SessionEncoder::new(ProtocolVersion::V2, dictionary.clone());
+        let mut decoder = SessionDecoder::new(ProtocolVersion::V2, dictionary.clone());
+
+        let added = ["NVDA".to_string(), "MSFT".to_string()];
+        let mut extended = dictionary.clone();
+        extended.extend(&added);
+        assert_eq!(extended.symbols(), ["AAPL", "MSFT", "TSLA", "NVDA"]);
+        assert!(dictionary.is_prefix_of(&extended));
+
+        encoder.extend_dictionary(&added);
+        decoder
+            .update_dictionary(extended)
+            .expect("Should accept extended dictionary");
+
+        let quote = Quote {
+            ticker: "NVDA".to_string(),
+            price: Price::new(100, 0),
+            volume: 10,
+            timestamp: 1,
+        };
+        let bytes = encoder
+            .encode(ServerMessage::Quote(quote.clone()))
+            .expect("Should encode");
+        let message = decoder.decode(&bytes).expect("Should decode");
+        assert_eq!(message.message, ServerMessage::Quote(quote));
+
+        assert!(
+            decoder
+                .update_dictionary(TickerDictionary::new(&["NVDA".to_string()]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_session_roundtrip() {
+        let dictionary = TickerDictionary::new(&tickers());
+        let mut encoder = SessionEncoder::new(ProtocolVersion::V2, dictionary.clone());
+        let decoder = SessionDecoder::new(ProtocolVersion::V2, dictionary);
+
+        let quote = Quote {
+            ticker: "TSLA".to_string(),
+            price: Price::new(15, 1),
+            volume: 10,
+            timestamp: 20,
+        };
+        let full_len = ServerMessage::Quote(quote.clone())
+            .encode(ProtocolVersion::V2)
+            .expect("Should encode")
+            .len();
+
+        encoder
+            .encode(ServerMessage::Err(ErrorMessage::new(
+                ErrorCode::Unspecified,
+                "skip",
+            )))
+            .expect("Should encode");
+        let bytes = encoder
+            .encode(ServerMessage::Quote(quote.clone()))
+            .expect("Should encode");
+        assert!(bytes.len() < full_len + SequencedMessage::SEQUENCE_SIZE);
+
+        let decoded = decoder.decode(&bytes).expect("Should decode");
+        assert_eq!(decoded.sequence, 1);
+        let ServerMessage::Quote(decoded) = decoded.message else {
+            panic!("Expected quote");
+        };
+        assert_eq!(decoded.ticker, quote.ticker);
+    }
+
+    #[test]
+    fn test_pack_quotes_fits_max_len() {
+        const MAX_LEN: usize = 120;
+        let dictionary = TickerDictionary::new(&tickers());
+        let mut encoder = SessionEncoder::new(ProtocolVersion::V2, dictionary);
+        let quotes = (0..30)
+            .map(|i| Quote {
+                ticker: tickers()[i % 4].clone(),
+                price: Price::new(i as i64, 2),
+                volume: i as u32,
+                timestamp: i as u64,
+            })
+            .collect::<Vec<_>>();
+
+        let batches = encoder.pack_quotes(quotes, MAX_LEN);
+        assert!(batches.len() > 1);
+        for batch in batches {
+            assert!(encoder.encode(batch).expect("Should encode").len() <= MAX_LEN);
+        }
+    }
+
+    #[test]
+    fn test_pack_books_only_for_v3() {
+        use crate::top_of_book::BookLevel;
+
+        let dictionary = TickerDictionary::new(&tickers());
+        let level = BookLevel::new(Price::new(100, 0), 1);
+        let book = TopOfBook::new("AAPL".to_string(), level, level, 0).expect("Not crossed");
+
+        for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            let legacy = SessionEncoder::new(version, dictionary.clone());
+            assert!(legacy.pack_books(vec![book.clone()], 1400).is_empty());
+        }
+
+        let mut encoder = SessionEncoder::new(ProtocolVersion::V3, dictionary.clone());
+        let decoder = SessionDecoder::new(ProtocolVersion::V3, dictionary);
+        let mut batches = encoder.pack_books(vec![book.clone()], 1400);
+        assert_eq!(batches.len(), 1);
+
+        let decoded = decoder
+            .decode(&encoder.encode(batches.remove(0)).expect("Should encode"))
+            .expect("Should decode");
+        assert!(matches!(decoded.message, ServerMessage::BookBatch(books) if books == [book]));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_session_roundtrip() {
+        use crate::top_of_book::BookLevel;
+
+        let dictionary = TickerDictionary::new(&tickers());
+        let mut encoder = SessionEncoder::new(ProtocolVersion::V2, dictionary.clone())
+            .with_format(WireFormat::Json);
+        let decoder =
+            SessionDecoder::new(ProtocolVersion::V2, dictionary).with_format(WireFormat::Json);
+
+        let level = BookLevel::new(Price::new(100, 0), 1);
+        let books = vec![
+            TopOfBook::new("AAPL".to_string(), level, level, 0).expect("Not crossed"),
+            TopOfBook::new("GOOG".to_string(), level, level, 0).expect("Not crossed"),
+        ];
+        let messages = encoder.pack_books(books.clone(), 1);
+        assert_eq!(messages.len(), books.len());
+
+        for (message, book) in messages.into_iter().zip(books) {
+            let bytes = encoder.encode(message).expect("Should encode");
+            assert_eq!(bytes.last(), Some(&b'\n'));
+            assert!(std::str::from_utf8(&bytes).is_ok());
+
+            let decoded = decoder.decode(&bytes).expect("Should decode");
+            assert!(
+                matches!(decoded.message, ServerMessage::TopOfBook(decoded) if decoded == book)
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let dictionary = TickerDictionary::new(&tickers());
+        let mut encoder = SessionEncoder::new(ProtocolVersion::V2, dictionary.clone());
+        let mut reference = SessionEncoder::new(ProtocolVersion::V2, dictionary);
+        let message = ServerMessage::Err(ErrorMessage::new(ErrorCode::Internal, "error"));
+        let mut buf = [0u8; 64];
+
+        assert!(encoder.encode_into(message.clone(), &mut buf[..4]).is_err());
+        assert_eq!(
+            encoder.sequence(),
+            0,
+            "Failed message keeps sequence number"
+        );
+
+        let len = encoder
+            .encode_into(message.clone(), &mut buf)
+            .expect("Should fit");
+        assert_eq!(
+            &buf[..len],
+            reference.encode(message).expect("Should encode").as_slice()
+        );
+        assert_eq!(encoder.sequence(), 1);
+    }
+
+    #[test]
+    fn test_decode_quotes() {
+        use crate::top_of_book::BookLevel;
+
+        let dictionary = TickerDictionary::new(&tickers());
+        let mut encoder = SessionEncoder::new(ProtocolVersion::V3, dictionary.clone());
+        let decoder = SessionDecoder::new(ProtocolVersion::V3, dictionary);
+
+        let quotes = ["AAPL", "NEW"]
+            .into_iter()
+            .map(|ticker| Quote {
+                ticker: ticker.to_string(),
+                price: Price::new(15, 1),
+                volume: 10,
+                timestamp: 20,
+            })
+            .collect::<Vec<_>>();
+        let messages = encoder.pack_quotes(quotes.clone(), 1000);
+        assert_eq!(messages.len(), 1);
+
+        let bytes = encoder.encode(messages[0].clone()).expect("Should encode");
+        let mut decoded = vec![];
+        let sequence = decoder
+            .decode_quotes(&bytes, |quote| decoded.push(Quote::from(quote)))
+            .expect("Should decode");
+        assert_eq!(sequence, Some(0));
+        assert_eq!(decoded, quotes);
+
+        let level = BookLevel::new(Price::new(100, 0), 1);
+        let book = TopOfBook::new("AAPL".to_string(), level, level, 0).expect("Not crossed");
+        let bytes = encoder
+            .encode(ServerMessage::TopOfBook(book))
+            .expect("Should encode");
+        let sequence = decoder
+            .decode_quotes(&bytes, |_| panic!("Book is not a quote"))
+            .expect("Should decode");
+        assert_eq!(sequence, None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Destinations of binary encoders, so the same encoder can fill a growing vector,
+//! a caller-provided buffer or just measure encoded length
+
+use crate::error::QuotesError;
+
+/// Destination of encoded bytes
+pub(crate) trait Sink {
+    /// Append bytes
+    fn put(&mut self, bytes: &[u8]) -> Result<(), QuotesError>;
+
+    /// Count of bytes written so far
+    fn position(&self) -> usize;
+
+    /// Overwrite bytes previously written at given position, used for length prefixes
+    fn patch(&mut self, position: usize, bytes: &[u8]);
+}
+
+impl Sink for Vec<u8> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), QuotesError> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.len()
+    }
+
+    fn patch(&mut self, position: usize, bytes: &[u8]) {
+        self[position..position + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+/// Fixed caller-provided buffer, fails instead of growing
+pub(crate) struct SliceSink<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> SliceSink<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+}
+
+impl Sink for SliceSink<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), QuotesError> {
+        let end = self.position + bytes.len();
+        if end > self.buf.len() {
+            return Err(QuotesError::BufferTooSmall(format!(
+                "{end} bytes needed so far, {} available",
+                self.buf.len()
+            )));
+        }
+
+        self.buf[self.position..end].copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn patch(&mut self, position: usize, bytes: &[u8]) {
+        self.buf[position..position + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+/// Counts bytes without storing them
+#[derive(Default)]
+pub(crate) struct LenSink {
+    len: usize,
+}
+
+impl Sink for LenSink {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), QuotesError> {
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.len
+    }
+
+    fn patch(&mut self, _position: usize, _bytes: &[u8]) {}
+}
+
+/// Encode into new vector. Vector never runs out of space,
+/// so it only fails for values that don't fit the wire format, e.g. too long tickers
+pub(crate) fn encode_to_vec(
+    capacity: usize,
+    write: impl FnOnce(&mut Vec<u8>) -> Result<(), QuotesError>,
+) -> Result<Vec<u8>, QuotesError> {
+    let mut data = Vec::with_capacity(capacity);
+    write(&mut data)?;
+    Ok(data)
+}
+
+/// Encode into caller-provided buffer, returns count of bytes written
+pub(crate) fn encode_into(
+    buf: &mut [u8],
+    write: impl FnOnce(&mut SliceSink) -> Result<(), QuotesError>,
+) -> Result<usize, QuotesError> {
+    let mut sink = SliceSink::new(buf);
+    write(&mut sink)?;
+    Ok(sink.position())
+}
+
+/// Count of bytes encoder would write, fails if value doesn't fit the wire format
+pub(crate) fn encoded_len(
+    write: impl FnOnce(&mut LenSink) -> Result<(), QuotesError>,
+) -> Result<usize, QuotesError> {
+    let mut sink = LenSink::default();
+    write(&mut sink)?;
+    Ok(sink.position())
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_slice_sink_overflow() {
+        let mut buf = [0u8; 4];
+        let mut sink = SliceSink::new(&mut buf);
+
+        sink.put(&[1, 2, 3]).expect("Should fit");
+        assert!(matches!(
+            sink.put(&[4, 5]),
+            Err(QuotesError::BufferTooSmall(_))
+        ));
+        sink.put(&[4]).expect("Should fit");
+        sink.patch(0, &[9]);
+
+        assert_eq!(sink.position(), 4);
+        assert_eq!(buf, [9, 2, 3, 4]);
+    }
+}
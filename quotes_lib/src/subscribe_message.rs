@@ -1,38 +1,113 @@
 //! Client messages module
 use std::{
     fmt::Display,
-    net::{AddrParseError, SocketAddrV4},
+    net::{AddrParseError, SocketAddr},
 };
 
-use crate::error::QuotesError;
+use crate::{
+    credentials::Credentials,
+    error::QuotesError,
+    pattern::TickerPattern,
+    protocol::{ProtocolVersion, WireFormat},
+};
 
 /// CLient message with request for streaming tickers data on address
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubscribeMessage {
-    /// address for UDP connection
-    pub address: SocketAddrV4,
+    /// address for UDP connection, IPv6 addresses are written in brackets, e.g. `[::1]:5000`.
+    /// None asks server to stream to address its authenticated pings come from, written as `-`
+    pub address: Option<SocketAddr>,
     /// list of tickers to stream
     pub tickers: Vec<String>,
+    /// protocol versions supported by client
+    pub versions: Vec<u8>,
+    /// requested encoding of UDP stream
+    pub format: WireFormat,
+    /// identify client to servers that require it
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub credentials: Option<Credentials>,
+    /// keep TCP connection open to change subscription, closing it ends the stream
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub control: bool,
 }
 
 impl SubscribeMessage {
-    /// Create new SubscribeMessage
-    pub fn new(address: SocketAddrV4, tickers: Vec<String>) -> Self {
-        Self { address, tickers }
+    /// Create new SubscribeMessage advertising all supported protocol versions
+    pub fn new(address: Option<SocketAddr>, tickers: Vec<String>) -> Self {
+        Self {
+            address,
+            tickers,
+            versions: ProtocolVersion::SUPPORTED.map(u8::from).to_vec(),
+            format: WireFormat::default(),
+            credentials: None,
+            control: false,
+        }
+    }
+
+    /// Request given encoding of UDP stream
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Present given credentials
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Keep TCP connection as control session
+    pub fn with_control(mut self) -> Self {
+        self.control = true;
+        self
     }
 
     const HEADER: &str = "SUBSCRIBE";
+    const LEARN_ADDRESS: &str = "-";
+    const VERSIONS_OPTION: &str = "versions";
+    const FORMAT_OPTION: &str = "format";
+    const CONTROL_OPTION: &str = "control";
 }
 
 impl Display for SubscribeMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let address = self
+            .address
+            .map_or(Self::LEARN_ADDRESS.to_string(), |address| {
+                address.to_string()
+            });
         write!(
             f,
-            "{} {} {}",
+            "{} {} {} {}={}",
             Self::HEADER,
-            self.address,
-            self.tickers.join(",")
-        )
+            address,
+            self.tickers.join(","),
+            Self::VERSIONS_OPTION,
+            self.versions
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+
+        // default format is omitted, so request looks the same for servers without formats
+        if self.format != WireFormat::default() {
+            write!(f, " {}={}", Self::FORMAT_OPTION, self.format)?;
+        }
+
+        if self.control {
+            write!(f, " {}={}", Self::CONTROL_OPTION, self.control)?;
+        }
+
+        if let Some(credentials) = &self.credentials {
+            write!(f, " {credentials}")?;
+        }
+
+        Ok(())
     }
 }
 
@@ -42,17 +117,61 @@ impl TryFrom<&str> for SubscribeMessage {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         let parts = value.trim().split(" ").collect::<Vec<_>>();
 
-        if parts.len() == 3 && parts[0] == Self::HEADER {
-            let address = parts[1]
-                .parse()
-                .map_err(|e: AddrParseError| QuotesError::ParseClientMessageError(e.to_string()))?;
+        if parts.len() >= 3 && parts[0] == Self::HEADER {
+            let address = match parts[1] {
+                Self::LEARN_ADDRESS => None,
+                address => Some(address.parse().map_err(|e: AddrParseError| {
+                    QuotesError::ParseClientMessageError(e.to_string())
+                })?),
+            };
 
             let tickers = parts[2]
                 .split(",")
                 .map(|t| t.to_string())
                 .collect::<Vec<_>>();
+            TickerPattern::check_selectors(&tickers)?;
+
+            // clients without versions option only know legacy protocol
+            let mut versions = vec![u8::from(ProtocolVersion::V1)];
+            let mut format = WireFormat::default();
+            let mut control = false;
+            let (mut token, mut user, mut password) = (None, None, None);
+
+            for option in &parts[3..] {
+                let (key, value) = option.split_once("=").ok_or_else(|| {
+                    QuotesError::ParseClientMessageError(format!("Malformed option {option}"))
+                })?;
 
-            Ok(Self { address, tickers })
+                // unknown options are skipped so newer clients can talk to this server
+                if key == Self::VERSIONS_OPTION {
+                    versions = value
+                        .split(",")
+                        .map(|v| v.parse::<u8>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| QuotesError::ParseClientMessageError(e.to_string()))?;
+                } else if key == Self::FORMAT_OPTION {
+                    format = WireFormat::try_from(value)?;
+                } else if key == Self::CONTROL_OPTION {
+                    control = value
+                        .parse::<bool>()
+                        .map_err(|e| QuotesError::ParseClientMessageError(e.to_string()))?;
+                } else if key == Credentials::TOKEN_OPTION {
+                    token = Some(value);
+                } else if key == Credentials::USER_OPTION {
+                    user = Some(value);
+                } else if key == Credentials::PASSWORD_OPTION {
+                    password = Some(value);
+                }
+            }
+
+            Ok(Self {
+                address,
+                tickers,
+                versions,
+                format,
+                credentials: Credentials::from_options(token, user, password)?,
+                control,
+            })
         } else {
             Err(QuotesError::ParseClientMessageError(
                 "Unexpected client message format".to_string(),
@@ -62,7 +181,8 @@ impl TryFrom<&str> for SubscribeMessage {
 }
 
 /// Client message to keep UDP connection alive
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PingMessage;
 
 impl PingMessage {
@@ -94,3 +214,244 @@ impl From<PingMessage> for Vec<u8> {
         PingMessage::HEADER.as_bytes().to_vec()
     }
 }
+
+/// Client message echoing cookie of [`ServerMessage::Challenge`](crate::server_message::ServerMessage::Challenge),
+/// sent over UDP or over control session. Written as `VERIFY <cookie in hex>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VerifyMessage(pub u64);
+
+impl VerifyMessage {
+    pub(crate) const HEADER: &str = "VERIFY";
+}
+
+impl Display for VerifyMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:016x}", Self::HEADER, self.0)
+    }
+}
+
+impl TryFrom<&str> for VerifyMessage {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value
+            .trim()
+            .strip_prefix(Self::HEADER)
+            .and_then(|cookie| cookie.strip_prefix(" "))
+            .and_then(|cookie| u64::from_str_radix(cookie, 16).ok())
+            .map(Self)
+            .ok_or_else(|| {
+                QuotesError::ParseClientMessageError(format!(
+                    "Malformed verify message {:?}",
+                    value.trim()
+                ))
+            })
+    }
+}
+
+impl TryFrom<&[u8]> for VerifyMessage {
+    type Error = QuotesError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let value = std::str::from_utf8(value)
+            .map_err(|e| QuotesError::ParseClientMessageError(e.to_string()))?;
+        Self::try_from(value)
+    }
+}
+
+impl From<VerifyMessage> for Vec<u8> {
+    fn from(value: VerifyMessage) -> Self {
+        value.to_string().into_bytes()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_subscribe_roundtrip() {
+        let message = SubscribeMessage::new(
+            Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 5000))),
+            vec!["AAPL".to_string(), "MSFT".to_string()],
+        );
+
+        let parsed =
+            SubscribeMessage::try_from(message.to_string().as_str()).expect("Should parse");
+
+        assert_eq!(parsed.address, message.address);
+        assert_eq!(parsed.tickers, message.tickers);
+        assert_eq!(parsed.versions, vec![1, 2, 3]);
+        assert!(!parsed.control);
+    }
+
+    #[test]
+    fn test_subscribe_control() {
+        let message = SubscribeMessage::new(
+            Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 5000))),
+            vec!["AAPL".to_string()],
+        )
+        .with_control();
+
+        assert!(message.to_string().contains(" control=true"));
+        assert_eq!(
+            SubscribeMessage::try_from(message.to_string().as_str()).expect("Should parse"),
+            message
+        );
+    }
+
+    #[test]
+    fn test_subscribe_ipv6() {
+        let parsed = SubscribeMessage::try_from("SUBSCRIBE [::1]:5000 AAPL versions=2")
+            .expect("Should parse");
+        assert_eq!(
+            parsed.address,
+            Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 5000)))
+        );
+        assert!(parsed.to_string().starts_with("SUBSCRIBE [::1]:5000 AAPL"));
+
+        let mapped = SubscribeMessage::try_from("SUBSCRIBE [::ffff:127.0.0.1]:5000 AAPL")
+            .expect("Should parse");
+        assert!(mapped.address.is_some_and(|address| address.is_ipv6()));
+
+        assert!(SubscribeMessage::try_from("SUBSCRIBE ::1:5000 AAPL").is_err());
+    }
+
+    #[test]
+    fn test_subscribe_without_address() {
+        let message = SubscribeMessage::new(None, vec!["AAPL".to_string()]);
+
+        assert!(message.to_string().starts_with("SUBSCRIBE - AAPL"));
+        assert_eq!(
+            SubscribeMessage::try_from(message.to_string().as_str()).expect("Should parse"),
+            message
+        );
+    }
+
+    #[test]
+    fn test_verify_roundtrip() {
+        let message = VerifyMessage(0xDEAD_BEEF);
+        let bytes: Vec<u8> = message.into();
+
+        assert_eq!(bytes, b"VERIFY 00000000deadbeef");
+        assert_eq!(
+            VerifyMessage::try_from(bytes.as_slice()).expect("Should parse"),
+            message
+        );
+        for text in ["VERIFY", "VERIFY xyz", "VERIFYdeadbeef", "PING"] {
+            assert!(VerifyMessage::try_from(text).is_err(), "{text}");
+        }
+        assert!(PingMessage::try_from(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_legacy_subscribe_defaults_to_v1() {
+        let parsed =
+            SubscribeMessage::try_from("SUBSCRIBE 127.0.0.1:5000 AAPL\n").expect("Should parse");
+
+        assert_eq!(parsed.versions, vec![1]);
+    }
+
+    #[test]
+    fn test_subscribe_skips_unknown_options() {
+        let parsed = SubscribeMessage::try_from("SUBSCRIBE 127.0.0.1:5000 AAPL versions=9 foo=bar")
+            .expect("Should parse");
+
+        assert_eq!(parsed.versions, vec![9]);
+    }
+
+    #[test]
+    fn test_subscribe_selector_limits() {
+        let many = vec!["A"; TickerPattern::MAX_COUNT + 1].join(",");
+        let long = "A".repeat(TickerPattern::MAX_LEN + 1);
+
+        for tickers in [many, long] {
+            let text = format!("SUBSCRIBE 127.0.0.1:5000 {tickers} versions=2");
+            assert!(SubscribeMessage::try_from(text.as_str()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_subscribe_format() {
+        let parsed = SubscribeMessage::try_from("SUBSCRIBE 127.0.0.1:5000 AAPL versions=2")
+            .expect("Should parse");
+        assert_eq!(parsed.format, WireFormat::Binary);
+        assert!(!parsed.to_string().contains(SubscribeMessage::FORMAT_OPTION));
+
+        assert!(
+            SubscribeMessage::try_from("SUBSCRIBE 127.0.0.1:5000 AAPL versions=2 format=xml")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_subscribe_credentials() {
+        for credentials in [
+            Credentials::token("bot.abc=").expect("Should be valid token"),
+            Credentials::password("alice", "hunter2").expect("Should be valid password"),
+        ] {
+            let message = SubscribeMessage::new(
+                Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 5000))),
+                vec!["AAPL".to_string()],
+            )
+            .with_credentials(credentials);
+
+            assert_eq!(
+                SubscribeMessage::try_from(message.to_string().as_str()).expect("Should parse"),
+                message
+            );
+        }
+
+        assert!(
+            SubscribeMessage::try_from("SUBSCRIBE 127.0.0.1:5000 AAPL versions=2 user=alice")
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_subscribe_json_format_roundtrip() {
+        let message = SubscribeMessage::new(
+            Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 5000))),
+            vec!["AAPL".to_string()],
+        )
+        .with_format(WireFormat::Json);
+
+        assert!(message.to_string().ends_with(" format=json"));
+        assert_eq!(
+            SubscribeMessage::try_from(message.to_string().as_str()).expect("Should parse"),
+            message
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_matches_text() {
+        let message = SubscribeMessage::new(
+            Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 5000))),
+            vec!["AAPL".to_string(), "MSFT".to_string()],
+        );
+        let from_text =
+            SubscribeMessage::try_from(message.to_string().as_str()).expect("Should parse");
+        let json = serde_json::to_string(&message).expect("Should serialize");
+
+        assert_eq!(
+            json,
+            r#"{"address":"127.0.0.1:5000","tickers":["AAPL","MSFT"],"versions":[1,2,3],"format":"binary","control":false}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<SubscribeMessage>(&json).expect("Should deserialize"),
+            from_text
+        );
+
+        let ping: Vec<u8> = PingMessage.into();
+        let json = serde_json::to_string(&PingMessage).expect("Should serialize");
+        assert_eq!(
+            serde_json::from_str::<PingMessage>(&json).expect("Should deserialize"),
+            PingMessage::try_from(ping.as_slice()).expect("Should parse")
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Server replies to subscribe requests
+use std::{fmt::Display, net::SocketAddr};
+
+use crate::{
+    auth::AuthParams,
+    error::QuotesError,
+    error_message::{ErrorCode, ErrorMessage},
+    protocol::{ProtocolVersion, WireFormat},
+    session::TickerDictionary,
+};
+
+/// Server reply sent over TCP connection after subscribe message
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum SubscribeResponse {
+    /// Subscription accepted, data will be streamed using given protocol version
+    Accepted {
+        /// negotiated protocol version
+        version: ProtocolVersion,
+        /// ticker ids used in compact quotes
+        dictionary: TickerDictionary,
+        /// encoding of UDP stream
+        format: WireFormat,
+        /// datagrams are sealed with pre-shared key, None for unauthenticated stream
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        auth: Option<AuthParams>,
+        /// requested tickers the server won't stream, absent from dictionary
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Vec::is_empty")
+        )]
+        rejected: Vec<RejectedTicker>,
+        /// datagrams carry CRC32 trailer
+        #[cfg_attr(feature = "serde", serde(default))]
+        checksum: bool,
+        /// max size of UDP datagram, None if server doesn't tell
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        max_payload: Option<usize>,
+        /// address stream is sent from and pings are expected at, None if server doesn't advertise it
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        source: Option<SocketAddr>,
+        /// milliseconds of idle stream after which server sends heartbeat, None if it doesn't
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        heartbeat_ms: Option<u64>,
+    },
+    /// Subscription rejected with reason
+    Rejected(ErrorMessage),
+}
+
+/// Requested ticker left out of subscription
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RejectedTicker {
+    /// ticker as requested
+    pub ticker: String,
+    /// why it was rejected, e.g. unknown ticker or not entitled
+    pub code: ErrorCode,
+}
+
+impl RejectedTicker {
+    /// Create new RejectedTicker
+    pub fn new(ticker: impl Into<String>, code: ErrorCode) -> Self {
+        Self {
+            ticker: ticker.into(),
+            code,
+        }
+    }
+}
+
+/// Written as `<ticker>:<numeric code>`
+impl Display for RejectedTicker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.ticker, u16::from(self.code))
+    }
+}
+
+impl TryFrom<&str> for RejectedTicker {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value
+            .rsplit_once(":")
+            .and_then(|(ticker, code)| Some((ticker, code.parse::<u16>().ok()?)))
+            .filter(|(ticker, _)| !ticker.is_empty())
+            .map(|(ticker, code)| Self::new(ticker, ErrorCode::from(code)))
+            .ok_or_else(|| {
+                QuotesError::ParseServerMessageError(format!("Malformed rejected ticker {value}"))
+            })
+    }
+}
+
+impl SubscribeResponse {
+    const OK_HEADER: &str = "OK";
+    const ERR_HEADER: &str = "ERR";
+    const VERSION_OPTION: &str = "version";
+    const DICTIONARY_OPTION: &str = "dictionary";
+    const FORMAT_OPTION: &str = "format";
+    const AUTH_OPTION: &str = "auth";
+    const REJECTED_OPTION: &str = "rejected";
+    const CHECKSUM_OPTION: &str = "checksum";
+    const MAX_PAYLOAD_OPTION: &str = "max_payload";
+    const SOURCE_OPTION: &str = "source";
+    const HEARTBEAT_OPTION: &str = "heartbeat_ms";
+    const CODE_OPTION: &str = "code";
+
+    fn option<'a>(options: &'a str, key: &str) -> Result<&'a str, QuotesError> {
+        options
+            .split(" ")
+            .filter_map(|option| option.split_once("="))
+            .find(|(option_key, _)| *option_key == key)
+            .map(|(_, value)| value)
+            .ok_or_else(|| QuotesError::ParseServerMessageError(format!("Missing option {key}")))
+    }
+}
+
+impl Display for SubscribeResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscribeResponse::Accepted {
+                version,
+                dictionary,
+                format,
+                auth,
+                rejected,
+                checksum,
+                max_payload,
+                source,
+                heartbeat_ms,
+            } => {
+                write!(
+                    f,
+                    "{} {}={version} {}={dictionary}",
+                    Self::OK_HEADER,
+                    Self::VERSION_OPTION,
+                    Self::DICTIONARY_OPTION
+                )?;
+                if *format != WireFormat::default() {
+                    write!(f, " {}={format}", Self::FORMAT_OPTION)?;
+                }
+                if let Some(auth) = auth {
+                    write!(f, " {}={auth}", Self::AUTH_OPTION)?;
+                }
+                if !rejected.is_empty() {
+                    write!(
+                        f,
+                        " {}={}",
+                        Self::REJECTED_OPTION,
+                        rejected
+                            .iter()
+                            .map(|ticker| ticker.to_string())
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    )?;
+                }
+                if *checksum {
+                    write!(f, " {}={checksum}", Self::CHECKSUM_OPTION)?;
+                }
+                if let Some(max_payload) = max_payload {
+                    write!(f, " {}={max_payload}", Self::MAX_PAYLOAD_OPTION)?;
+                }
+                if let Some(source) = source {
+                    write!(f, " {}={source}", Self::SOURCE_OPTION)?;
+                }
+                if let Some(heartbeat_ms) = heartbeat_ms {
+                    write!(f, " {}={heartbeat_ms}", Self::HEARTBEAT_OPTION)?;
+                }
+                Ok(())
+            }
+            SubscribeResponse::Rejected(error) => write!(
+                f,
+                "{} {}={} {}",
+                Self::ERR_HEADER,
+                Self::CODE_OPTION,
+                u16::from(error.code),
+                error.detail
+            ),
+        }
+    }
+}
+
+impl TryFrom<&str> for SubscribeResponse {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        let (header, rest) = value.split_once(" ").unwrap_or((value, ""));
+
+        match header {
+            Self::OK_HEADER => {
+                let version = Self::option(rest, Self::VERSION_OPTION)?
+                    .parse::<u8>()
+                    .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))?;
+                let dictionary =
+                    TickerDictionary::try_from(Self::option(rest, Self::DICTIONARY_OPTION)?)?;
+
+                // servers without formats always reply in default one
+                let format = match Self::option(rest, Self::FORMAT_OPTION) {
+                    Ok(format) => WireFormat::try_from(format)?,
+                    Err(_) => WireFormat::default(),
+                };
+                let auth = match Self::option(rest, Self::AUTH_OPTION) {
+                    Ok(auth) => Some(AuthParams::try_from(auth)?),
+                    Err(_) => None,
+                };
+
+                // servers without acknowledgement details stream every requested ticker
+                let rejected = match Self::option(rest, Self::REJECTED_OPTION) {
+                    Ok(rejected) => rejected
+                        .split(",")
+                        .map(RejectedTicker::try_from)
+                        .collect::<Result<Vec<_>, _>>()?,
+                    Err(_) => vec![],
+                };
+                let checksum = match Self::option(rest, Self::CHECKSUM_OPTION) {
+                    Ok(checksum) => checksum
+                        .parse::<bool>()
+                        .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))?,
+                    Err(_) => false,
+                };
+                let max_payload = match Self::option(rest, Self::MAX_PAYLOAD_OPTION) {
+                    Ok(max_payload) => Some(
+                        max_payload
+                            .parse::<usize>()
+                            .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))?,
+                    ),
+                    Err(_) => None,
+                };
+                // clients of servers without advertised source ping address quotes come from
+                let source = match Self::option(rest, Self::SOURCE_OPTION) {
+                    Ok(source) => Some(source.parse::<SocketAddr>().map_err(|e| {
+                        QuotesError::ParseServerMessageError(format!("Malformed source {e}"))
+                    })?),
+                    Err(_) => None,
+                };
+                // servers without heartbeats are silent while nothing is streamed
+                let heartbeat_ms = match Self::option(rest, Self::HEARTBEAT_OPTION) {
+                    Ok(heartbeat_ms) => Some(
+                        heartbeat_ms
+                            .parse::<u64>()
+                            .map_err(|e| QuotesError::ParseServerMessageError(e.to_string()))?,
+                    ),
+                    Err(_) => None,
+                };
+
+                Ok(SubscribeResponse::Accepted {
+                    version: ProtocolVersion::try_from(version)?,
+                    dictionary,
+                    format,
+                    auth,
+                    rejected,
+                    checksum,
+                    max_payload,
+                    source,
+                    heartbeat_ms,
+                })
+            }
+            Self::ERR_HEADER => {
+                let (first, detail) = rest.split_once(" ").unwrap_or((rest, ""));
+                let code = first
+                    .strip_prefix(Self::CODE_OPTION)
+                    .and_then(|code| code.strip_prefix("="))
+                    .and_then(|code| code.parse::<u16>().ok());
+
+                // servers without error codes reply with plain text
+                Ok(SubscribeResponse::Rejected(match code {
+                    Some(code) => ErrorMessage::new(ErrorCode::from(code), detail),
+                    None => ErrorMessage::new(ErrorCode::Unspecified, rest),
+                }))
+            }
+            other => Err(QuotesError::ParseServerMessageError(format!(
+                "Unexpected subscribe response {other}"
+            ))),
+        }
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::*;
+    use crate::auth::AuthMode;
+
+    #[test]
+    fn test_response_roundtrip() {
+        for response in [
+            SubscribeResponse::Accepted {
+                version: ProtocolVersion::V2,
+                dictionary: TickerDictionary::new(&["AAPL".to_string(), "MSFT".to_string()]),
+                format: WireFormat::Binary,
+                auth: None,
+                rejected: vec![],
+                checksum: false,
+                max_payload: None,
+                source: None,
+                heartbeat_ms: None,
+            },
+            SubscribeResponse::Accepted {
+                version: ProtocolVersion::V2,
+                dictionary: TickerDictionary::new(&["AAPL".to_string()]),
+                format: WireFormat::Binary,
+                auth: Some(AuthParams::new(
+                    AuthMode::Encrypt,
+                    [0xA5; AuthParams::SALT_SIZE],
+                )),
+                rejected: vec![
+                    RejectedTicker::new("ZZZZ", ErrorCode::UnknownTicker),
+                    RejectedTicker::new("BRK:B", ErrorCode::NotEntitled),
+                ],
+                checksum: true,
+                max_payload: Some(1400),
+                source: Some("[2001:db8::1]:40000".parse().unwrap()),
+                heartbeat_ms: Some(2000),
+            },
+            SubscribeResponse::Rejected(ErrorMessage::new(
+                ErrorCode::UnsupportedVersion,
+                "No common protocol version",
+            )),
+        ] {
+            let parsed =
+                SubscribeResponse::try_from(response.to_string().as_str()).expect("Should parse");
+            assert_eq!(parsed, response);
+        }
+    }
+
+    #[test]
+    fn test_legacy_rejection() {
+        assert_eq!(
+            SubscribeResponse::try_from("ERR Unsupported protocol version: 9\n")
+                .expect("Should parse"),
+            SubscribeResponse::Rejected(ErrorMessage::new(
+                ErrorCode::Unspecified,
+                "Unsupported protocol version: 9"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_legacy_acceptance() {
+        let SubscribeResponse::Accepted {
+            rejected,
+            checksum,
+            max_payload,
+            source,
+            heartbeat_ms,
+            ..
+        } = SubscribeResponse::try_from("OK version=2 dictionary=0:AAPL\n").expect("Should parse")
+        else {
+            panic!("Should be accepted");
+        };
+
+        assert!(rejected.is_empty());
+        assert!(!checksum);
+        assert_eq!(max_payload, None);
+        assert_eq!(source, None);
+        assert_eq!(heartbeat_ms, None);
+        assert!(
+            SubscribeResponse::try_from("OK version=2 dictionary=0:AAPL rejected=MSFT").is_err()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_matches_text() {
+        let response = SubscribeResponse::Accepted {
+            version: ProtocolVersion::V2,
+            dictionary: TickerDictionary::new(&["AAPL".to_string(), "MSFT".to_string()]),
+            format: WireFormat::Binary,
+            auth: None,
+            rejected: vec![],
+            checksum: false,
+            max_payload: Some(1400),
+            source: Some("192.0.2.1:40000".parse().unwrap()),
+            heartbeat_ms: Some(2000),
+        };
+        let json = serde_json::to_string(&response).expect("Should serialize");
+
+        assert_eq!(
+            json,
+            r#"{"accepted":{"version":2,"dictionary":["AAPL","MSFT"],"format":"binary","checksum":false,"max_payload":1400,"source":"192.0.2.1:40000","heartbeat_ms":2000}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<SubscribeResponse>(&json).expect("Should deserialize"),
+            SubscribeResponse::try_from(response.to_string().as_str()).expect("Should parse")
+        );
+        assert!(
+            serde_json::from_str::<SubscribeResponse>(
+                r#"{"accepted":{"version":9,"dictionary":[],"format":"binary"}}"#
+            )
+            .is_err()
+        );
+    }
+}
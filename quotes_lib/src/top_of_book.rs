@@ -0,0 +1,363 @@
+//! Top-of-book quote module
+use std::fmt::Display;
+
+use crate::{
+    error::QuotesError,
+    price::Price,
+    session::{TickerDictionary, split_ticker, split_ticker_id, write_ticker},
+    sink::{self, Sink},
+};
+
+/// Price level of the order book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BookLevel {
+    /// Price of the level
+    pub price: Price,
+    /// Size available at the price in units
+    pub size: u32,
+}
+
+impl BookLevel {
+    /// Create new BookLevel
+    pub fn new(price: Price, size: u32) -> Self {
+        Self { price, size }
+    }
+}
+
+impl Display for BookLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} x {}", self.price, self.size)
+    }
+}
+
+/// Best bid and ask of an instrument.
+/// Bid never exceeds ask, so the book can't be constructed or decoded crossed
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "BookFields")
+)]
+pub struct TopOfBook {
+    ticker: String,
+    bid: BookLevel,
+    ask: BookLevel,
+    timestamp: u64,
+}
+
+/// Unchecked fields of deserialized book, validated by TopOfBook::new
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct BookFields {
+    ticker: String,
+    bid: BookLevel,
+    ask: BookLevel,
+    timestamp: u64,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<BookFields> for TopOfBook {
+    type Error = QuotesError;
+
+    fn try_from(value: BookFields) -> Result<Self, Self::Error> {
+        Self::new(value.ticker, value.bid, value.ask, value.timestamp)
+    }
+}
+
+impl Display for TopOfBook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Book {} bid: {}, ask: {}, timestamp: {}",
+            self.ticker, self.bid, self.ask, self.timestamp
+        )
+    }
+}
+
+impl TopOfBook {
+    /// Version byte of the length-prefixed binary format
+    pub const FORMAT_VERSION: u8 = 1;
+
+    /// Version byte of binary format with ticker replaced by session dictionary id
+    pub const COMPACT_FORMAT_VERSION: u8 = 2;
+
+    const TICKER_LEN_SIZE: usize = 2;
+    const TICKER_ID_SIZE: usize = 2;
+    const SIZE_SIZE: usize = 4;
+    const TIMESTAMP_SIZE: usize = 8;
+    const LEVEL_SIZE: usize = Price::ENCODED_SIZE + Self::SIZE_SIZE;
+    const NUMERIC_FIELDS_SIZE: usize = 2 * Self::LEVEL_SIZE + Self::TIMESTAMP_SIZE;
+
+    /// Create new TopOfBook, fails if bid price is above ask price
+    pub fn new(
+        ticker: String,
+        bid: BookLevel,
+        ask: BookLevel,
+        timestamp: u64,
+    ) -> Result<Self, QuotesError> {
+        if bid.price > ask.price {
+            return Err(QuotesError::CrossedBook(format!(
+                "{ticker} bid {} is above ask {}",
+                bid.price, ask.price
+            )));
+        }
+
+        Ok(Self {
+            ticker,
+            bid,
+            ask,
+            timestamp,
+        })
+    }
+
+    /// Ticker
+    pub fn ticker(&self) -> &str {
+        &self.ticker
+    }
+
+    /// Best bid
+    pub fn bid(&self) -> BookLevel {
+        self.bid
+    }
+
+    /// Best ask
+    pub fn ask(&self) -> BookLevel {
+        self.ask
+    }
+
+    /// Unix timestamp in millis
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Difference between ask and bid prices, None if it doesn't fit into Price
+    pub fn spread(&self) -> Option<Price> {
+        self.ask.price.checked_sub(self.bid.price)
+    }
+
+    /// Encode book in compact binary format:
+    /// `[version: u8][ticker id: u16][bid][ask][timestamp: u64]`,
+    /// ticker id must be resolved by peer with the same session dictionary
+    pub fn to_compact_bytes(&self, ticker_id: u16) -> Result<Vec<u8>, QuotesError> {
+        sink::encode_to_vec(
+            1 + Self::TICKER_ID_SIZE + Self::NUMERIC_FIELDS_SIZE,
+            |sink| self.write_compact(ticker_id, sink),
+        )
+    }
+
+    /// Encode book in length-prefixed binary format into buffer, returns count of bytes written
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write_binary(sink))
+    }
+
+    /// Encode book in compact binary format into buffer, returns count of bytes written
+    pub fn encode_compact_into(
+        &self,
+        ticker_id: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write_compact(ticker_id, sink))
+    }
+
+    /// Decode book in any format, resolving compact ticker ids with dictionary
+    pub fn decode_with(value: &[u8], dictionary: &TickerDictionary) -> Result<Self, QuotesError> {
+        if value.first() != Some(&Self::COMPACT_FORMAT_VERSION) {
+            return Self::try_from(value);
+        }
+
+        let (ticker, rest) = split_ticker_id(&value[1..], dictionary)?;
+        Self::decode_numeric_fields(ticker.to_string(), rest)
+    }
+
+    pub(crate) fn write_binary(&self, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        sink.put(&[Self::FORMAT_VERSION])?;
+        write_ticker(sink, &self.ticker)?;
+        self.write_numeric_fields(sink)
+    }
+
+    pub(crate) fn write_compact(
+        &self,
+        ticker_id: u16,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        sink.put(&[Self::COMPACT_FORMAT_VERSION])?;
+        sink.put(&ticker_id.to_be_bytes())?;
+        self.write_numeric_fields(sink)
+    }
+
+    fn write_level(level: BookLevel, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        sink.put(&level.price.to_be_bytes())?;
+        sink.put(&level.size.to_be_bytes())
+    }
+
+    fn write_numeric_fields(&self, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        Self::write_level(self.bid, sink)?;
+        Self::write_level(self.ask, sink)?;
+        sink.put(&self.timestamp.to_be_bytes())
+    }
+
+    fn decode_level(value: &[u8]) -> Result<BookLevel, QuotesError> {
+        let mut price = [0u8; Price::ENCODED_SIZE];
+        price.copy_from_slice(&value[..Price::ENCODED_SIZE]);
+        let mut size = [0u8; Self::SIZE_SIZE];
+        size.copy_from_slice(&value[Price::ENCODED_SIZE..Self::LEVEL_SIZE]);
+
+        Ok(BookLevel::new(
+            Price::from_be_bytes(price)?,
+            u32::from_be_bytes(size),
+        ))
+    }
+
+    fn decode_numeric_fields(ticker: String, value: &[u8]) -> Result<Self, QuotesError> {
+        if value.len() != Self::NUMERIC_FIELDS_SIZE {
+            return Err(QuotesError::ParseQuoteError(
+                "Incorrect data format".to_string(),
+            ));
+        }
+
+        let (bid, rest) = value.split_at(Self::LEVEL_SIZE);
+        let (ask, timestamp_bytes) = rest.split_at(Self::LEVEL_SIZE);
+        let mut timestamp = [0u8; Self::TIMESTAMP_SIZE];
+        timestamp.copy_from_slice(timestamp_bytes);
+
+        Self::new(
+            ticker,
+            Self::decode_level(bid)?,
+            Self::decode_level(ask)?,
+            u64::from_be_bytes(timestamp),
+        )
+        .map_err(|e| QuotesError::ParseQuoteError(e.to_string()))
+    }
+}
+
+/// Encodes book in length-prefixed binary format:
+/// `[version: u8][ticker len: u16][ticker][bid][ask][timestamp: u64]`,
+/// level is `[price][size: u32]`, price is `[mantissa: i64][scale: u8]`, all numbers are big-endian.
+/// Fails for tickers longer than `u16::MAX` bytes
+impl TryFrom<&TopOfBook> for Vec<u8> {
+    type Error = QuotesError;
+
+    fn try_from(value: &TopOfBook) -> Result<Self, Self::Error> {
+        sink::encode_to_vec(
+            1 + TopOfBook::TICKER_LEN_SIZE + value.ticker.len() + TopOfBook::NUMERIC_FIELDS_SIZE,
+            |sink| value.write_binary(sink),
+        )
+    }
+}
+
+/// Decodes book in length-prefixed binary format
+impl TryFrom<&[u8]> for TopOfBook {
+    type Error = QuotesError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match value.first() {
+            Some(&TopOfBook::FORMAT_VERSION) => {}
+            Some(&TopOfBook::COMPACT_FORMAT_VERSION) => {
+                return Err(QuotesError::ParseQuoteError(
+                    "Compact book requires ticker dictionary".to_string(),
+                ));
+            }
+            _ => {
+                return Err(QuotesError::ParseQuoteError(
+                    "Unknown book format".to_string(),
+                ));
+            }
+        }
+
+        let (ticker, rest) = split_ticker(&value[1..])?;
+        Self::decode_numeric_fields(ticker.to_string(), rest)
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    fn sample_book() -> TopOfBook {
+        TopOfBook::new(
+            "AAPL".to_string(),
+            BookLevel::new(Price::new(12344, 2), 300),
+            BookLevel::new(Price::new(123455, 3), 1200),
+            1_700_000_000_000,
+        )
+        .expect("Book should not be crossed")
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let book = sample_book();
+        let bytes = Vec::<u8>::try_from(&book).expect("Should encode book");
+
+        assert_eq!(
+            TopOfBook::try_from(bytes.as_slice()).expect("Should decode"),
+            book
+        );
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let book = sample_book();
+        let dictionary = TickerDictionary::new(&["MSFT".to_string(), "AAPL".to_string()]);
+        let bytes = book.to_compact_bytes(1).expect("Should encode book");
+
+        assert!(TopOfBook::try_from(bytes.as_slice()).is_err());
+        assert_eq!(
+            TopOfBook::decode_with(&bytes, &dictionary).expect("Should decode"),
+            book
+        );
+        assert!(
+            TopOfBook::decode_with(
+                &book.to_compact_bytes(2).expect("Should encode book"),
+                &dictionary
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_spread() {
+        assert_eq!(sample_book().spread(), Some(Price::new(15, 3)));
+    }
+
+    #[test]
+    fn test_crossed_book_rejected() {
+        let bid = BookLevel::new(Price::new(101, 0), 1);
+        let ask = BookLevel::new(Price::new(100, 0), 1);
+
+        assert!(TopOfBook::new("AAPL".to_string(), bid, ask, 0).is_err());
+        assert!(TopOfBook::new("AAPL".to_string(), ask, ask, 0).is_ok());
+
+        // swap bid and ask of a valid encoded book
+        let mut bytes = Vec::<u8>::try_from(&sample_book()).expect("Should encode book");
+        let bid_start = 1 + TopOfBook::TICKER_LEN_SIZE + "AAPL".len();
+        let ask_start = bid_start + TopOfBook::LEVEL_SIZE;
+        let bid_bytes = bytes[bid_start..ask_start].to_vec();
+        bytes.copy_within(ask_start..ask_start + TopOfBook::LEVEL_SIZE, bid_start);
+        bytes[ask_start..ask_start + TopOfBook::LEVEL_SIZE].copy_from_slice(&bid_bytes);
+
+        assert!(TopOfBook::try_from(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_truncated_and_trailing() {
+        let bytes = Vec::<u8>::try_from(&sample_book()).expect("Should encode book");
+
+        for len in 0..bytes.len() {
+            assert!(TopOfBook::try_from(&bytes[..len]).is_err(), "len {len}");
+        }
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(TopOfBook::try_from(longer.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_scale() {
+        let mut bytes = Vec::<u8>::try_from(&sample_book()).expect("Should encode book");
+        let scale_position = 1 + TopOfBook::TICKER_LEN_SIZE + "AAPL".len() + 8;
+        bytes[scale_position] = Price::MAX_SCALE + 1;
+
+        assert!(TopOfBook::try_from(bytes.as_slice()).is_err());
+    }
+}
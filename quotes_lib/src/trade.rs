@@ -0,0 +1,301 @@
+//! Trade print module
+use std::fmt::Display;
+
+use crate::{
+    error::QuotesError,
+    price::Price,
+    session::{TickerDictionary, split_ticker, split_ticker_id, write_ticker},
+    sink::{self, Sink},
+};
+
+/// Side of the order that initiated a trade
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Side {
+    /// Buyer lifted the offer
+    Buy = 1,
+    /// Seller hit the bid
+    Sell = 2,
+}
+
+impl Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Buy => write!(f, "BUY"),
+            Side::Sell => write!(f, "SELL"),
+        }
+    }
+}
+
+impl From<Side> for u8 {
+    fn from(value: Side) -> Self {
+        value as u8
+    }
+}
+
+impl TryFrom<u8> for Side {
+    type Error = QuotesError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Side::Buy),
+            2 => Ok(Side::Sell),
+            other => Err(QuotesError::ParseQuoteError(format!(
+                "Unknown aggressor side {other}"
+            ))),
+        }
+    }
+}
+
+/// Single trade print
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Trade {
+    /// Ticker
+    pub ticker: String,
+    /// Execution price
+    pub price: Price,
+    /// Executed size in units
+    pub size: u32,
+    /// Side of the order that initiated the trade
+    pub aggressor: Side,
+    /// Id of the trade, unique within the stream
+    pub trade_id: u64,
+    /// Unix timestamp in millis
+    pub timestamp: u64,
+}
+
+impl Display for Trade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Trade {} #{} {} {} @ {}, timestamp: {}",
+            self.ticker, self.trade_id, self.aggressor, self.size, self.price, self.timestamp
+        )
+    }
+}
+
+impl Trade {
+    /// Version byte of the length-prefixed binary format
+    pub const FORMAT_VERSION: u8 = 1;
+
+    /// Version byte of binary format with ticker replaced by session dictionary id
+    pub const COMPACT_FORMAT_VERSION: u8 = 2;
+
+    const TICKER_LEN_SIZE: usize = 2;
+    const TICKER_ID_SIZE: usize = 2;
+    const SIZE_SIZE: usize = 4;
+    const SIDE_SIZE: usize = 1;
+    const TRADE_ID_SIZE: usize = 8;
+    const TIMESTAMP_SIZE: usize = 8;
+    const NUMERIC_FIELDS_SIZE: usize = Price::ENCODED_SIZE
+        + Self::SIZE_SIZE
+        + Self::SIDE_SIZE
+        + Self::TRADE_ID_SIZE
+        + Self::TIMESTAMP_SIZE;
+
+    /// Encode trade in compact binary format:
+    /// `[version: u8][ticker id: u16][price][size: u32][side: u8][trade id: u64][timestamp: u64]`,
+    /// ticker id must be resolved by peer with the same session dictionary
+    pub fn to_compact_bytes(&self, ticker_id: u16) -> Result<Vec<u8>, QuotesError> {
+        sink::encode_to_vec(
+            1 + Self::TICKER_ID_SIZE + Self::NUMERIC_FIELDS_SIZE,
+            |sink| self.write_compact(ticker_id, sink),
+        )
+    }
+
+    /// Encode trade in length-prefixed binary format into buffer, returns count of bytes written
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write_binary(sink))
+    }
+
+    /// Encode trade in compact binary format into buffer, returns count of bytes written
+    pub fn encode_compact_into(
+        &self,
+        ticker_id: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, QuotesError> {
+        sink::encode_into(buf, |sink| self.write_compact(ticker_id, sink))
+    }
+
+    /// Decode trade in any format, resolving compact ticker ids with dictionary
+    pub fn decode_with(value: &[u8], dictionary: &TickerDictionary) -> Result<Self, QuotesError> {
+        if value.first() != Some(&Self::COMPACT_FORMAT_VERSION) {
+            return Self::try_from(value);
+        }
+
+        let (ticker, rest) = split_ticker_id(&value[1..], dictionary)?;
+        Self::decode_numeric_fields(ticker.to_string(), rest)
+    }
+
+    pub(crate) fn write_binary(&self, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        sink.put(&[Self::FORMAT_VERSION])?;
+        write_ticker(sink, &self.ticker)?;
+        self.write_numeric_fields(sink)
+    }
+
+    pub(crate) fn write_compact(
+        &self,
+        ticker_id: u16,
+        sink: &mut impl Sink,
+    ) -> Result<(), QuotesError> {
+        sink.put(&[Self::COMPACT_FORMAT_VERSION])?;
+        sink.put(&ticker_id.to_be_bytes())?;
+        self.write_numeric_fields(sink)
+    }
+
+    fn write_numeric_fields(&self, sink: &mut impl Sink) -> Result<(), QuotesError> {
+        sink.put(&self.price.to_be_bytes())?;
+        sink.put(&self.size.to_be_bytes())?;
+        sink.put(&[self.aggressor.into()])?;
+        sink.put(&self.trade_id.to_be_bytes())?;
+        sink.put(&self.timestamp.to_be_bytes())
+    }
+
+    fn decode_numeric_fields(ticker: String, value: &[u8]) -> Result<Self, QuotesError> {
+        if value.len() != Self::NUMERIC_FIELDS_SIZE {
+            return Err(QuotesError::ParseQuoteError(
+                "Incorrect data format".to_string(),
+            ));
+        }
+
+        let (price_bytes, rest) = value.split_at(Price::ENCODED_SIZE);
+        let (size_bytes, rest) = rest.split_at(Self::SIZE_SIZE);
+        let (side_bytes, rest) = rest.split_at(Self::SIDE_SIZE);
+        let (trade_id_bytes, timestamp_bytes) = rest.split_at(Self::TRADE_ID_SIZE);
+
+        let mut price = [0u8; Price::ENCODED_SIZE];
+        price.copy_from_slice(price_bytes);
+        let mut size = [0u8; Self::SIZE_SIZE];
+        size.copy_from_slice(size_bytes);
+        let mut trade_id = [0u8; Self::TRADE_ID_SIZE];
+        trade_id.copy_from_slice(trade_id_bytes);
+        let mut timestamp = [0u8; Self::TIMESTAMP_SIZE];
+        timestamp.copy_from_slice(timestamp_bytes);
+
+        Ok(Self {
+            ticker,
+            price: Price::from_be_bytes(price)?,
+            size: u32::from_be_bytes(size),
+            aggressor: Side::try_from(side_bytes[0])?,
+            trade_id: u64::from_be_bytes(trade_id),
+            timestamp: u64::from_be_bytes(timestamp),
+        })
+    }
+}
+
+/// Encodes trade in length-prefixed binary format:
+/// `[version: u8][ticker len: u16][ticker][price][size: u32][side: u8][trade id: u64][timestamp: u64]`,
+/// price is `[mantissa: i64][scale: u8]`, all numbers are big-endian.
+/// Fails for tickers longer than `u16::MAX` bytes
+impl TryFrom<&Trade> for Vec<u8> {
+    type Error = QuotesError;
+
+    fn try_from(value: &Trade) -> Result<Self, Self::Error> {
+        sink::encode_to_vec(
+            1 + Trade::TICKER_LEN_SIZE + value.ticker.len() + Trade::NUMERIC_FIELDS_SIZE,
+            |sink| value.write_binary(sink),
+        )
+    }
+}
+
+/// Decodes trade in length-prefixed binary format
+impl TryFrom<&[u8]> for Trade {
+    type Error = QuotesError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match value.first() {
+            Some(&Trade::FORMAT_VERSION) => {
+                let (ticker, rest) = split_ticker(&value[1..])?;
+                Self::decode_numeric_fields(ticker.to_string(), rest)
+            }
+            Some(&Trade::COMPACT_FORMAT_VERSION) => Err(QuotesError::ParseQuoteError(
+                "Compact trade requires ticker dictionary".to_string(),
+            )),
+            _ => Err(QuotesError::ParseQuoteError(
+                "Unknown trade format".to_string(),
+            )),
+        }
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    fn sample_trade() -> Trade {
+        Trade {
+            ticker: "AAPL".to_string(),
+            price: Price::new(12345, 2),
+            size: 300,
+            aggressor: Side::Sell,
+            trade_id: u64::MAX - 7,
+            timestamp: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for aggressor in [Side::Buy, Side::Sell] {
+            let trade = Trade {
+                aggressor,
+                ..sample_trade()
+            };
+            let bytes = Vec::<u8>::try_from(&trade).expect("Should encode trade");
+
+            assert_eq!(
+                Trade::try_from(bytes.as_slice()).expect("Should decode"),
+                trade
+            );
+        }
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let trade = sample_trade();
+        let dictionary = TickerDictionary::new(&["MSFT".to_string(), "AAPL".to_string()]);
+        let bytes = trade.to_compact_bytes(1).expect("Should encode trade");
+
+        assert!(Trade::try_from(bytes.as_slice()).is_err());
+        assert_eq!(
+            Trade::decode_with(&bytes, &dictionary).expect("Should decode"),
+            trade
+        );
+        assert!(
+            Trade::decode_with(
+                &trade.to_compact_bytes(2).expect("Should encode trade"),
+                &dictionary
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_unknown_side() {
+        let mut bytes = Vec::<u8>::try_from(&sample_trade()).expect("Should encode trade");
+        let side_position =
+            1 + Trade::TICKER_LEN_SIZE + "AAPL".len() + Price::ENCODED_SIZE + Trade::SIZE_SIZE;
+
+        for side in [0, 3, u8::MAX] {
+            bytes[side_position] = side;
+            assert!(Trade::try_from(bytes.as_slice()).is_err(), "side {side}");
+        }
+    }
+
+    #[test]
+    fn test_truncated_and_trailing() {
+        let bytes = Vec::<u8>::try_from(&sample_trade()).expect("Should encode trade");
+
+        for len in 0..bytes.len() {
+            assert!(Trade::try_from(&bytes[..len]).is_err(), "len {len}");
+        }
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(Trade::try_from(longer.as_slice()).is_err());
+    }
+}
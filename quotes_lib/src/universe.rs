@@ -0,0 +1,147 @@
+//! Tickers known to server and named groups of them, used to expand subscription requests
+use std::path::PathBuf;
+
+use crate::{error::QuotesError, pattern::TickerPattern};
+
+/// Server tickers file, one ticker per line or group definition `<name> = <pattern>,<pattern>...`,
+/// e.g. `tech = AAPL,MSFT,NV*`. Empty lines and lines starting with `#` are skipped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TickerUniverse {
+    tickers: Vec<String>,
+    groups: Vec<(String, Vec<TickerPattern>)>,
+}
+
+impl TickerUniverse {
+    const GROUP_SEPARATOR: char = '=';
+
+    /// Read universe from file
+    pub fn from_file(path: PathBuf) -> Result<Self, QuotesError> {
+        Self::try_from(std::fs::read_to_string(path)?.as_str())
+    }
+
+    /// All known tickers in file order
+    pub fn tickers(&self) -> &[String] {
+        &self.tickers
+    }
+
+    /// Names of defined groups
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Known tickers matching selector in file order: group name, pattern or exact ticker.
+    /// Empty for malformed selectors and unknown tickers
+    pub fn expand(&self, selector: &str) -> Vec<String> {
+        let patterns = match self.group(selector) {
+            Some(patterns) => patterns.to_vec(),
+            None => match TickerPattern::try_from(selector) {
+                Ok(pattern) => vec![pattern],
+                Err(_) => return vec![],
+            },
+        };
+
+        self.tickers
+            .iter()
+            .filter(|ticker| patterns.iter().any(|pattern| pattern.matches(ticker)))
+            .cloned()
+            .collect()
+    }
+
+    fn group(&self, name: &str) -> Option<&[TickerPattern]> {
+        self.groups
+            .iter()
+            .find(|(group, _)| group == name)
+            .map(|(_, patterns)| patterns.as_slice())
+    }
+}
+
+impl TryFrom<&str> for TickerUniverse {
+    type Error = QuotesError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut universe = Self::default();
+
+        for (index, line) in value.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error =
+                || QuotesError::ConfigError(format!("Malformed tickers line {}", index + 1));
+            match line.split_once(Self::GROUP_SEPARATOR) {
+                Some((name, patterns)) => {
+                    let name = name.trim();
+                    let pattern = TickerPattern::try_from(name).map_err(|_| error())?;
+                    if pattern.is_wildcard() {
+                        return Err(error());
+                    }
+
+                    let patterns = patterns
+                        .trim()
+                        .split(',')
+                        .map(TickerPattern::try_from)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| error())?;
+                    universe.groups.push((name.to_string(), patterns));
+                }
+                None => {
+                    let ticker = TickerPattern::try_from(line).map_err(|_| error())?;
+                    if ticker.is_wildcard() {
+                        return Err(error());
+                    }
+                    if !universe.tickers.iter().any(|known| known == line) {
+                        universe.tickers.push(line.to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(universe)
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    const TICKERS: &str = "
+        # tickers
+        AAPL
+        MSFT
+        NVDA
+        NVDX
+        JPM
+
+        tech = AAPL,MSFT,NV*
+        banks = JPM,GS
+    ";
+
+    #[test]
+    fn test_expand() {
+        let universe = TickerUniverse::try_from(TICKERS).expect("Should parse");
+
+        assert_eq!(universe.tickers(), ["AAPL", "MSFT", "NVDA", "NVDX", "JPM"]);
+        assert_eq!(universe.groups().collect::<Vec<_>>(), ["tech", "banks"]);
+        assert_eq!(universe.expand("tech"), ["AAPL", "MSFT", "NVDA", "NVDX"]);
+        assert_eq!(universe.expand("banks"), ["JPM"]);
+        assert_eq!(universe.expand("NV*"), ["NVDA", "NVDX"]);
+        assert_eq!(universe.expand("*").len(), 5);
+        assert_eq!(universe.expand("JPM"), ["JPM"]);
+        assert!(universe.expand("GS").is_empty());
+        assert!(universe.expand("A,B").is_empty());
+    }
+
+    #[test]
+    fn test_malformed_universe() {
+        for text in ["A*", "tech =", "tech = A,,B", "* = AAPL", "AAPL MSFT"] {
+            assert!(
+                matches!(
+                    TickerUniverse::try_from(text),
+                    Err(QuotesError::ConfigError(_))
+                ),
+                "{text}"
+            );
+        }
+    }
+}
@@ -1,35 +1,60 @@
 use std::{
-    collections::{HashMap, hash_map::Entry},
-    net::SocketAddrV4,
+    collections::HashMap,
+    net::SocketAddr,
     sync::{Arc, RwLock},
     thread::{self, JoinHandle},
     time::Duration,
 };
 
 use crossbeam_channel::{Receiver, Sender, unbounded};
-use log::{error, trace, warn};
-use quotes_lib::quote::Quote;
+use log::{debug, error, info, trace, warn};
+use quotes_lib::{
+    control_message::ControlMessage,
+    entitlements::Entitlements,
+    error_message::{ErrorCode, ErrorMessage},
+    protocol::ProtocolVersion,
+    server_message::ServerMessage,
+    session::TickerDictionary,
+    subscribe_response::{RejectedTicker, SubscribeResponse},
+    universe::TickerUniverse,
+};
 
 use crate::{
     error::ServerError,
-    single_client_handler::{SingleClientHandler, SingleClientHandlerEvent},
+    events::Subscription,
+    quotes_source::MarketData,
+    single_client_handler::{SingleClientHandler, SingleClientHandlerEvent, StreamConfig},
 };
 
 pub struct ClientsHandler {
-    quotes: Arc<RwLock<HashMap<String, Quote>>>,
-    clients: Arc<RwLock<HashMap<SocketAddrV4, SingleClientHandler>>>,
+    quotes: Arc<RwLock<HashMap<String, MarketData>>>,
+    /// tickers and groups subscriptions are expanded against
+    universe: Arc<RwLock<TickerUniverse>>,
+    stream_config: StreamConfig,
+    /// tickers each client may receive, everything is allowed if not set
+    entitlements: Option<Entitlements>,
+    /// streams by subscription id
+    clients: Arc<RwLock<HashMap<u64, SingleClientHandler>>>,
     event_tx: Sender<SingleClientHandlerEvent>,
     event_rx: Receiver<SingleClientHandlerEvent>,
     thread_handle: Option<JoinHandle<()>>,
 }
 
 impl ClientsHandler {
-    pub fn new(quotes: Arc<RwLock<HashMap<String, Quote>>>) -> Self {
+    pub fn new(
+        quotes: Arc<RwLock<HashMap<String, MarketData>>>,
+        universe: Arc<RwLock<TickerUniverse>>,
+        stream_config: StreamConfig,
+        entitlements: Option<Entitlements>,
+    ) -> Self {
         let (event_tx, event_rx) = unbounded();
         let clients = Arc::new(RwLock::new(HashMap::new()));
 
         Self {
             quotes,
+            universe,
+            stream_config,
+            entitlements,
             clients,
             event_tx,
             event_rx,
@@ -52,17 +77,16 @@ impl ClientsHandler {
                 loop {
                     match event_rx.recv() {
                         Ok(msg) => match msg {
-                            SingleClientHandlerEvent::Disconnected(socket_addr_v4) => {
-                                if let Err(e) = Self::remove_and_stop_clients(
-                                    clients.clone(),
-                                    &[socket_addr_v4],
-                                ) {
+                            SingleClientHandlerEvent::Disconnected(id) => {
+                                if let Err(e) =
+                                    Self::remove_and_stop_clients(clients.clone(), &[id])
+                                {
                                     error!("Error stopping clients {e}");
                                     break;
                                 }
                             }
-                            SingleClientHandlerEvent::Error(socket_addr_v4, server_error) => {
-                                warn!("Error in client for {socket_addr_v4}: {server_error}")
+                            SingleClientHandlerEvent::Error(id, server_error) => {
+                                warn!("Error in client #{id}: {server_error}")
                             }
                         },
                         Err(e) => warn!("ClientsHandler listen events read error {e}"),
@@ -83,6 +107,10 @@ impl ClientsHandler {
                 .read()
                 .map(|guard| guard.keys().copied().collect::<Vec<_>>())
                 .map_err(|e| ServerError::ClientsReadError(e.to_string()))?;
+            Self::notify_clients(
+                &self.clients,
+                ErrorMessage::new(ErrorCode::ShuttingDown, "Server is shutting down"),
+            )?;
             if let Err(e) = Self::remove_and_stop_clients(self.clients.clone(), &all_clients) {
                 error!("Error stopping clients {e}");
             }
@@ -110,18 +138,20 @@ impl ClientsHandler {
                 .read()
                 .map_err(|e| ServerError::ClientsReadError(e.to_string()))?;
 
-            for (addr, client) in clients.iter() {
+            for (id, client) in clients.iter() {
+                let mut client_data = Vec::with_capacity(client.tickers().len());
                 for ticker in client.tickers().iter() {
-                    if let Some(quote) = quotes.get(ticker) {
-                        if let Err(e) = client.send_quote(quote.clone()) {
-                            warn!("Client unable to send quote {e}");
-                            clients_with_errors.push(*addr);
-                            break;
-                        }
+                    if let Some(data) = quotes.get(ticker) {
+                        client_data.push(data.clone());
                     } else {
                         warn!("Ticker not found {ticker}");
                     }
                 }
+
+                if let Err(e) = client.send_market_data(client_data) {
+                    warn!("Client unable to send quotes {e}");
+                    clients_with_errors.push(*id);
+                }
             }
 
             trace!("Clients with errors count {}", clients_with_errors.len());
@@ -130,12 +160,30 @@ impl ClientsHandler {
         Self::remove_and_stop_clients(self.clients.clone(), &clients_with_errors)
     }
 
+    /// Send message to every client, delivery errors only get logged as clients are going away anyway
+    fn notify_clients(
+        clients: &RwLock<HashMap<u64, SingleClientHandler>>,
+        error: ErrorMessage,
+    ) -> Result<(), ServerError> {
+        let clients = clients
+            .read()
+            .map_err(|e| ServerError::ClientsReadError(e.to_string()))?;
+
+        for (id, client) in clients.iter() {
+            if let Err(e) = client.send_message(ServerMessage::Err(error.clone())) {
+                warn!("Unable to notify #{id}: {e}");
+            }
+        }
+
+        Ok(())
+    }
+
     fn remove_and_stop_clients(
-        clients: Arc<RwLock<HashMap<SocketAddrV4, SingleClientHandler>>>,
-        addr_to_remove: &[SocketAddrV4],
+        clients: Arc<RwLock<HashMap<u64, SingleClientHandler>>>,
+        ids_to_remove: &[u64],
     ) -> Result<(), ServerError> {
-        trace!("remove_and_stop_clients {addr_to_remove:?}");
-        if addr_to_remove.is_empty() {
+        trace!("remove_and_stop_clients {ids_to_remove:?}");
+        if ids_to_remove.is_empty() {
             trace!("list is empty");
             return Ok(());
         }
@@ -146,8 +194,8 @@ impl ClientsHandler {
                 Err(e) => return Err(ServerError::ClientsReadError(e.to_string())),
             };
 
-            for addr in addr_to_remove {
-                if let Some(client) = guard.remove(addr)
+            for id in ids_to_remove {
+                if let Some(client) = guard.remove(id)
                     && let Err(e) = client.stop()
                 {
                     warn!("Client stop error {e}");
@@ -159,29 +207,466 @@ impl ClientsHandler {
     }
 
     const CLIENT_PING_TIMEOUT: Duration = Duration::from_secs(5);
+    /// time client has to echo challenge of stream with verified address
+    const CLIENT_VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
 
+    /// Start streaming checked tickers, client learns accepted and rejected ones from reply
     pub fn handle_new_client(
         &mut self,
-        address: SocketAddrV4,
-        tickers: Vec<String>,
+        mut subscription: Subscription,
+        reply: Sender<SubscribeResponse>,
     ) -> Result<(), ServerError> {
+        let id = subscription.id;
+        let (tickers, rejected) = self.expand(&subscription)?;
+        subscription.tickers = tickers;
+
         let mut guard = match self.clients.write() {
             Ok(guard) => guard,
             Err(e) => return Err(ServerError::ClientsReadError(e.to_string())),
         };
 
-        match guard.entry(address) {
-            Entry::Occupied(_) => Err(ServerError::AddressAlreadyInUse(address)),
-            Entry::Vacant(entry) => {
-                let client = SingleClientHandler::new(
-                    address,
-                    tickers,
-                    self.event_tx.clone(),
-                    Self::CLIENT_PING_TIMEOUT,
-                )?;
-                entry.insert(client);
-                Ok(())
+        // clients older than V3 can't decode challenge, so they would only expire unverified.
+        // Learned address is challenged too once client moves
+        let challenged =
+            subscription.address.is_none() || self.stream_config.challenges(subscription.address);
+        if challenged && subscription.version < ProtocolVersion::V3 {
+            Self::reply(
+                &reply,
+                SubscribeResponse::Rejected(ErrorMessage::new(
+                    ErrorCode::UnsupportedVersion,
+                    "Server verifies stream address, protocol version 3 is required",
+                )),
+            );
+            return Err(ServerError::UnverifiableAddress(
+                subscription.address.unwrap_or(subscription.peer),
+            ));
+        }
+
+        // learned addresses are not checked, client that moves there proves it owns the address
+        if let Some(address) = subscription.address
+            && guard
+                .values()
+                .any(|client| client.subscription().address == Some(address))
+        {
+            Self::reply(
+                &reply,
+                SubscribeResponse::Rejected(ErrorMessage::new(
+                    ErrorCode::AddressInUse,
+                    format!("Another subscription already streams to {address}"),
+                )),
+            );
+            return Err(ServerError::AddressAlreadyInUse(address));
+        }
+
+        // client fails fast on empty acknowledgement, so there is nothing to stream
+        let response = if subscription.tickers.is_empty() {
+            warn!("#{id} has no tickers to stream");
+            let dictionary = TickerDictionary::new(&subscription.tickers);
+            self.accepted(&subscription, dictionary, None, rejected)
+        } else {
+            let client = match SingleClientHandler::new(
+                subscription,
+                self.stream_config.clone(),
+                self.event_tx.clone(),
+                Self::CLIENT_PING_TIMEOUT,
+                Self::CLIENT_VERIFY_TIMEOUT,
+            ) {
+                Ok(client) => client,
+                Err(e) => {
+                    Self::reply(
+                        &reply,
+                        SubscribeResponse::Rejected(ErrorMessage::new(
+                            ErrorCode::InvalidRequest,
+                            e.to_string(),
+                        )),
+                    );
+                    return Err(e);
+                }
+            };
+            let response = self.accepted(
+                client.subscription(),
+                client.dictionary().clone(),
+                client.source(),
+                rejected,
+            );
+            guard.insert(client.subscription().id, client);
+            response
+        };
+
+        // subscriber gave up waiting and told client request failed, so stream must not start
+        if !Self::reply(&reply, response)
+            && let Some(client) = guard.remove(&id)
+        {
+            warn!("#{id} subscriber is gone, stopping stream");
+            client.stop()?;
+        }
+        Ok(())
+    }
+
+    /// Change tickers streamed by subscription, reply has dictionary with ids of all tickers ever streamed
+    pub fn handle_control(
+        &mut self,
+        id: u64,
+        message: ControlMessage,
+        reply: Sender<SubscribeResponse>,
+    ) -> Result<(), ServerError> {
+        let mut guard = self
+            .clients
+            .write()
+            .map_err(|e| ServerError::ClientsReadError(e.to_string()))?;
+        let Some(client) = guard.get_mut(&id) else {
+            // stream may have ended on ping timeout while control session stays open
+            Self::reply(
+                &reply,
+                SubscribeResponse::Rejected(ErrorMessage::new(
+                    ErrorCode::InvalidRequest,
+                    format!("No stream of subscription #{id}"),
+                )),
+            );
+            return Ok(());
+        };
+
+        let subscription = client.subscription_mut();
+        match message {
+            ControlMessage::Add(selectors) => {
+                let added = self.expand_plain(&selectors)?;
+                subscription
+                    .excluded
+                    .retain(|ticker| !added.contains(ticker));
+                for selector in &selectors {
+                    if !subscription.selectors.contains(selector) {
+                        subscription.selectors.push(selector.clone());
+                    }
+                }
+            }
+            ControlMessage::Remove(selectors) => {
+                // tickers may still match patterns or groups of subscription
+                let removed = self.expand_plain(&selectors)?;
+                subscription
+                    .selectors
+                    .retain(|selector| !selectors.contains(selector));
+                subscription.excluded.extend(removed);
+            }
+            ControlMessage::Replace(selectors) => {
+                subscription.selectors = selectors;
+                subscription.excluded.clear();
+            }
+            ControlMessage::Unsubscribe => {
+                drop(guard);
+                return self.handle_unsubscribe(id);
+            }
+            ControlMessage::Verify(cookie) => {
+                let response = match client.verify(cookie) {
+                    true => {
+                        info!("Stream #{id} verified over control session");
+                        self.accepted(
+                            client.subscription(),
+                            client.dictionary().clone(),
+                            client.source(),
+                            vec![],
+                        )
+                    }
+                    false => SubscribeResponse::Rejected(ErrorMessage::new(
+                        ErrorCode::AccessDenied,
+                        "Cookie doesn't match stream challenge",
+                    )),
+                };
+                Self::reply(&reply, response);
+                return Ok(());
+            }
+        };
+
+        let (tickers, rejected) = self.expand(subscription)?;
+        debug!("#{id} streams {}", tickers.join(","));
+        client.extend_dictionary(&tickers)?;
+        client.subscription_mut().tickers = tickers;
+        let response = self.accepted(
+            client.subscription(),
+            client.dictionary().clone(),
+            client.source(),
+            rejected,
+        );
+        Self::reply(&reply, response);
+
+        Ok(())
+    }
+
+    /// Stop stream whose control session ended
+    pub fn handle_unsubscribe(&mut self, id: u64) -> Result<(), ServerError> {
+        debug!("#{id} unsubscribed");
+        Self::remove_and_stop_clients(self.clients.clone(), &[id])
+    }
+
+    /// Re-expand patterns and groups of every subscription against reloaded tickers file.
+    /// Clients with control session get updated ticker dictionary and rejections pushed,
+    /// others can't learn ids of added tickers, so those are streamed with full name
+    pub fn handle_universe_changed(&mut self) -> Result<(), ServerError> {
+        let mut clients = self
+            .clients
+            .write()
+            .map_err(|e| ServerError::ClientsReadError(e.to_string()))?;
+
+        for (id, client) in clients.iter_mut() {
+            let (tickers, rejected) = self.expand(client.subscription())?;
+            if tickers == client.tickers() {
+                continue;
+            }
+
+            debug!("#{id} streams {}", tickers.join(","));
+            let Some(updates) = client.subscription().updates.clone() else {
+                client.subscription_mut().tickers = tickers;
+                continue;
+            };
+
+            client.extend_dictionary(&tickers)?;
+            client.subscription_mut().tickers = tickers;
+            let response = self.accepted(
+                client.subscription(),
+                client.dictionary().clone(),
+                client.source(),
+                rejected,
+            );
+            if updates.send(response).is_err() {
+                warn!("#{id} control session is gone, update not sent");
             }
         }
+
+        Ok(())
+    }
+
+    /// Expand selectors of subscription into tickers server can stream to client,
+    /// selectors matching no ticker or no entitled ticker are rejected.
+    /// Tickers of pattern or group that client isn't entitled to are rejected one by one
+    fn expand(
+        &self,
+        subscription: &Subscription,
+    ) -> Result<(Vec<String>, Vec<RejectedTicker>), ServerError> {
+        let universe = self
+            .universe
+            .read()
+            .map_err(|e| ServerError::QuotesReadError(e.to_string()))?;
+        let mut tickers: Vec<String> = vec![];
+        let mut rejected = vec![];
+
+        for selector in &subscription.selectors {
+            let matched = universe.expand(selector);
+            if matched.is_empty() {
+                rejected.push(RejectedTicker::new(selector, ErrorCode::UnknownTicker));
+                continue;
+            }
+
+            let (allowed, denied) = match &self.entitlements {
+                Some(entitlements) => entitlements.split(subscription.client.as_deref(), matched),
+                None => (matched, vec![]),
+            };
+            if allowed.is_empty() {
+                rejected.push(RejectedTicker::new(selector, ErrorCode::NotEntitled));
+                continue;
+            }
+
+            for ticker in denied {
+                if !rejected.iter().any(|rejected| rejected.ticker == ticker) {
+                    rejected.push(RejectedTicker::new(ticker, ErrorCode::NotEntitled));
+                }
+            }
+
+            for ticker in allowed {
+                if !tickers.contains(&ticker) && !subscription.excluded.contains(&ticker) {
+                    tickers.push(ticker);
+                }
+            }
+        }
+
+        if !rejected.is_empty() {
+            warn!(
+                "#{} selectors rejected: {}",
+                subscription.id,
+                rejected
+                    .iter()
+                    .map(|rejected| format!("{} ({})", rejected.ticker, rejected.code))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok((tickers, rejected))
+    }
+
+    /// Known tickers matching selectors, regardless of entitlements
+    fn expand_plain(&self, selectors: &[String]) -> Result<Vec<String>, ServerError> {
+        let universe = self
+            .universe
+            .read()
+            .map_err(|e| ServerError::QuotesReadError(e.to_string()))?;
+
+        Ok(selectors
+            .iter()
+            .flat_map(|selector| universe.expand(selector))
+            .collect())
+    }
+
+    fn accepted(
+        &self,
+        subscription: &Subscription,
+        dictionary: TickerDictionary,
+        source: Option<SocketAddr>,
+        rejected: Vec<RejectedTicker>,
+    ) -> SubscribeResponse {
+        SubscribeResponse::Accepted {
+            version: subscription.version,
+            dictionary,
+            format: subscription.format,
+            auth: subscription.auth,
+            rejected,
+            checksum: self
+                .stream_config
+                .checksum_for(subscription.version, subscription.format),
+            max_payload: Some(self.stream_config.max_payload),
+            source,
+            heartbeat_ms: self
+                .stream_config
+                .heartbeat_for(subscription.version)
+                .map(|interval| interval.as_millis() as u64),
+        }
+    }
+
+    /// Subscriber may be gone already, so lost reply is only logged
+    /// Returns false if subscriber is not waiting for reply anymore
+    fn reply(reply: &Sender<SubscribeResponse>, response: SubscribeResponse) -> bool {
+        if let Err(e) = reply.send(response) {
+            warn!("Unable to reply to subscriber {e}");
+            return false;
+        }
+        true
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+    use crossbeam_channel::bounded;
+    use quotes_lib::{
+        price::Price,
+        protocol::{ProtocolVersion, WireFormat},
+        quote::Quote,
+        top_of_book::{BookLevel, TopOfBook},
+    };
+
+    use super::*;
+
+    fn clients_handler() -> ClientsHandler {
+        let level = BookLevel::new(Price::new(100, 0), 1);
+        let data = MarketData {
+            quote: Quote {
+                ticker: "AAPL".to_string(),
+                price: Price::new(100, 0),
+                volume: 1,
+                timestamp: 0,
+            },
+            book: TopOfBook::new("AAPL".to_string(), level, level, 0).expect("Not crossed"),
+            trades: vec![],
+        };
+        let universe = TickerUniverse::try_from("AAPL\nMSFT\nMU").expect("Should parse tickers");
+        let entitlements = Entitlements::try_from("* AAPL,MSFT").expect("Should parse rules");
+        ClientsHandler::new(
+            Arc::new(RwLock::new(HashMap::from([("AAPL".to_string(), data)]))),
+            Arc::new(RwLock::new(universe)),
+            StreamConfig {
+                checksum: false,
+                max_payload: 1400,
+                psk: None,
+                bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                advertise: vec![],
+                verify_address: false,
+                heartbeat: None,
+            },
+            Some(entitlements),
+        )
+    }
+
+    fn subscription(address: SocketAddr) -> Subscription {
+        Subscription {
+            id: 1,
+            address: Some(address),
+            peer: address,
+            selectors: vec!["AAPL".to_string()],
+            excluded: vec![],
+            tickers: vec![],
+            version: ProtocolVersion::V3,
+            format: WireFormat::Binary,
+            auth: None,
+            client: None,
+            updates: None,
+        }
+    }
+
+    fn local_address(udp: &UdpSocket) -> SocketAddr {
+        udp.local_addr().expect("Should be bound")
+    }
+
+    fn clients_count(handler: &ClientsHandler) -> usize {
+        handler.clients.read().expect("Should lock clients").len()
+    }
+
+    #[test]
+    fn test_no_stream_for_abandoned_subscription() {
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("Should bind");
+        let address = local_address(&udp);
+        let mut handler = clients_handler();
+
+        // subscriber timed out waiting for reply
+        let (reply_tx, reply_rx) = bounded(1);
+        drop(reply_rx);
+        handler
+            .handle_new_client(subscription(address), reply_tx)
+            .expect("Should handle");
+        assert_eq!(clients_count(&handler), 0);
+
+        let (reply_tx, reply_rx) = bounded(1);
+        handler
+            .handle_new_client(subscription(address), reply_tx)
+            .expect("Should handle");
+        assert!(matches!(
+            reply_rx.try_recv(),
+            Ok(SubscribeResponse::Accepted { .. })
+        ));
+        assert_eq!(clients_count(&handler), 1);
+    }
+
+    #[test]
+    fn test_control_rejects_denied_pattern_members() {
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("Should bind");
+        let address = local_address(&udp);
+        let mut handler = clients_handler();
+
+        let (reply_tx, reply_rx) = bounded(1);
+        handler
+            .handle_new_client(subscription(address), reply_tx)
+            .expect("Should handle");
+        reply_rx.try_recv().expect("Should reply to subscribe");
+
+        let (reply_tx, reply_rx) = bounded(1);
+        handler
+            .handle_control(1, ControlMessage::Add(vec!["M*".to_string()]), reply_tx)
+            .expect("Should handle");
+        let Ok(SubscribeResponse::Accepted {
+            dictionary,
+            rejected,
+            ..
+        }) = reply_rx.try_recv()
+        else {
+            panic!("Should accept control command");
+        };
+        assert_eq!(
+            dictionary,
+            TickerDictionary::new(&["AAPL".to_string(), "MSFT".to_string()])
+        );
+        assert_eq!(
+            rejected,
+            vec![RejectedTicker::new("MU", ErrorCode::NotEntitled)]
+        );
+
+        handler.handle_unsubscribe(1).expect("Should stop");
     }
 }
@@ -1,4 +1,4 @@
-use std::{fmt::Display, net::SocketAddrV4};
+use std::{fmt::Display, net::SocketAddr};
 
 use crossbeam_channel::{RecvError, SendError};
 use log::SetLoggerError;
@@ -16,7 +16,11 @@ pub enum ServerError {
     SendError(String),
     RecvError(String),
     QuotesSourceDataError,
-    AddressAlreadyInUse(SocketAddrV4),
+    AddressAlreadyInUse(SocketAddr),
+    /// server isn't bound to address family of client
+    NoBindAddress(SocketAddr),
+    /// client older than V3 can't echo challenge of stream address
+    UnverifiableAddress(SocketAddr),
     QuotesReadError(String),
     ClientsReadError(String),
 }
@@ -69,6 +73,12 @@ impl Display for ServerError {
             ServerError::AddressAlreadyInUse(socket_addr_v4) => {
                 write!(f, "Client with address {socket_addr_v4} already exists")
             }
+            ServerError::NoBindAddress(address) => {
+                write!(f, "No bind address to stream to {address} from")
+            }
+            ServerError::UnverifiableAddress(address) => {
+                write!(f, "Legacy client can't verify stream address {address}")
+            }
             ServerError::QuotesReadError(reason) => write!(f, "Quotes lock read error: {reason}"),
             ServerError::ClientsReadError(reason) => write!(f, "Clients lock read error: {reason}"),
         }
@@ -1,13 +1,75 @@
-use std::{fmt::Display, net::SocketAddrV4};
+use std::{fmt::Display, net::SocketAddr};
 
-use quotes_lib::error::QuotesError;
+use crossbeam_channel::Sender;
+use quotes_lib::{
+    auth::AuthParams,
+    control_message::ControlMessage,
+    error::QuotesError,
+    protocol::{ProtocolVersion, WireFormat},
+    subscribe_response::SubscribeResponse,
+};
 
 use crate::error::ServerError;
 
+/// Accepted subscribe request
+#[derive(Debug)]
+pub struct Subscription {
+    /// unique id of subscription, keys its stream
+    pub id: u64,
+    /// address to stream to, None if it is learned from authenticated pings
+    pub address: Option<SocketAddr>,
+    /// TCP address subscription came from
+    pub peer: SocketAddr,
+    /// tickers, patterns and group names as requested by client
+    pub selectors: Vec<String>,
+    /// tickers client asked to remove though its selectors match them
+    pub excluded: Vec<String>,
+    /// streamed tickers, selectors expanded by clients handler
+    pub tickers: Vec<String>,
+    pub version: ProtocolVersion,
+    pub format: WireFormat,
+    pub auth: Option<AuthParams>,
+    /// name from credentials store, None if server doesn't check credentials
+    pub client: Option<String>,
+    /// responses pushed to control session, None if client has no control session
+    pub updates: Option<Sender<SubscribeResponse>>,
+}
+
+impl Display for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} ", self.id)?;
+        match self.address {
+            Some(address) => write!(f, "{address}")?,
+            None => write!(f, "learned from {}", self.peer)?,
+        }
+        write!(
+            f,
+            " [{}] v{} {}",
+            self.selectors.join(","),
+            self.version,
+            self.format
+        )?;
+        if let Some(auth) = self.auth {
+            write!(f, " {}", auth.mode)?;
+        }
+        if let Some(client) = &self.client {
+            write!(f, " by {client}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
     QuotesUpdated,
-    NewClient(SocketAddrV4, Vec<String>),
+    /// tickers file was reloaded, subscriptions with patterns and groups may match other tickers
+    UniverseChanged,
+    /// subscription to start and channel for reply to client, sent once tickers are checked
+    NewClient(Subscription, Sender<SubscribeResponse>),
+    /// change of tickers streamed by subscription with id and channel for reply to client
+    Control(u64, ControlMessage, Sender<SubscribeResponse>),
+    /// control session of subscription with id ended
+    Unsubscribe(u64),
     Error(ServerError),
 }
 
@@ -15,7 +77,10 @@ impl Display for Event {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Event::QuotesUpdated => write!(f, "QuotesUpdated"),
-            Event::NewClient(address, tickers) => write!(f, "NewClient({address}, {tickers:?})"),
+            Event::UniverseChanged => write!(f, "UniverseChanged"),
+            Event::NewClient(subscription, _) => write!(f, "NewClient({subscription})"),
+            Event::Control(id, message, _) => write!(f, "Control(#{id}, {message})"),
+            Event::Unsubscribe(id) => write!(f, "Unsubscribe(#{id})"),
             Event::Error(server_error) => write!(f, "Error({server_error})"),
         }
     }
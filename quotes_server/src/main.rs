@@ -1,14 +1,24 @@
-use std::path::PathBuf;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
-use clap::Parser;
-use crossbeam_channel::Select;
+use clap::{Parser, builder::RangedU64ValueParser};
+use crossbeam_channel::{Receiver, Select, never};
 use env_logger::Builder;
-use log::{LevelFilter, error, trace, warn};
-use quotes_lib::read_tickers_from_file;
+use log::{LevelFilter, debug, error, trace, warn};
+use quotes_lib::{
+    auth::{AuthMode, PreSharedKey},
+    credentials::CredentialsStore,
+    datagram::Datagram,
+    entitlements::Entitlements,
+};
 
 use crate::{
     clients_handler::ClientsHandler, error::ServerError, events::Event,
-    quotes_source::QuotesSource, subscriptions_handler::SubscriptionsHandler,
+    quotes_source::QuotesSource, single_client_handler::StreamConfig,
+    subscriptions_handler::SubscriptionsHandler,
 };
 
 mod clients_handler;
@@ -22,8 +32,36 @@ mod subscriptions_handler;
 struct Args {
     #[arg(long, default_value_t = 3000)]
     port: u16,
+    #[arg(long, default_values_t = [IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)])]
+    bind: Vec<IpAddr>,
+    #[arg(long)]
+    advertise: Vec<IpAddr>,
+    #[arg(long, conflicts_with = "no_verify_address")]
+    verify_address: bool,
+    #[arg(long)]
+    no_verify_address: bool,
+    #[arg(long, default_value_t = 2000)]
+    heartbeat_ms: u64,
     #[arg(long, default_value = "all_tickers.txt")]
     tickers: PathBuf,
+    #[arg(long)]
+    checksum: bool,
+    #[arg(long, default_value_t = 1400, value_parser = max_payload_parser())]
+    max_payload: usize,
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+    #[arg(long, requires = "psk_file")]
+    encrypt: bool,
+    #[arg(long)]
+    credentials: Option<PathBuf>,
+    #[arg(long)]
+    entitlements: Option<PathBuf>,
+}
+
+/// Payload has to fit at least a single quote and at most the longest frame
+fn max_payload_parser() -> RangedU64ValueParser<usize> {
+    const MIN_PAYLOAD: u64 = 64;
+    RangedU64ValueParser::new().range(MIN_PAYLOAD..=Datagram::MAX_FRAME_LEN as u64)
 }
 
 fn init_logger() -> Result<(), ServerError> {
@@ -43,15 +81,50 @@ fn run_server() -> Result<(), ServerError> {
     init_logger()?;
     let args = Args::parse();
 
-    let tickers = read_tickers_from_file(args.tickers)?;
-    let mut quotes_source = QuotesSource::new(tickers);
-    let mut subscriptions_handler = SubscriptionsHandler::new(args.port);
-    let mut clients_handler = ClientsHandler::new(quotes_source.quotes().clone());
+    let mut quotes_source = QuotesSource::new(args.tickers)?;
+    let psk = args.psk_file.map(PreSharedKey::from_file).transpose()?;
+    let auth_mode = psk.as_ref().map(|_| match args.encrypt {
+        true => AuthMode::Encrypt,
+        false => AuthMode::Hmac,
+    });
+    let credentials = args
+        .credentials
+        .map(CredentialsStore::from_file)
+        .transpose()?;
+    if let Some(credentials) = &credentials {
+        debug!("Loaded credentials of {} clients", credentials.len());
+    }
+    // loopback streams can't reach other hosts, so only they are not verified by default
+    let verify_address = args.verify_address
+        || (!args.no_verify_address && args.bind.iter().any(|ip| !ip.is_loopback()));
+    let addresses = args
+        .bind
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, args.port))
+        .collect();
+    let mut subscriptions_handler = SubscriptionsHandler::new(addresses, auth_mode, credentials);
+    let stream_config = StreamConfig {
+        checksum: args.checksum,
+        max_payload: args.max_payload,
+        psk,
+        bind: args.bind,
+        advertise: args.advertise,
+        verify_address,
+        heartbeat: (args.heartbeat_ms > 0).then(|| Duration::from_millis(args.heartbeat_ms)),
+    };
+    let entitlements = args.entitlements.map(Entitlements::from_file).transpose()?;
+    let mut clients_handler = ClientsHandler::new(
+        quotes_source.quotes().clone(),
+        quotes_source.universe().clone(),
+        stream_config,
+        entitlements,
+    );
 
     if let Err(run_loop_error) = run_loop(
         &mut quotes_source,
         &mut subscriptions_handler,
         &mut clients_handler,
+        &never(),
     ) {
         error!("Error in run_loop {run_loop_error}")
     } else {
@@ -82,6 +155,7 @@ fn run_loop(
     quotes_source: &mut QuotesSource,
     subscriptions_handler: &mut SubscriptionsHandler,
     clients_handler: &mut ClientsHandler,
+    stop_rx: &Receiver<()>,
 ) -> Result<(), ServerError> {
     let quotes_rx = quotes_source.start()?;
     let subscriptions_rx = subscriptions_handler.start()?;
@@ -89,6 +163,7 @@ fn run_loop(
     let mut select = Select::new();
     let quotes_index = select.recv(&quotes_rx);
     let subscriptions_index = select.recv(&subscriptions_rx);
+    let stop_index = select.recv(stop_rx);
     clients_handler.start()?;
 
     trace!("Starting server loop");
@@ -115,6 +190,10 @@ fn run_loop(
                     }
                 }
             }
+            i if i == stop_index => {
+                debug!("Server loop stopped");
+                break;
+            }
             other => {
                 error!("Unreacheable receiver index {other}");
                 break;
@@ -129,12 +208,27 @@ fn run_loop(
                     warn!("Error in handle_quotes_updated {e}");
                 }
             }
-            Event::NewClient(address, tickers) => {
-                trace!("Event::NewClient {address} [{}]", tickers.join(","));
-                if let Err(e) = clients_handler.handle_new_client(address, tickers) {
+            Event::UniverseChanged => {
+                if let Err(e) = clients_handler.handle_universe_changed() {
+                    warn!("Error in handle_universe_changed {e}");
+                }
+            }
+            Event::NewClient(subscription, reply) => {
+                trace!("Event::NewClient {subscription}");
+                if let Err(e) = clients_handler.handle_new_client(subscription, reply) {
                     warn!("Error adding new client {e}");
                 }
             }
+            Event::Control(id, message, reply) => {
+                if let Err(e) = clients_handler.handle_control(id, message, reply) {
+                    warn!("Error changing client #{id} {e}");
+                }
+            }
+            Event::Unsubscribe(id) => {
+                if let Err(e) = clients_handler.handle_unsubscribe(id) {
+                    warn!("Error removing client #{id} {e}");
+                }
+            }
             Event::Error(server_error) => warn!("Server error {server_error}"),
         }
 
@@ -145,3 +239,132 @@ fn run_loop(
 
     Ok(())
 }
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use crossbeam_channel::{Sender, bounded};
+    use quotes_lib::{
+        datagram::DatagramParser, subscribe_message::SubscribeMessage,
+        subscribe_response::SubscribeResponse,
+    };
+
+    use super::*;
+
+    /// Stops server loop and removes its tickers file when test ends, even on failed assertion
+    struct TestServer {
+        stop_tx: Sender<()>,
+        handle: Option<JoinHandle<()>>,
+        tickers: PathBuf,
+    }
+
+    impl TestServer {
+        fn start(address: SocketAddr, tickers: &str) -> Self {
+            let port = address.port();
+            let path = std::env::temp_dir().join(format!("quotes_server_{port}.txt"));
+            std::fs::write(&path, tickers).expect("Should write tickers");
+            let mut quotes_source = QuotesSource::new(path.clone()).expect("Should load tickers");
+            let mut subscriptions_handler = SubscriptionsHandler::new(vec![address], None, None);
+            let mut clients_handler = ClientsHandler::new(
+                quotes_source.quotes().clone(),
+                quotes_source.universe().clone(),
+                StreamConfig {
+                    checksum: false,
+                    max_payload: 1400,
+                    psk: None,
+                    bind: vec![IpAddr::V6(Ipv6Addr::LOCALHOST)],
+                    advertise: vec![],
+                    verify_address: false,
+                    heartbeat: None,
+                },
+                None,
+            );
+
+            let (stop_tx, stop_rx) = bounded(1);
+            // accept threads stay blocked on listeners and clients handler waits for
+            // client events until test process ends, streams stop on ping timeout
+            let handle = thread::spawn(move || {
+                if let Err(e) = run_loop(
+                    &mut quotes_source,
+                    &mut subscriptions_handler,
+                    &mut clients_handler,
+                    &stop_rx,
+                ) {
+                    error!("Error in run_loop {e}");
+                }
+                quotes_source.stop().expect("Should stop quotes source");
+            });
+
+            Self {
+                stop_tx,
+                handle: Some(handle),
+                tickers: path,
+            }
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = self.stop_tx.send(());
+            if let Some(handle) = self.handle.take() {
+                let _ = handle.join();
+            }
+            let _ = std::fs::remove_file(&self.tickers);
+        }
+    }
+
+    #[test]
+    fn test_stream_over_ipv6_loopback() {
+        let Ok(udp) = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)) else {
+            eprintln!("Skipping test_stream_over_ipv6_loopback, host has no IPv6 loopback");
+            return;
+        };
+        let port = TcpListener::bind((Ipv6Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("Should pick free port")
+            .port();
+        let server_address = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port);
+        let _server = TestServer::start(server_address, "AAPL\nMSFT\n");
+
+        let mut stream = (0..50)
+            .find_map(|_| {
+                TcpStream::connect(server_address)
+                    .inspect_err(|_| thread::sleep(Duration::from_millis(100)))
+                    .ok()
+            })
+            .expect("Should connect");
+        let message = SubscribeMessage::new(
+            Some(udp.local_addr().expect("Should be bound")),
+            vec!["AAPL".to_string()],
+        );
+        writeln!(stream, "{message}").expect("Should subscribe");
+
+        let mut response = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut response)
+            .expect("Should reply");
+        assert!(
+            matches!(
+                SubscribeResponse::try_from(response.as_str()),
+                Ok(SubscribeResponse::Accepted { .. })
+            ),
+            "{response}"
+        );
+
+        udp.set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("Should set timeout");
+        let mut buf = [0u8; 2048];
+        let (len, source) = udp.recv_from(&mut buf).expect("Should receive datagram");
+        assert_eq!(source.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+        let datagrams = DatagramParser::new()
+            .parse(&buf[..len])
+            .expect("Should parse datagram");
+        assert!(!datagrams.is_empty());
+    }
+}
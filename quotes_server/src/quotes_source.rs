@@ -1,30 +1,58 @@
 use std::{
     collections::HashMap,
+    path::PathBuf,
     sync::{Arc, RwLock},
     thread::{self, JoinHandle},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crossbeam_channel::{Receiver, unbounded};
-use log::debug;
-use quotes_lib::quote::Quote;
+use log::{debug, info, warn};
+use quotes_lib::{
+    price::Price,
+    quote::Quote,
+    top_of_book::{BookLevel, TopOfBook},
+    trade::{Side, Trade},
+    universe::TickerUniverse,
+};
 
 use crate::{error::ServerError, events::Event};
 
+/// Latest state of a single instrument
+#[derive(Debug, Clone)]
+pub struct MarketData {
+    /// last trade
+    pub quote: Quote,
+    /// best bid and ask
+    pub book: TopOfBook,
+    /// trades since previous update, oldest first
+    pub trades: Vec<Trade>,
+}
+
 /// Simulate some data source that posts new quotes from stock
 pub struct QuotesSource {
-    tickers: Vec<String>,
-    quotes: Arc<RwLock<HashMap<String, Quote>>>,
+    /// tickers file, reloaded when changed
+    path: PathBuf,
+    universe: Arc<RwLock<TickerUniverse>>,
+    quotes: Arc<RwLock<HashMap<String, MarketData>>>,
     thread_handle: Option<JoinHandle<Result<(), ServerError>>>,
 }
 
 impl QuotesSource {
-    pub fn new(tickers: Vec<String>) -> Self {
-        Self {
-            tickers,
+    pub fn new(path: PathBuf) -> Result<Self, ServerError> {
+        let universe = TickerUniverse::from_file(path.clone())?;
+        debug!(
+            "Loaded {} tickers, groups: {}",
+            universe.tickers().len(),
+            universe.groups().collect::<Vec<_>>().join(",")
+        );
+
+        Ok(Self {
+            path,
+            universe: Arc::new(RwLock::new(universe)),
             quotes: Arc::new(RwLock::new(HashMap::new())),
             thread_handle: None,
-        }
+        })
     }
 
     pub fn start(&mut self) -> Result<Receiver<Event>, ServerError> {
@@ -36,21 +64,40 @@ impl QuotesSource {
 
         let (tx, rx) = unbounded::<Event>();
         let interval = Duration::from_secs(1);
-        let generator = QuotesGenerator {
-            tickers: self.tickers.clone(),
-        };
+        let mut generator = QuotesGenerator { next_trade_id: 0 };
+        let mut watcher = UniverseWatcher::new(self.path.clone());
+        let universe = self.universe.clone();
         let quotes = self.quotes.clone();
 
         let handle = thread::spawn(move || {
             debug!("Start QuotesSource loop");
             loop {
-                let new_quotes = generator.generate_new_quotes();
+                let changed = match watcher.poll() {
+                    Some(new_universe) => match universe.write() {
+                        Ok(mut lock) => {
+                            *lock = new_universe;
+                            true
+                        }
+                        Err(_) => return Err(ServerError::QuotesSourceDataError),
+                    },
+                    None => false,
+                };
+
+                let new_quotes = match universe.read() {
+                    Ok(lock) => generator.generate_new_data_set(lock.tickers()),
+                    Err(_) => return Err(ServerError::QuotesSourceDataError),
+                };
 
                 match quotes.write() {
                     Ok(mut lock) => *lock = new_quotes,
                     Err(_) => return Err(ServerError::QuotesSourceDataError),
                 }
 
+                // quotes of new tickers are ready before subscriptions are expanded to them
+                if changed && let Err(e) = tx.send(Event::UniverseChanged) {
+                    return Err(ServerError::from(e));
+                }
+
                 if let Err(e) = tx.send(Event::QuotesUpdated) {
                     return Err(ServerError::from(e));
                 }
@@ -73,33 +120,153 @@ impl QuotesSource {
         }
     }
 
-    pub fn quotes(&self) -> &Arc<RwLock<HashMap<String, Quote>>> {
+    pub fn quotes(&self) -> &Arc<RwLock<HashMap<String, MarketData>>> {
         &self.quotes
     }
+
+    pub fn universe(&self) -> &Arc<RwLock<TickerUniverse>> {
+        &self.universe
+    }
+}
+
+/// Reloads tickers file when its modification time changes
+struct UniverseWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl UniverseWatcher {
+    fn new(path: PathBuf) -> Self {
+        let modified = Self::modified(&path);
+        Self { path, modified }
+    }
+
+    fn modified(path: &PathBuf) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// New universe if file changed since last poll, broken file keeps the old one
+    fn poll(&mut self) -> Option<TickerUniverse> {
+        let modified = Self::modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        match TickerUniverse::from_file(self.path.clone()) {
+            Ok(universe) => {
+                info!(
+                    "Reloaded {} tickers from {}",
+                    universe.tickers().len(),
+                    self.path.display()
+                );
+                Some(universe)
+            }
+            Err(e) => {
+                warn!("Unable to reload {}: {e}", self.path.display());
+                None
+            }
+        }
+    }
 }
 
 struct QuotesGenerator {
-    tickers: Vec<String>,
+    next_trade_id: u64,
 }
 
 impl QuotesGenerator {
-    fn generate_new_quote(ticker: String) -> Quote {
-        Quote {
+    /// Simulated instruments are quoted in cents
+    const PRICE_SCALE: u8 = 2;
+
+    /// Max distance of bid and ask from mid price in ticks
+    const MAX_HALF_SPREAD: i64 = 5;
+
+    /// Book sizes are multiples of a round lot
+    const LOT_SIZE: u32 = 100;
+
+    /// Max count of trades of a single instrument between updates
+    const MAX_TRADES: usize = 3;
+
+    /// Book is placed symmetrically around a random mid price,
+    /// trades and last price are somewhere between bid and ask
+    fn generate_new_data(&mut self, ticker: String) -> MarketData {
+        let cents = 10i64.pow(Self::PRICE_SCALE as u32);
+        let mid = 100 * cents + rand::random_range(0..=50 * cents);
+        let half_spread = rand::random_range(1..=Self::MAX_HALF_SPREAD);
+        let bid = mid - half_spread;
+        let ask = mid + half_spread;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let book = TopOfBook::new(
+            ticker.clone(),
+            BookLevel::new(
+                Price::new(bid, Self::PRICE_SCALE),
+                Self::LOT_SIZE * rand::random_range(1..=20),
+            ),
+            BookLevel::new(
+                Price::new(ask, Self::PRICE_SCALE),
+                Self::LOT_SIZE * rand::random_range(1..=20),
+            ),
+            timestamp,
+        )
+        .expect("Generated bid is always below ask");
+
+        let trades = (0..rand::random_range(0..=Self::MAX_TRADES))
+            .map(|_| self.generate_trade(&ticker, bid, ask, timestamp))
+            .collect::<Vec<_>>();
+
+        let last_price = match trades.last() {
+            Some(trade) => trade.price,
+            None => Price::new(rand::random_range(bid..=ask), Self::PRICE_SCALE),
+        };
+
+        let quote = Quote {
             ticker,
-            price: 100f64 + rand::random::<f64>() * 50f64,
+            price: last_price,
             volume: 1000 + (rand::random::<f32>() * 1000f32) as u32,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
+            timestamp,
+        };
+
+        MarketData {
+            quote,
+            book,
+            trades,
+        }
+    }
+
+    /// Trade at bid is initiated by seller, at ask by buyer,
+    /// inside the spread aggressor is picked randomly.
+    /// Size never exceeds a lot, so it always fits into the book level
+    fn generate_trade(&mut self, ticker: &str, bid: i64, ask: i64, timestamp: u64) -> Trade {
+        let price = rand::random_range(bid..=ask);
+        let aggressor = if price == ask || (price != bid && rand::random::<bool>()) {
+            Side::Buy
+        } else {
+            Side::Sell
+        };
+
+        let trade_id = self.next_trade_id;
+        self.next_trade_id += 1;
+
+        Trade {
+            ticker: ticker.to_string(),
+            price: Price::new(price, Self::PRICE_SCALE),
+            size: rand::random_range(1..=Self::LOT_SIZE),
+            aggressor,
+            trade_id,
+            timestamp,
         }
     }
 
-    fn generate_new_quotes(&self) -> HashMap<String, Quote> {
+    fn generate_new_data_set(&mut self, tickers: &[String]) -> HashMap<String, MarketData> {
         HashMap::from_iter(
-            self.tickers
+            tickers
                 .iter()
-                .map(|ticker| (ticker.clone(), Self::generate_new_quote(ticker.clone()))),
+                .map(|ticker| (ticker.clone(), self.generate_new_data(ticker.clone()))),
         )
     }
 }
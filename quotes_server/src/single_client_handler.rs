@@ -1,70 +1,552 @@
 use std::{
-    net::{SocketAddrV4, UdpSocket},
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use crossbeam_channel::{Receiver, Sender, unbounded};
-use log::{debug, trace, warn};
+use crossbeam_channel::{Receiver, RecvTimeoutError, SendError, Sender, bounded, unbounded};
+use log::{debug, info, trace, warn};
 use quotes_lib::{
+    auth::{Direction, FrameSealer, PreSharedKey},
     datagram::{Datagram, DatagramParser},
+    protocol::{ProtocolVersion, WireFormat},
     quote::Quote,
     server_message::ServerMessage,
-    subscribe_message::PingMessage,
+    session::{SessionEncoder, TickerDictionary},
+    subscribe_message::{PingMessage, VerifyMessage},
+    top_of_book::TopOfBook,
+    trade::Trade,
 };
 
-use crate::error::ServerError;
+use crate::{error::ServerError, events::Subscription, quotes_source::MarketData};
 
+/// Events of stream with subscription id
 pub enum SingleClientHandlerEvent {
-    Disconnected(SocketAddrV4),
-    Error(SocketAddrV4, ServerError),
+    Disconnected(u64),
+    Error(u64, ServerError),
 }
 
 pub enum SingleClientCommand {
-    SendQuote(Quote),
+    SendMarketData(Vec<MarketData>),
+    SendMessage(ServerMessage),
+    ExtendDictionary(Vec<String>),
     Stop,
 }
 
+/// Settings applied to every client stream
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    /// protect datagrams with CRC32 trailer, only for binary clients supporting V2 framing
+    pub checksum: bool,
+    /// max size of a single UDP datagram, quotes are batched to fit it
+    pub max_payload: usize,
+    /// key for sessions with authenticated datagrams
+    pub psk: Option<PreSharedKey>,
+    /// addresses UDP sockets are bound to, one of client address family is used
+    pub bind: Vec<IpAddr>,
+    /// addresses clients see server at, e.g. public address of host behind port forwarding
+    pub advertise: Vec<IpAddr>,
+    /// stream to claimed address starts only after client echoes cookie sent there
+    pub verify_address: bool,
+    /// idle time after which stream gets heartbeat, None to send none
+    pub heartbeat: Option<Duration>,
+}
+
+impl StreamConfig {
+    /// Checksum is used, legacy clients don't understand frame flags, JSON lines are not framed at all
+    pub fn checksum_for(&self, version: ProtocolVersion, format: WireFormat) -> bool {
+        self.checksum && version >= ProtocolVersion::V2 && format == WireFormat::Binary
+    }
+
+    /// Stream to address claimed in subscription waits for challenge echo,
+    /// learned addresses are proven by authenticated pings already
+    pub fn challenges(&self, address: Option<SocketAddr>) -> bool {
+        self.verify_address && address.is_some()
+    }
+
+    /// Heartbeat interval, clients older than V3 can't decode heartbeats
+    pub fn heartbeat_for(&self, version: ProtocolVersion) -> Option<Duration> {
+        self.heartbeat.filter(|_| version >= ProtocolVersion::V3)
+    }
+
+    /// Address to send stream to client from, socket of other address family can't reach it.
+    /// Wildcard bind address serves clients of both families
+    pub fn local_ip_for(&self, address: SocketAddr) -> Option<IpAddr> {
+        let unspecified = match address {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+
+        self.bind
+            .iter()
+            .find(|ip| ip.is_ipv4() == address.is_ipv4())
+            .copied()
+            .or_else(|| {
+                self.bind
+                    .iter()
+                    .any(IpAddr::is_unspecified)
+                    .then_some(unspecified)
+            })
+    }
+
+    /// Advertised address of stream sent from local socket, None if nothing is advertised for its family
+    pub fn source_for(&self, local: SocketAddr) -> Option<SocketAddr> {
+        self.advertise
+            .iter()
+            .find(|ip| ip.is_ipv4() == local.is_ipv4())
+            .map(|ip| SocketAddr::new(*ip, local.port()))
+    }
+}
+
+/// Cookie stream to claimed address waits for, shared by threads of the stream
+#[derive(Clone)]
+struct Challenge {
+    cookie: u64,
+    verified: Arc<AtomicBool>,
+    /// unverified stream is dropped at this time
+    deadline: Instant,
+}
+
+impl Challenge {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            cookie: rand::random(),
+            verified: Arc::new(AtomicBool::new(false)),
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn is_verified(&self) -> bool {
+        self.verified.load(Ordering::SeqCst)
+    }
+
+    /// Mark stream verified if cookie matches
+    fn verify(&self, cookie: u64) -> bool {
+        if cookie == self.cookie {
+            self.verified.store(true, Ordering::SeqCst);
+        }
+        self.is_verified()
+    }
+}
+
+/// How listen thread confirms address of the stream
+enum ReturnPath {
+    /// address from subscribe message, verified by echoed cookie if challenged
+    Claimed(Option<Challenge>),
+    /// address learned from authenticated pings and passed to writer,
+    /// new addresses are challenged for given time before stream moves there
+    Learned(Sender<AddressUpdate>, Duration),
+}
+
+/// Address of learned stream kept by listen thread, it moves only to addresses that echo challenge
+#[derive(Default)]
+struct LearnedAddress {
+    current: Option<SocketAddr>,
+    /// new address pings came from, challenged before stream moves there
+    probe: Option<(SocketAddr, Challenge)>,
+    last_probe: Option<Instant>,
+}
+
+impl LearnedAddress {
+    /// New addresses are challenged at most this often, so stream can't be bounced around
+    const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Follow authenticated pings and challenge echoes, changes are passed to writer.
+    /// Captured ping delivered from other address only gets a challenge there, stream stays put
+    fn update(
+        &mut self,
+        id: u64,
+        ping_from: Option<SocketAddr>,
+        echoed: Option<u64>,
+        address_tx: &Sender<AddressUpdate>,
+        probe_timeout: Duration,
+    ) -> Result<(), SendError<AddressUpdate>> {
+        if let Some((address, challenge)) = &self.probe {
+            let address = *address;
+            if echoed.is_some_and(|cookie| challenge.verify(cookie)) {
+                if let Some(current) = self.current {
+                    info!("Stream #{id} moved from {current} to {address}");
+                }
+                self.current = Some(address);
+                self.probe = None;
+                address_tx.send(AddressUpdate::Learned(address))?;
+            } else if Instant::now() >= challenge.deadline {
+                info!("Stream #{id} didn't move to {address}, challenge expired unechoed");
+                self.probe = None;
+            }
+        }
+
+        let Some(from) = ping_from else {
+            return Ok(());
+        };
+        let probed = self.probe.as_ref().map(|(address, _)| *address);
+        if self.current == Some(from) || probed == Some(from) {
+            return Ok(());
+        }
+
+        match self.current {
+            None => {
+                info!("Stream #{id} learned address {from}");
+                self.current = Some(from);
+                address_tx.send(AddressUpdate::Learned(from))?;
+            }
+            Some(_)
+                if probed.is_some()
+                    || self
+                        .last_probe
+                        .is_some_and(|sent| sent.elapsed() < Self::PROBE_INTERVAL) =>
+            {
+                trace!("Stream #{id} pinged from {from}, address change is rate limited");
+            }
+            Some(current) => {
+                info!(
+                    "Stream #{id} pinged from {from}, challenging it before moving from {current}"
+                );
+                let challenge = Challenge::new(probe_timeout);
+                self.probe = Some((from, challenge.clone()));
+                self.last_probe = Some(Instant::now());
+                address_tx.send(AddressUpdate::Probe(from, challenge))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Learned address changes passed from listen thread to writer
+enum AddressUpdate {
+    /// stream goes to this address from now on
+    Learned(SocketAddr),
+    /// pings come from new address, it gets challenge while stream stays at old one
+    Probe(SocketAddr, Challenge),
+}
+
+/// Encodes messages for a single client stream and sends them
+struct StreamWriter {
+    socket: Arc<UdpSocket>,
+    /// nothing is sent until address is learned from pings
+    address: Option<SocketAddr>,
+    /// addresses learned by listen thread
+    address_rx: Receiver<AddressUpdate>,
+    /// nothing but challenge is sent until client echoes it
+    challenge: Option<Challenge>,
+    last_challenge: Option<Instant>,
+    /// new learned address stream moves to once client echoes challenge sent there
+    probe: Option<(SocketAddr, Challenge)>,
+    last_probe: Option<Instant>,
+    /// heartbeat is sent when nothing was for this long
+    heartbeat: Option<Duration>,
+    last_sent: Instant,
+    encoder: SessionEncoder,
+    checksum: bool,
+    /// seals datagrams of authenticated session
+    sealer: Option<FrameSealer>,
+    max_payload: usize,
+    /// reused for every message, so sending doesn't allocate
+    buf: Vec<u8>,
+}
+
+impl StreamWriter {
+    /// Challenge is resent this often until client echoes it
+    const CHALLENGE_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new(
+        socket: Arc<UdpSocket>,
+        address: Option<SocketAddr>,
+        address_rx: Receiver<AddressUpdate>,
+        challenge: Option<Challenge>,
+        encoder: SessionEncoder,
+        sealer: Option<FrameSealer>,
+        config: &StreamConfig,
+    ) -> Self {
+        Self {
+            socket,
+            address,
+            address_rx,
+            challenge,
+            last_challenge: None,
+            probe: None,
+            last_probe: None,
+            heartbeat: config.heartbeat_for(encoder.version()),
+            last_sent: Instant::now(),
+            checksum: config.checksum_for(encoder.version(), encoder.format()),
+            encoder,
+            sealer,
+            max_payload: config.max_payload,
+            // big enough for any UDP datagram, oversized quote is still sent in a batch of one
+            buf: vec![0; u16::MAX as usize],
+        }
+    }
+
+    fn max_message_len(&self) -> usize {
+        let (auth_overhead, max_data_len) = match self.sealer {
+            Some(_) => (FrameSealer::OVERHEAD, Datagram::MAX_SEALED_DATA_LEN),
+            None => (0, Datagram::MAX_DATA_LEN),
+        };
+        // data of sealed frames has less room, so payload allowed by config may not fit
+        self.max_payload
+            .saturating_sub(Datagram::overhead(self.checksum))
+            .min(max_data_len)
+            .saturating_sub(auth_overhead)
+    }
+
+    /// Split quotes into messages supported by client
+    fn pack_quotes(&self, quotes: Vec<Quote>) -> Vec<ServerMessage> {
+        self.encoder.pack_quotes(quotes, self.max_message_len())
+    }
+
+    /// Split books into messages supported by client
+    fn pack_books(&self, books: Vec<TopOfBook>) -> Vec<ServerMessage> {
+        self.encoder.pack_books(books, self.max_message_len())
+    }
+
+    /// Split trades into messages supported by client
+    fn pack_trades(&self, trades: Vec<Trade>) -> Vec<ServerMessage> {
+        self.encoder.pack_trades(trades, self.max_message_len())
+    }
+
+    /// True while client hasn't echoed challenge, which is resent every interval until it does
+    fn unverified(&mut self) -> Result<bool, ServerError> {
+        let Some(challenge) = &self.challenge else {
+            return Ok(false);
+        };
+        if challenge.is_verified() {
+            self.challenge = None;
+            return Ok(false);
+        }
+
+        let cookie = challenge.cookie;
+        if self
+            .last_challenge
+            .is_none_or(|sent| sent.elapsed() >= Self::CHALLENGE_INTERVAL)
+        {
+            self.last_challenge = Some(Instant::now());
+            self.transmit(ServerMessage::Challenge(cookie))?;
+        }
+        Ok(true)
+    }
+
+    /// Apply learned address changes passed by listen thread
+    fn update_address(&mut self) {
+        for update in self.address_rx.try_iter() {
+            match update {
+                AddressUpdate::Learned(address) => {
+                    self.address = Some(address);
+                    self.probe = None;
+                }
+                AddressUpdate::Probe(address, challenge) => {
+                    self.probe = Some((address, challenge));
+                    self.last_probe = None;
+                }
+            }
+        }
+    }
+
+    /// Challenge new address of learned stream every interval until it is echoed or expires,
+    /// stream itself stays at the old address meanwhile
+    fn challenge_probe(&mut self) -> Result<(), ServerError> {
+        let Some((address, challenge)) = &self.probe else {
+            return Ok(());
+        };
+        if challenge.is_verified() || Instant::now() >= challenge.deadline {
+            self.probe = None;
+            return Ok(());
+        }
+
+        let (address, cookie) = (*address, challenge.cookie);
+        if self
+            .last_probe
+            .is_none_or(|sent| sent.elapsed() >= Self::CHALLENGE_INTERVAL)
+        {
+            self.last_probe = Some(Instant::now());
+            self.transmit_to(ServerMessage::Challenge(cookie), address)?;
+        }
+        Ok(())
+    }
+
+    /// Send message unless stream waits for challenge echo, so unverified address gets nothing else
+    fn send(&mut self, message: ServerMessage) -> Result<(), ServerError> {
+        self.update_address();
+        self.challenge_probe()?;
+        if self.unverified()? {
+            trace!("Stream is not verified yet, {message} skipped");
+            return Ok(());
+        }
+        self.transmit(message)
+    }
+
+    fn transmit(&mut self, message: ServerMessage) -> Result<(), ServerError> {
+        // skipped messages don't take sequence numbers, so client sees no gap
+        let Some(address) = self.address else {
+            trace!("Address is not learned yet, {message} skipped");
+            return Ok(());
+        };
+        self.transmit_to(message, address)
+    }
+
+    fn transmit_to(
+        &mut self,
+        message: ServerMessage,
+        address: SocketAddr,
+    ) -> Result<(), ServerError> {
+        trace!(
+            "Sending #{} {message} to {address}",
+            self.encoder.sequence()
+        );
+
+        let len = match (self.encoder.format(), self.sealer.as_mut()) {
+            (WireFormat::Binary, Some(sealer)) => {
+                sealer.frame_into(&mut self.buf, self.checksum, |data| {
+                    self.encoder.encode_into(message, data)
+                })?
+            }
+            (WireFormat::Binary, None) => {
+                Datagram::frame_into(&mut self.buf, self.checksum, |data| {
+                    self.encoder.encode_into(message, data)
+                })?
+            }
+            // JSON line is sent as is, so stream can be read with plain `nc -u`
+            _ => self.encoder.encode_into(message, &mut self.buf)?,
+        };
+        self.socket.send_to(&self.buf[..len], address)?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    /// Time left until heartbeat is due, waited for between commands
+    fn heartbeat_timeout(&self) -> Option<Duration> {
+        self.heartbeat
+            .map(|interval| interval.saturating_sub(self.last_sent.elapsed()))
+    }
+
+    /// Send heartbeat if stream was idle for heartbeat interval
+    fn heartbeat(&mut self) -> Result<(), ServerError> {
+        if self
+            .heartbeat
+            .is_some_and(|interval| self.last_sent.elapsed() >= interval)
+        {
+            // heartbeat skipped by unverified stream is due again after interval, not at once
+            self.last_sent = Instant::now();
+            self.send(ServerMessage::Heartbeat)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct SingleClientHandler {
-    tickers: Vec<String>,
+    /// streamed tickers may change over control session
+    subscription: Subscription,
+    /// ids of every ticker ever streamed, known to client
+    dictionary: TickerDictionary,
+    /// advertised address of stream socket
+    source: Option<SocketAddr>,
+    /// cookie client has to echo before stream starts
+    challenge: Option<Challenge>,
+    /// commands of send thread
     command_tx: Sender<SingleClientCommand>,
+    /// listen thread has its own channel, so it never takes commands of send thread
+    listen_stop_tx: Sender<()>,
     listen_thread: JoinHandle<()>,
     send_thread: JoinHandle<()>,
 }
 
 impl SingleClientHandler {
     pub fn new(
-        address: SocketAddrV4,
-        tickers: Vec<String>,
+        subscription: Subscription,
+        config: StreamConfig,
         event_sender: Sender<SingleClientHandlerEvent>,
         ping_timeout: Duration,
+        verify_timeout: Duration,
     ) -> Result<Self, ServerError> {
-        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0")?);
+        let Subscription {
+            id,
+            address,
+            peer,
+            version,
+            format,
+            auth,
+            ..
+        } = subscription;
+        let dictionary = TickerDictionary::new(&subscription.tickers);
+        let (sealer, opener) = match (auth, &config.psk) {
+            (Some(auth), Some(psk)) => (
+                Some(auth.sealer(psk, Direction::ServerToClient)),
+                Some(auth.opener(psk, Direction::ClientToServer)),
+            ),
+            _ => (None, None),
+        };
+        let datagram_parser = match opener {
+            Some(opener) => DatagramParser::new().with_opener(opener),
+            None => DatagramParser::new(),
+        };
+        // learned address comes from the family client connected over
+        let target = address.unwrap_or(peer);
+        let local_ip = config
+            .local_ip_for(target)
+            .ok_or(ServerError::NoBindAddress(target))?;
+        let socket = Arc::new(UdpSocket::bind(SocketAddr::new(local_ip, 0))?);
+        let local = socket.local_addr()?;
+        // client has to know where to ping if stream waits for it
+        let source = match address {
+            Some(_) => config.source_for(local),
+            None => config.source_for(local).or(Some(local)),
+        };
+        let challenge = config
+            .challenges(address)
+            .then(|| Challenge::new(verify_timeout));
         let (command_tx, command_rx) = unbounded();
+        let (listen_stop_tx, listen_stop_rx) = bounded(1);
+        let (address_tx, address_rx) = unbounded();
         let read_socket = socket.clone();
+        let return_path = match address {
+            Some(_) => ReturnPath::Claimed(challenge.clone()),
+            None => ReturnPath::Learned(address_tx, verify_timeout),
+        };
 
         let listen_thread = Self::setup_listen_thread(
             read_socket,
-            command_rx.clone(),
+            listen_stop_rx,
             event_sender.clone(),
-            address,
+            id,
+            return_path,
+            datagram_parser,
             ping_timeout,
         )?;
-        let send_thread = Self::setup_send_thread(socket, command_rx, address, event_sender);
+        let writer = StreamWriter::new(
+            socket,
+            address,
+            address_rx,
+            challenge.clone(),
+            SessionEncoder::new(version, dictionary.clone()).with_format(format),
+            sealer,
+            &config,
+        );
+        let send_thread = Self::setup_send_thread(id, writer, command_rx, event_sender);
         Ok(Self {
-            tickers,
+            subscription,
+            dictionary,
+            source,
+            challenge,
             command_tx,
+            listen_stop_tx,
             listen_thread,
             send_thread,
         })
     }
 
+    /// Keep stream alive while pings come, confirm stream address with echoed challenge or learn it from pings.
+    /// Only authenticated pings are parsed for learned addresses, so stream can't be redirected by others
     fn setup_listen_thread(
         socket: Arc<UdpSocket>,
-        command_rx: Receiver<SingleClientCommand>,
+        stop_rx: Receiver<()>,
         event_sender: Sender<SingleClientHandlerEvent>,
-        address: SocketAddrV4,
+        id: u64,
+        return_path: ReturnPath,
+        mut datagram_parser: DatagramParser,
         ping_timeout: Duration,
     ) -> Result<JoinHandle<()>, ServerError> {
         const SINGLE_READ_TIMEOUT: Duration = Duration::from_millis(250);
@@ -72,24 +554,56 @@ impl SingleClientHandler {
 
         let handle = thread::spawn(move || {
             let mut buf = [0; 2048];
-            let mut datagram_parser = DatagramParser::new();
             let mut last_ping_time = Instant::now();
+            let mut learned = LearnedAddress::default();
 
             loop {
                 let result = socket.recv_from(&mut buf);
 
-                let datagrams = match result {
-                    Ok((bytes_read, ..)) => match datagram_parser.parse(&buf[0..bytes_read]) {
-                        Ok(datagrams) => datagrams,
-                        // don't care if datagrams contains errors
-                        Err(datagrams) => datagrams,
-                    },
-                    // both timeout and read error
-                    Err(_) => vec![],
-                };
+                let mut have_ping = false;
+                let mut ping_from = None;
+                let mut echoed = None;
+                // timeout and read error are handled as no data
+                if let Ok((bytes_read, from)) = result {
+                    // don't care if data contains malformed datagrams
+                    let _ = datagram_parser.parse_with(&buf[0..bytes_read], |dg| {
+                        have_ping |= PingMessage::try_from(dg.data).is_ok();
+                        if let Ok(VerifyMessage(cookie)) = VerifyMessage::try_from(dg.data) {
+                            echoed = Some(cookie);
+                        }
+                    });
+                    ping_from = have_ping.then_some(from);
+                }
+
+                if let ReturnPath::Claimed(Some(challenge)) = &return_path
+                    && !challenge.is_verified()
+                {
+                    if let Some(cookie) = echoed
+                        && challenge.verify(cookie)
+                    {
+                        info!("Stream #{id} verified");
+                    } else if Instant::now() >= challenge.deadline {
+                        info!("Stream #{id} expired unverified");
+                        if let Err(send_error) =
+                            event_sender.send(SingleClientHandlerEvent::Disconnected(id))
+                        {
+                            warn!("Unable to send disconnect event {send_error}");
+                        }
+                        break;
+                    }
+                }
 
-                if let Ok(SingleClientCommand::Stop) = command_rx.try_recv() {
-                    debug!("Stop command received, shutting down listen thread for {address}");
+                // client behind NAT may get new public port, sealed ping counters rule out replays
+                if let ReturnPath::Learned(address_tx, probe_timeout) = &return_path
+                    && learned
+                        .update(id, ping_from, echoed, address_tx, *probe_timeout)
+                        .is_err()
+                {
+                    break;
+                }
+
+                if stop_rx.try_recv().is_ok() {
+                    debug!("Stop command received, shutting down listen thread for #{id}");
                     break;
                 }
 
@@ -97,53 +611,92 @@ impl SingleClientHandler {
 
                 if now - last_ping_time >= ping_timeout {
                     if let Err(send_error) =
-                        event_sender.send(SingleClientHandlerEvent::Disconnected(address))
+                        event_sender.send(SingleClientHandlerEvent::Disconnected(id))
                     {
                         warn!("Unable to send disconnect event {send_error}");
                     }
                     break;
                 }
 
-                let have_ping = datagrams
-                    .into_iter()
-                    .any(|dg| PingMessage::try_from(dg.data.as_slice()).is_ok());
-
                 if have_ping {
                     last_ping_time = now;
                 }
             }
+
+            // forged pings are dropped, so they can't keep subscription alive
+            let auth_failures = datagram_parser.auth_failures();
+            if auth_failures > 0 {
+                warn!("{auth_failures} unauthenticated datagrams to #{id} dropped");
+            }
         });
 
         Ok(handle)
     }
 
     fn setup_send_thread(
-        socket: Arc<UdpSocket>,
+        id: u64,
+        mut writer: StreamWriter,
         command_rx: Receiver<SingleClientCommand>,
-        address: SocketAddrV4,
         event_tx: Sender<SingleClientHandlerEvent>,
     ) -> JoinHandle<()> {
         thread::spawn(move || {
-            loop {
-                match command_rx.recv() {
-                    Ok(SingleClientCommand::SendQuote(quote)) => {
-                        trace!("Sending {quote} to {address}");
-                        let datagram = Datagram::from(ServerMessage::Quote(quote));
-                        let buf: Vec<u8> = datagram.into();
-                        if let Err(io_err) = socket.send_to(&buf, address)
-                            && let Err(send_err) = event_tx.send(SingleClientHandlerEvent::Error(
-                                address,
-                                ServerError::from(io_err),
-                            ))
+            'commands: loop {
+                let command = match writer.heartbeat_timeout() {
+                    Some(timeout) => command_rx.recv_timeout(timeout),
+                    None => command_rx.recv().map_err(RecvTimeoutError::from),
+                };
+                match command {
+                    Ok(SingleClientCommand::SendMarketData(data)) => {
+                        trace!("Sending {} instruments to #{id}", data.len());
+                        let mut quotes = Vec::with_capacity(data.len());
+                        let mut books = Vec::with_capacity(data.len());
+                        let mut trades = vec![];
+                        for data in data {
+                            quotes.push(data.quote);
+                            books.push(data.book);
+                            trades.extend(data.trades);
+                        }
+
+                        // trades happened before the state in quotes and books
+                        let mut messages = writer.pack_trades(trades);
+                        messages.extend(writer.pack_quotes(quotes));
+                        messages.extend(writer.pack_books(books));
+
+                        for message in messages {
+                            if let Err(error) = writer.send(message)
+                                && let Err(send_err) =
+                                    event_tx.send(SingleClientHandlerEvent::Error(id, error))
+                            {
+                                warn!("Unable to send client message {send_err}");
+                                break 'commands;
+                            }
+                        }
+                    }
+                    Ok(SingleClientCommand::SendMessage(message)) => {
+                        if let Err(error) = writer.send(message)
+                            && let Err(send_err) =
+                                event_tx.send(SingleClientHandlerEvent::Error(id, error))
                         {
                             warn!("Unable to send client message {send_err}");
                             break;
                         }
                     }
+                    Ok(SingleClientCommand::ExtendDictionary(tickers)) => {
+                        writer.encoder.extend_dictionary(&tickers);
+                    }
                     Ok(SingleClientCommand::Stop) => {
-                        debug!("Stop command received, shutting down send thread for {address}");
+                        debug!("Stop command received, shutting down send thread for #{id}");
                         break;
                     }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(error) = writer.heartbeat()
+                            && let Err(send_err) =
+                                event_tx.send(SingleClientHandlerEvent::Error(id, error))
+                        {
+                            warn!("Unable to send client message {send_err}");
+                            break;
+                        }
+                    }
                     Err(e) => {
                         warn!("Error recieving data: {e}");
                         break;
@@ -153,14 +706,23 @@ impl SingleClientHandler {
         })
     }
 
-    pub fn send_quote(&self, quote: Quote) -> Result<(), ServerError> {
+    pub fn send_market_data(&self, data: Vec<MarketData>) -> Result<(), ServerError> {
         self.command_tx
-            .send(SingleClientCommand::SendQuote(quote))
+            .send(SingleClientCommand::SendMarketData(data))
+            .map_err(|e| ServerError::SendError(e.to_string()))
+    }
+
+    pub fn send_message(&self, message: ServerMessage) -> Result<(), ServerError> {
+        self.command_tx
+            .send(SingleClientCommand::SendMessage(message))
             .map_err(|e| ServerError::SendError(e.to_string()))
     }
 
     pub fn stop(self) -> Result<(), ServerError> {
         trace!("Stopping single client handler");
+        if let Err(e) = self.listen_stop_tx.send(()) {
+            warn!("Unable to send stop signal to listen thread {e}");
+        }
         if let Err(e) = self.command_tx.send(SingleClientCommand::Stop) {
             warn!("Unable to send stop command {e}");
         } else {
@@ -181,6 +743,91 @@ impl SingleClientHandler {
     }
 
     pub fn tickers(&self) -> &[String] {
-        &self.tickers
+        &self.subscription.tickers
+    }
+
+    pub fn subscription(&self) -> &Subscription {
+        &self.subscription
+    }
+
+    pub fn source(&self) -> Option<SocketAddr> {
+        self.source
+    }
+
+    /// Verify stream with cookie echoed over control session, true if stream needs no more verification
+    pub fn verify(&self, cookie: u64) -> bool {
+        self.challenge
+            .as_ref()
+            .is_none_or(|challenge| challenge.verify(cookie))
+    }
+
+    pub fn dictionary(&self) -> &TickerDictionary {
+        &self.dictionary
+    }
+
+    /// Selection of tickers may change over control session or on tickers file reload
+    pub fn subscription_mut(&mut self) -> &mut Subscription {
+        &mut self.subscription
+    }
+
+    /// Assign ids to new tickers after known ones, client learns them from control session reply.
+    /// Tickers without id are streamed with full name
+    pub fn extend_dictionary(&mut self, tickers: &[String]) -> Result<(), ServerError> {
+        let added = tickers
+            .iter()
+            .filter(|ticker| self.dictionary.id(ticker).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        if !added.is_empty() {
+            self.dictionary.extend(&added);
+            self.command_tx
+                .send(SingleClientCommand::ExtendDictionary(added))
+                .map_err(|e| ServerError::SendError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    use super::*;
+
+    fn config(bind: &[IpAddr]) -> StreamConfig {
+        StreamConfig {
+            checksum: false,
+            max_payload: 1400,
+            psk: None,
+            bind: bind.to_vec(),
+            advertise: vec![],
+            verify_address: false,
+            heartbeat: None,
+        }
+    }
+
+    #[test]
+    fn test_local_ip_for() {
+        let v4 = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 5000));
+        let v6 = SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 5000));
+        let v4_loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6_loopback = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+        let dual = config(&[v4_loopback, v6_loopback]);
+        assert_eq!(dual.local_ip_for(v4), Some(v4_loopback));
+        assert_eq!(dual.local_ip_for(v6), Some(v6_loopback));
+
+        let v4_only = config(&[v4_loopback]);
+        assert_eq!(v4_only.local_ip_for(v6), None);
+
+        // wildcard serves the other family from its wildcard
+        let wildcard = config(&[IpAddr::V4(Ipv4Addr::UNSPECIFIED)]);
+        assert_eq!(
+            wildcard.local_ip_for(v4),
+            Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        );
+        assert_eq!(
+            wildcard.local_ip_for(v6),
+            Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        );
     }
 }
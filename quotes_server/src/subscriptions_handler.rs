@@ -1,104 +1,392 @@
 use std::{
-    io::{BufRead, BufReader},
-    net::TcpListener,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use crossbeam_channel::{Receiver, Sender, unbounded};
-use log::{debug, error, trace};
-use quotes_lib::{error::QuotesError, subscribe_message::SubscribeMessage};
+use crossbeam_channel::{Receiver, Sender, bounded, unbounded};
+use log::{debug, error, info, trace, warn};
+use quotes_lib::{
+    auth::{AuthMode, AuthParams},
+    control_message::ControlMessage,
+    credentials::CredentialsStore,
+    error::QuotesError,
+    error_message::{ErrorCode, ErrorMessage},
+    protocol::{ProtocolVersion, WireFormat},
+    subscribe_message::SubscribeMessage,
+    subscribe_response::SubscribeResponse,
+};
 
-use crate::{error::ServerError, events::Event};
+use crate::{
+    error::ServerError,
+    events::{Event, Subscription},
+};
 
 pub struct SubscriptionsHandler {
-    port: u16,
-    thread_handle: Option<JoinHandle<Result<(), ServerError>>>,
+    /// TCP addresses to accept subscriptions on
+    addresses: Vec<SocketAddr>,
+    /// streams are sealed with pre-shared key if set
+    auth_mode: Option<AuthMode>,
+    /// clients must present known credentials if set
+    credentials: Option<Arc<CredentialsStore>>,
+    /// one accept thread per bound address
+    thread_handles: Vec<JoinHandle<Result<(), ServerError>>>,
 }
 
 impl SubscriptionsHandler {
-    pub fn new(port: u16) -> Self {
+    pub fn new(
+        addresses: Vec<SocketAddr>,
+        auth_mode: Option<AuthMode>,
+        credentials: Option<CredentialsStore>,
+    ) -> Self {
         Self {
-            port,
-            thread_handle: None,
+            addresses,
+            auth_mode,
+            credentials: credentials.map(Arc::new),
+            thread_handles: vec![],
+        }
+    }
+
+    /// Listen on every address available on host, e.g. default loopback of missing address family
+    fn bind(addresses: &[SocketAddr]) -> Result<Vec<TcpListener>, ServerError> {
+        let mut listeners = vec![];
+        let mut last_error = None;
+
+        for address in addresses {
+            match TcpListener::bind(address) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => {
+                    warn!("Unable to listen on {address} {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match (listeners.is_empty(), last_error) {
+            (true, Some(e)) => Err(ServerError::from(e)),
+            _ => Ok(listeners),
         }
     }
 
     pub fn start(&mut self) -> Result<Receiver<Event>, ServerError> {
-        if self.thread_handle.is_some() {
+        if !self.thread_handles.is_empty() {
             return Err(ServerError::ComponentAlreadyStarted(
                 "SubscriptionsHandler".to_string(),
             ));
         }
 
-        let port = self.port;
         let (tx, rx) = unbounded();
-        let handle = thread::spawn(move || {
-            let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-            debug!("Started TCP server on port {}", port);
-
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        debug!("New client from {:?}", stream.peer_addr());
-                        handle_client(stream, tx.clone())
-                    }
-                    Err(e) => {
-                        return Err(ServerError::from(e));
+        // ids are unique across listeners, control sessions refer to streams by them
+        let next_id = Arc::new(AtomicU64::new(1));
+        for listener in Self::bind(&self.addresses)? {
+            let next_id = next_id.clone();
+            let auth_mode = self.auth_mode;
+            let credentials = self.credentials.clone();
+            let tx = tx.clone();
+            let local_address = listener.local_addr()?;
+            debug!("Started TCP server on {local_address}");
+
+            let handle = thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            debug!("New client from {:?}", stream.peer_addr());
+                            let id = next_id.fetch_add(1, Ordering::Relaxed);
+                            handle_client(stream, id, tx.clone(), auth_mode, credentials.clone())
+                        }
+                        Err(e) => {
+                            return Err(ServerError::from(e));
+                        }
                     }
                 }
-            }
 
-            trace!("Stopped TCP server");
+                trace!("Stopped TCP server on {local_address}");
 
-            Ok(())
-        });
-
-        self.thread_handle = Some(handle);
+                Ok(())
+            });
+            self.thread_handles.push(handle);
+        }
 
         Ok(rx)
     }
 
     pub fn stop(&mut self) -> Result<(), ServerError> {
-        if let Some(handle) = self.thread_handle.take() {
-            handle.join().unwrap_or(Err(ServerError::ComponentStopError(
-                "SubscriptionsHandler".to_string(),
-            )))
-        } else {
-            Ok(())
+        for handle in self.thread_handles.drain(..) {
+            handle
+                .join()
+                .unwrap_or(Err(ServerError::ComponentStopError(
+                    "SubscriptionsHandler".to_string(),
+                )))?;
         }
+
+        Ok(())
     }
 }
 
-impl From<SubscribeMessage> for Event {
-    fn from(value: SubscribeMessage) -> Self {
-        Event::NewClient(value.address, value.tickers)
+/// Time to wait for server loop to handle new subscription
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check credentials and negotiate protocol version, tickers are checked later by clients handler
+fn subscribe(
+    message: Result<SubscribeMessage, QuotesError>,
+    id: u64,
+    peer: SocketAddr,
+    auth: Option<AuthParams>,
+    credentials: Option<&CredentialsStore>,
+) -> Result<Subscription, QuotesError> {
+    let message = message?;
+    // anyone could ping first and take over stream, so address is learned from sealed pings only
+    if message.address.is_none() && auth.is_none() {
+        return Err(QuotesError::AuthError(
+            "stream address is learned only from authenticated pings, server has no pre-shared key"
+                .to_string(),
+        ));
     }
-}
+    let client = credentials
+        .map(|store| store.verify(message.credentials.as_ref()))
+        .transpose()?
+        .map(str::to_string);
 
-impl From<Result<SubscribeMessage, QuotesError>> for Event {
-    fn from(value: Result<SubscribeMessage, QuotesError>) -> Self {
-        value.map(Event::from).unwrap_or_else(Event::from)
+    match ProtocolVersion::negotiate(&message.versions) {
+        // legacy frames have no room for auth flag, JSON lines are not framed at all
+        Some(version)
+            if auth.is_some()
+                && (version < ProtocolVersion::V2 || message.format != WireFormat::Binary) =>
+        {
+            Err(QuotesError::AuthError(format!(
+                "server requires authenticated binary stream, client asks for v{version} {}",
+                message.format
+            )))
+        }
+        Some(version) => Ok(Subscription {
+            id,
+            address: message.address.map(canonical),
+            peer: canonical(peer),
+            selectors: message.tickers,
+            excluded: vec![],
+            tickers: vec![],
+            version,
+            format: message.format,
+            auth,
+            client,
+            updates: None,
+        }),
+        None => Err(QuotesError::UnsupportedProtocolVersion(format!(
+            "client offers {:?}, server supports {:?}",
+            message.versions,
+            ProtocolVersion::SUPPORTED.map(u8::from)
+        ))),
     }
 }
 
-fn handle_client(stream: std::net::TcpStream, tx: Sender<Event>) {
+/// Dual-stack clients may write IPv4 address as IPv4-mapped IPv6 one
+fn canonical(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+fn handle_client(
+    stream: TcpStream,
+    id: u64,
+    tx: Sender<Event>,
+    auth_mode: Option<AuthMode>,
+    credentials: Option<Arc<CredentialsStore>>,
+) {
     thread::spawn(move || {
         trace!("Handling new client from {:?}", stream.peer_addr());
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("Client disconnected before subscribe {e}");
+                return;
+            }
+        };
         let mut buf_reader = BufReader::new(stream);
         let mut buf = String::new();
 
         let event = if let Err(e) = buf_reader.read_line(&mut buf) {
             trace!("TCP READ ERR {e}");
-            Event::from(ServerError::from(e))
+            Some(Event::from(ServerError::from(e)))
         } else {
-            trace!("TCP READ {buf:?}");
-            Event::from(SubscribeMessage::try_from(buf.as_str()))
+            // the line may carry credentials, so it is not logged as is
+            trace!("TCP READ {} bytes", buf.len());
+            let message = SubscribeMessage::try_from(buf.as_str());
+            let control = message.as_ref().is_ok_and(|message| message.control);
+            // every subscription gets its own salt, so frames can't be replayed into another one
+            let auth = auth_mode.map(|mode| AuthParams::new(mode, rand::random()));
+            // replies of control session and updates pushed by server loop
+            let updates = control.then(unbounded);
+            let (response, event, subscribed) =
+                match subscribe(message, id, peer, auth, credentials.as_deref()) {
+                    Ok(mut subscription) => {
+                        subscription.updates =
+                            updates.as_ref().map(|(updates_tx, _)| updates_tx.clone());
+                        if let Some(client) = &subscription.client {
+                            info!("Client {client} subscribed from {peer}");
+                        }
+
+                        // reply lists tickers, so it waits until server loop checks them
+                        let Some(response) =
+                            request(&tx, |reply| Event::NewClient(subscription, reply))
+                        else {
+                            return;
+                        };
+                        (response, None, true)
+                    }
+                    Err(e) => (
+                        SubscribeResponse::Rejected(ErrorMessage::from(&e)),
+                        Some(Event::from(e)),
+                        false,
+                    ),
+                };
+
+            // legacy clients don't wait for reply, so write errors are not critical
+            if let Err(e) = writeln!(buf_reader.get_mut(), "{response}") {
+                warn!("Unable to send subscribe response {e}");
+            }
+
+            // session of rejected subscription must not tear down stream of another one
+            let streaming = matches!(
+                &response,
+                SubscribeResponse::Accepted { dictionary, .. } if !dictionary.is_empty()
+            );
+            if subscribed
+                && let Some(updates) = updates
+                && streaming
+            {
+                control_session(buf_reader, &tx, id, updates);
+            }
+
+            event
         };
 
-        if let Err(e) = tx.send(event) {
+        if let Some(event) = event
+            && let Err(e) = tx.send(event)
+        {
             error!("Unable to send event {e}")
         } else {
             trace!("New client message sent OK");
         }
     });
 }
+
+/// Send event with reply channel to server loop and wait for reply, None if server loop is gone
+fn request(
+    tx: &Sender<Event>,
+    event: impl FnOnce(Sender<SubscribeResponse>) -> Event,
+) -> Option<SubscribeResponse> {
+    let (reply_tx, reply_rx) = bounded(1);
+    if let Err(e) = tx.send(event(reply_tx)) {
+        error!("Unable to send event {e}");
+        return None;
+    }
+
+    Some(reply_rx.recv_timeout(REPLY_TIMEOUT).unwrap_or_else(|e| {
+        SubscribeResponse::Rejected(ErrorMessage::new(
+            ErrorCode::Internal,
+            format!("Request not handled: {e}"),
+        ))
+    }))
+}
+
+/// Apply control commands to stream until client unsubscribes or closes connection
+fn control_session(
+    mut buf_reader: BufReader<TcpStream>,
+    tx: &Sender<Event>,
+    id: u64,
+    (updates_tx, updates_rx): (Sender<SubscribeResponse>, Receiver<SubscribeResponse>),
+) {
+    debug!("Control session for #{id} started");
+    // replies and pushed updates share connection, so they are written by a single thread
+    match buf_reader.get_ref().try_clone() {
+        Ok(stream) => {
+            thread::spawn(move || write_responses(stream, updates_rx));
+        }
+        Err(e) => warn!("Control session for #{id} can't write responses {e}"),
+    }
+    let mut buf = String::new();
+
+    loop {
+        buf.clear();
+        match buf_reader.read_line(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Control session for #{id} read error {e}");
+                break;
+            }
+        }
+        trace!("TCP CONTROL {}", buf.trim());
+
+        let response = match ControlMessage::try_from(buf.as_str()) {
+            Ok(ControlMessage::Unsubscribe) => break,
+            Ok(message) => match request(tx, |reply| Event::Control(id, message, reply)) {
+                Some(response) => response,
+                None => return,
+            },
+            Err(e) => SubscribeResponse::Rejected(ErrorMessage::from(&e)),
+        };
+
+        if updates_tx.send(response).is_err() {
+            break;
+        }
+    }
+
+    debug!("Control session for #{id} ended");
+    if let Err(e) = tx.send(Event::Unsubscribe(id)) {
+        error!("Unable to send event {e}")
+    }
+}
+
+/// Write responses of control session until connection breaks or stream is stopped
+fn write_responses(mut stream: TcpStream, responses: Receiver<SubscribeResponse>) {
+    for response in responses {
+        if let Err(e) = writeln!(stream, "{response}") {
+            warn!("Unable to send control response {e}");
+            break;
+        }
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_canonical() {
+        let mapped = SocketAddr::new(IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()), 5000);
+        assert_eq!(
+            canonical(mapped),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 5000))
+        );
+
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 5000));
+        assert_eq!(canonical(v6), v6);
+    }
+
+    #[test]
+    fn test_bind_skips_unavailable() {
+        // documentation address is not assigned to any host
+        let unavailable = SocketAddr::from(([192, 0, 2, 1], 0));
+        let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+
+        let listeners =
+            SubscriptionsHandler::bind(&[unavailable, loopback]).expect("Should bind loopback");
+        assert_eq!(listeners.len(), 1);
+        assert!(
+            listeners[0]
+                .local_addr()
+                .expect("Should be bound")
+                .ip()
+                .is_loopback()
+        );
+
+        assert!(SubscriptionsHandler::bind(&[unavailable]).is_err());
+    }
+}